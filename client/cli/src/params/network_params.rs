@@ -22,7 +22,7 @@ use sc_network::{
 	config::{NetworkConfiguration, NodeKeyConfig},
 	multiaddr::Protocol,
 };
-use sc_network_common::config::{NonReservedPeerMode, SetConfig, TransportConfig};
+use sc_network_common::config::{NonReservedPeerMode, PeerLimit, SetConfig, TransportConfig};
 use sc_service::{
 	config::{Multiaddr, MultiaddrWithPeerId},
 	ChainSpec, ChainType,
@@ -212,14 +212,11 @@ impl NetworkParams {
 			boot_nodes,
 			net_config_path,
 			default_peers_set: SetConfig {
-				in_peers: self.in_peers + self.in_peers_light,
-				out_peers: self.out_peers,
+				in_peers: PeerLimit::Limited(self.in_peers + self.in_peers_light),
+				out_peers: PeerLimit::Limited(self.out_peers),
 				reserved_nodes: self.reserved_nodes.clone(),
-				non_reserved_mode: if self.reserved_only {
-					NonReservedPeerMode::Deny
-				} else {
-					NonReservedPeerMode::Accept
-				},
+				reserved_peer_ids: Vec::new(),
+				non_reserved_mode: NonReservedPeerMode::from_accept_flag(!self.reserved_only),
 			},
 			default_peers_set_num_full: self.in_peers + self.out_peers,
 			listen_addresses,
@@ -699,9 +699,10 @@ pub fn grandpa_peers_set_config(
 		max_notification_size: 1024 * 1024,
 		handshake: None,
 		set_config: sc_network_common::config::SetConfig {
-			in_peers: 0,
-			out_peers: 0,
+			in_peers: sc_network_common::config::PeerLimit::Limited(0),
+			out_peers: sc_network_common::config::PeerLimit::Limited(0),
 			reserved_nodes: Vec::new(),
+			reserved_peer_ids: Vec::new(),
 			non_reserved_mode: sc_network_common::config::NonReservedPeerMode::Deny,
 		},
 	}
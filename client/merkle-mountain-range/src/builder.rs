@@ -0,0 +1,292 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Builder consolidating the options of the MMR gadget.
+
+use crate::{
+	CanonicalKeyEncoding, CanonicalizeScope, CanonicalizedEvent, CatchUpOrder, CatchUpSummary,
+	ControlMessage, Error, ForkDiscriminator, GadgetExecutor, HeaderProvider, IndexingPrefix,
+	MmrClient, MmrGadget, MmrGadgetConfig, MmrGadgetStatus, MmrRootHasher, MmrTelemetry,
+	OnInconsistentLeafCount, PruningMode, StartupConsistencyCheck,
+};
+use beefy_primitives::MmrRootHash;
+use futures::channel::mpsc;
+use prometheus::Registry;
+use sc_client_api::Backend;
+use sp_mmr_primitives::{LeafIndex, MmrApi};
+use sp_runtime::traits::{Block, Header, NumberFor};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+
+/// Builder of an [`MmrGadget`], see [`MmrGadget::builder`].
+///
+/// Every option defaults to the corresponding [`MmrGadgetConfig`] default.
+pub struct MmrGadgetBuilder<B: Block, BE, C> {
+	client: Arc<C>,
+	backend: Arc<BE>,
+	indexing_prefix: IndexingPrefix,
+	config: MmrGadgetConfig<B>,
+}
+
+impl<B, BE, C> MmrGadgetBuilder<B, BE, C>
+where
+	B: Block,
+	<B::Header as Header>::Number: Into<LeafIndex>,
+	BE: Backend<B>,
+	C: MmrClient<B, BE>,
+	C::Api: MmrApi<B, MmrRootHash, NumberFor<B>>,
+{
+	/// Create a builder for a gadget canonicalizing the MMR nodes indexed under
+	/// `indexing_prefix`.
+	pub fn new(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: impl Into<IndexingPrefix>,
+	) -> Self {
+		Self {
+			client,
+			backend,
+			indexing_prefix: indexing_prefix.into(),
+			config: Default::default(),
+		}
+	}
+
+	/// Replace all the options with `config`.
+	pub fn config(mut self, config: MmrGadgetConfig<B>) -> Self {
+		self.config = config;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::canonicalize_margin`].
+	pub fn canonicalize_margin(mut self, canonicalize_margin: NumberFor<B>) -> Self {
+		self.config.canonicalize_margin = canonicalize_margin;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::max_canonicalize_retries`].
+	pub fn max_canonicalize_retries(mut self, retries: u32) -> Self {
+		self.config.max_canonicalize_retries = retries;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::start_height`].
+	pub fn start_height(mut self, start_height: NumberFor<B>) -> Self {
+		self.config.start_height = Some(start_height);
+		self
+	}
+
+	/// See [`MmrGadgetConfig::pruning`].
+	pub fn pruning(mut self, pruning: PruningMode) -> Self {
+		self.config.pruning = pruning;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::status`].
+	pub fn status(mut self, status: MmrGadgetStatus<B>) -> Self {
+		self.config.status = status;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::control`].
+	pub fn control(mut self, control: mpsc::UnboundedReceiver<ControlMessage>) -> Self {
+		self.config.control = Some(control);
+		self
+	}
+
+	/// See [`MmrGadgetConfig::max_buffered_notifications`].
+	pub fn max_buffered_notifications(mut self, max_buffered_notifications: usize) -> Self {
+		self.config.max_buffered_notifications = max_buffered_notifications;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::duplicate_notification_window`].
+	pub fn duplicate_notification_window(mut self, window: usize) -> Self {
+		self.config.duplicate_notification_window = window;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::on_before_write`].
+	pub fn on_before_write(mut self, on_before_write: impl FnMut(usize) + Send + 'static) -> Self {
+		self.config.on_before_write = Some(Box::new(on_before_write));
+		self
+	}
+
+	/// See [`MmrGadgetConfig::checkpoint_path`].
+	pub fn checkpoint_path(mut self, checkpoint_path: impl Into<PathBuf>) -> Self {
+		self.config.checkpoint_path = Some(checkpoint_path.into());
+		self
+	}
+
+	/// See [`MmrGadgetConfig::subscribe_canonicalized`].
+	pub fn subscribe_canonicalized(&mut self) -> broadcast::Receiver<CanonicalizedEvent<B>> {
+		self.config.subscribe_canonicalized()
+	}
+
+	/// See [`MmrGadgetConfig::on_inconsistent_leaf_count`].
+	pub fn on_inconsistent_leaf_count(mut self, policy: OnInconsistentLeafCount) -> Self {
+		self.config.on_inconsistent_leaf_count = policy;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::offchain_storage_timeout`].
+	pub fn offchain_storage_timeout(mut self, timeout: Duration) -> Self {
+		self.config.offchain_storage_timeout = timeout;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::absent_pallet_log_level`].
+	pub fn absent_pallet_log_level(mut self, level: log::Level) -> Self {
+		self.config.absent_pallet_log_level = level;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::prometheus_registry`].
+	pub fn prometheus_registry(mut self, registry: Registry) -> Self {
+		self.config.prometheus_registry = Some(registry);
+		self
+	}
+
+	/// See [`MmrGadgetConfig::canonical_key_encoding`].
+	pub fn canonical_key_encoding(mut self, encoding: CanonicalKeyEncoding) -> Self {
+		self.config.canonical_key_encoding = encoding;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::fork_discriminator`].
+	pub fn fork_discriminator(mut self, discriminator: ForkDiscriminator) -> Self {
+		self.config.fork_discriminator = discriminator;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::canonicalize_scope`].
+	pub fn canonicalize_scope(mut self, scope: CanonicalizeScope) -> Self {
+		self.config.canonicalize_scope = scope;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::header_provider`].
+	pub fn header_provider(mut self, provider: Arc<dyn HeaderProvider<B>>) -> Self {
+		self.config.header_provider = Some(provider);
+		self
+	}
+
+	/// See [`MmrGadgetConfig::read_concurrency`].
+	pub fn read_concurrency(mut self, read_concurrency: usize) -> Self {
+		self.config.read_concurrency = read_concurrency;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::root_hasher`].
+	pub fn root_hasher(mut self, hasher: Arc<dyn MmrRootHasher>) -> Self {
+		self.config.root_hasher = Some(hasher);
+		self
+	}
+
+	/// See [`MmrGadgetConfig::catch_up_order`].
+	pub fn catch_up_order(mut self, order: CatchUpOrder) -> Self {
+		self.config.catch_up_order = order;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::max_offchain_key_len`].
+	pub fn max_offchain_key_len(mut self, max_len: usize) -> Self {
+		self.config.max_offchain_key_len = max_len;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::telemetry`] and [`MmrGadgetConfig::telemetry_interval`].
+	pub fn telemetry(
+		mut self,
+		telemetry: impl MmrTelemetry<NumberFor<B>> + 'static,
+		interval: Duration,
+	) -> Self {
+		self.config.telemetry = Some(Box::new(telemetry));
+		self.config.telemetry_interval = interval;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::startup_consistency_check`].
+	pub fn startup_consistency_check(mut self, check: StartupConsistencyCheck) -> Self {
+		self.config.startup_consistency_check = check;
+		self
+	}
+
+	/// See [`MmrGadgetConfig::executor`].
+	pub fn executor(mut self, executor: GadgetExecutor) -> Self {
+		self.config.executor = executor;
+		self
+	}
+
+	/// Run the gadget, see [`MmrGadget::start_with_config`].
+	pub async fn start(self)
+	where
+		BE: 'static,
+		C: 'static,
+	{
+		MmrGadget::start_with_config(self.client, self.backend, self.indexing_prefix, self.config)
+			.await
+	}
+
+	/// Only canonicalize the blocks finalized so far, see [`MmrGadget::run_catch_up_only`].
+	pub fn run_catch_up_only(self) -> Result<CatchUpSummary, Error> {
+		MmrGadget::run_catch_up_only(self.client, self.backend, self.indexing_prefix, self.config)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		test_utils::{wait_for_canonicalized, MockClient, MockRuntimeApi},
+		MmrGadget, MmrGadgetStatus,
+	};
+	use sp_runtime::generic::BlockId;
+	use std::{sync::Arc, time::Duration};
+	use tokio::runtime::Runtime;
+
+	#[test]
+	fn gadget_can_be_started_through_builder() {
+		let client = Arc::new(MockClient::new());
+		let status = MmrGadgetStatus::default();
+		let runtime = Runtime::new().unwrap();
+
+		let gadget = MmrGadget::builder(
+			client.clone(),
+			client.backend.clone(),
+			MockRuntimeApi::INDEXING_PREFIX.to_vec(),
+		)
+		.canonicalize_margin(1)
+		.status(status.clone())
+		.max_buffered_notifications(16);
+		runtime.spawn(gadget.start());
+
+		runtime.block_on(async move {
+			tokio::time::sleep(Duration::from_millis(200)).await;
+			// G -> A1 -> A2 -> A3
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			client.finalize_block(a3.hash(), Some(3));
+			wait_for_canonicalized(&status, 2).await;
+
+			// A3 is held back by the margin.
+			client.assert_canonicalized(&[&a1, &a2]);
+			client.assert_not_canonicalized(&[&a3]);
+			assert_eq!(status.best_canonicalized(), Some(2));
+		});
+	}
+}
@@ -0,0 +1,58 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Human-readable checkpoint of the canonicalization progress.
+//!
+//! Complements the watermark kept in the aux db for tooling that can't read the node databases.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fs, io, path::Path};
+
+/// Last canonicalized block, as stored in the JSON checkpoint file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint<N, H> {
+	/// Number of the last canonicalized block.
+	pub block_number: N,
+	/// Hash of the last canonicalized block.
+	pub block_hash: H,
+}
+
+impl<N, H> Checkpoint<N, H>
+where
+	N: Serialize + DeserializeOwned,
+	H: Serialize + DeserializeOwned,
+{
+	/// Read the checkpoint stored at `path`. Returns `None` if there is no such file.
+	pub fn read(path: &Path) -> io::Result<Option<Self>> {
+		match fs::read(path) {
+			Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Replace the checkpoint stored at `path`.
+	///
+	/// The checkpoint is written to a temporary file first, so that `path` always holds a
+	/// complete checkpoint.
+	pub fn write(&self, path: &Path) -> io::Result<()> {
+		let tmp_path = path.with_extension("tmp");
+		fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+		fs::rename(tmp_path, path)
+	}
+}
@@ -0,0 +1,64 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Runtime control of a running MMR gadget.
+
+use futures::channel::mpsc;
+
+/// Message sent to a running [`MmrGadget`](crate::MmrGadget) through [`MmrGadgetControl`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+	/// Stop canonicalizing and pruning, buffering the finality notifications instead.
+	Pause,
+	/// Process the buffered finality notifications and carry on as usual.
+	Resume,
+}
+
+/// Cheaply cloneable handle to pause and resume a running [`MmrGadget`](crate::MmrGadget).
+///
+/// The receiving side is handed over to the gadget through
+/// [`MmrGadgetConfig::control`](crate::MmrGadgetConfig::control). Once every handle is
+/// dropped, the gadget resumes and can't be paused anymore.
+#[derive(Clone)]
+pub struct MmrGadgetControl {
+	sender: mpsc::UnboundedSender<ControlMessage>,
+}
+
+impl MmrGadgetControl {
+	/// Create a new control handle along with the receiver to pass to the gadget.
+	pub fn new() -> (Self, mpsc::UnboundedReceiver<ControlMessage>) {
+		let (sender, receiver) = mpsc::unbounded();
+		(Self { sender }, receiver)
+	}
+
+	/// Pause the gadget, e.g. for the duration of an offchain db migration.
+	pub fn pause(&self) {
+		self.send(ControlMessage::Pause)
+	}
+
+	/// Resume a paused gadget.
+	pub fn resume(&self) {
+		self.send(ControlMessage::Resume)
+	}
+
+	fn send(&self, message: ControlMessage) {
+		// The gadget only stops listening once it has stopped altogether, in which case there
+		// is nothing left to control.
+		let _ = self.sender.unbounded_send(message);
+	}
+}
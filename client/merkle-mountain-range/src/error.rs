@@ -0,0 +1,77 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! MMR gadget specific errors.
+
+/// Errors that can occur while running the MMR gadget.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	/// The gadget state couldn't be loaded from or written to the aux db.
+	#[error("MMR gadget aux db error: {0}")]
+	AuxSchema(#[from] sp_blockchain::Error),
+	/// The block where `pallet-mmr` was added to the runtime is not a valid MMR block.
+	#[error("Invalid first MMR block: {0}")]
+	InvalidFirstMmrBlock(String),
+	/// The finality notifications stream closed unexpectedly.
+	#[error("Finality notifications stream closed unexpectedly")]
+	FinalityStreamClosed,
+	/// The node doesn't have an offchain storage to canonicalize.
+	#[error("Node has no offchain storage")]
+	NoOffchainStorage,
+	/// The checkpoint file couldn't be read.
+	#[error("Couldn't read the MMR gadget checkpoint: {0}")]
+	Checkpoint(String),
+	/// The runtime reports more MMR leaves than there are blocks.
+	#[error("Inconsistent MMR leaf count: {0}")]
+	InconsistentLeafCount(String),
+	/// The offchain keys built from the indexing prefix exceed
+	/// [`MmrGadgetConfig::max_offchain_key_len`](crate::MmrGadgetConfig::max_offchain_key_len).
+	#[error("MMR offchain keys too long: {0}")]
+	OffchainKeyTooLong(String),
+	/// The canonical entries found in the offchain db don't match the canonicalized blocks.
+	#[error("Inconsistent MMR offchain db: {0}")]
+	InconsistentOffchainDb(String),
+	/// A [`CanonicalSink`](crate::CanonicalSink) couldn't commit the canonical entries of a block.
+	#[error("Canonical sink error: {0}")]
+	CanonicalSink(String),
+	/// A block still couldn't be canonicalized after
+	/// [`MmrGadgetConfig::max_canonicalize_retries`](crate::MmrGadgetConfig::max_canonicalize_retries)
+	/// retries.
+	#[error("Couldn't canonicalize {0}")]
+	CanonicalizationFailed(String),
+	/// A canonical MMR snapshot couldn't be exported or imported.
+	#[error("MMR snapshot error: {0}")]
+	Snapshot(String),
+	/// The offchain entry of an MMR node doesn't decode as the expected node type, e.g. because
+	/// it is corrupted or was written in another format.
+	#[error(
+		"Couldn't decode the MMR node at pos {pos} (key 0x{}): {error}",
+		sp_core::hexdisplay::HexDisplay::from(.key)
+	)]
+	NodeDecode {
+		/// Position of the node.
+		pos: sp_mmr_primitives::NodeIndex,
+		/// Offchain key of the entry.
+		key: Vec<u8>,
+		/// Why decoding failed.
+		error: codec::Error,
+	},
+	/// `pallet-mmr` isn't part of the runtime at the given block.
+	#[error("pallet-mmr not detected at block {0}")]
+	PalletNotDetected(String),
+}
@@ -0,0 +1,32 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Alternative source of block headers.
+
+use sp_blockchain::CachedHeaderMetadata;
+use sp_runtime::traits::Block;
+
+/// Source of the headers missing from the client, e.g. old headers offloaded to cold storage by
+/// an archive node.
+///
+/// Only consulted for the blocks the client doesn't know about, see
+/// [`MmrGadgetConfig::header_provider`](crate::MmrGadgetConfig::header_provider).
+pub trait HeaderProvider<B: Block>: Send + Sync {
+	/// Metadata of the header of block `hash`, if known.
+	fn header_metadata(&self, hash: B::Hash) -> Option<CachedHeaderMetadata<B>>;
+}
@@ -38,24 +38,73 @@
 #![warn(missing_docs)]
 
 mod aux_schema;
+mod builder;
+mod checkpoint;
+pub mod clock;
+mod control;
+mod error;
+mod header_provider;
+mod metrics;
 mod offchain_mmr;
+mod peaks;
+mod prefix;
+mod reader;
+mod root;
+mod sink;
+mod snapshot;
+mod status;
+mod telemetry;
 #[cfg(test)]
 pub mod test_utils;
 
-use crate::offchain_mmr::OffchainMmr;
+pub use crate::{
+	builder::MmrGadgetBuilder,
+	checkpoint::Checkpoint,
+	control::{ControlMessage, MmrGadgetControl},
+	error::Error,
+	header_provider::HeaderProvider,
+	offchain_mmr::{
+		keys::{
+			self, canonical_key_prefix, is_canonical_key, CanonicalKeyEncoding, ForkDiscriminator,
+		},
+		CanonicalizedEvent, CatchUpSummary, OffchainMmr, PrefixMigration, ReplayReport,
+	},
+	peaks::peaks_for_leaf_count,
+	prefix::IndexingPrefix,
+	reader::{OffchainMmrReader, OnNodeDecodeError},
+	root::{Keccak256RootHasher, MmrRootHasher},
+	sink::CanonicalSink,
+	status::MmrGadgetStatus,
+	telemetry::{MmrTelemetry, TelemetryEvent},
+};
 use beefy_primitives::MmrRootHash;
-use futures::StreamExt;
-use log::{debug, error, trace, warn};
+use futures::{
+	channel::{mpsc, oneshot},
+	future, FutureExt, SinkExt, StreamExt,
+};
+use log::{debug, error, info, warn};
+use prometheus::Registry;
 use sc_client_api::{Backend, BlockchainEvents, FinalityNotification, FinalityNotifications};
 use sc_offchain::OffchainDb;
 use sp_api::ProvideRuntimeApi;
 use sp_blockchain::{HeaderBackend, HeaderMetadata};
+use sp_core::{
+	hexdisplay::HexDisplay,
+	offchain::{DbExternalities, OffchainStorage, StorageKind},
+};
 use sp_mmr_primitives::{utils, LeafIndex, MmrApi};
 use sp_runtime::{
 	generic::BlockId,
-	traits::{Block, Header, NumberFor},
+	traits::{Block, Header, NumberFor, Zero},
 };
-use std::{marker::PhantomData, sync::Arc};
+use std::{
+	collections::VecDeque,
+	marker::PhantomData,
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
 
 /// Logging target for the mmr gadget.
 pub const LOG_TARGET: &str = "mmr";
@@ -71,7 +120,25 @@ where
 {
 	/// Get the block number where the mmr pallet was added to the runtime.
 	fn first_mmr_block_num(&self, notification: &FinalityNotification<B>) -> Option<NumberFor<B>> {
-		let best_block = *notification.header.number();
+		self.first_mmr_block_num_at(*notification.header.number())
+	}
+
+	/// Get the block number where the mmr pallet was added to the runtime, as seen from the
+	/// runtime state at `best_block`.
+	fn first_mmr_block_num_at(&self, best_block: NumberFor<B>) -> Option<NumberFor<B>> {
+		self.try_first_mmr_block_num_at(best_block).unwrap_or_else(|e| {
+			error!(target: LOG_TARGET, "Error calculating the first mmr block: {}", e);
+			None
+		})
+	}
+
+	/// Same as [`MmrClient::first_mmr_block_num_at`], but returns an
+	/// [`Error::InconsistentLeafCount`] if the runtime reports more MMR leaves than there are
+	/// blocks up to `best_block`.
+	fn try_first_mmr_block_num_at(
+		&self,
+		best_block: NumberFor<B>,
+	) -> Result<Option<NumberFor<B>>, Error> {
 		match self.runtime_api().mmr_leaf_count(&BlockId::number(best_block)) {
 			Ok(Ok(mmr_leaf_count)) => {
 				match utils::first_mmr_block_num::<B::Header>(best_block, mmr_leaf_count) {
@@ -82,26 +149,15 @@ where
 							best_block,
 							first_mmr_block
 						);
-						Some(first_mmr_block)
-					},
-					Err(e) => {
-						error!(
-							target: LOG_TARGET,
-							"Error calculating the first mmr block: {:?}", e
-						);
-						None
+						Ok(Some(first_mmr_block))
 					},
+					Err(e) => Err(Error::InconsistentLeafCount(format!(
+						"{} leaves at block {:?}: {:?}",
+						mmr_leaf_count, best_block, e
+					))),
 				}
 			},
-			_ => {
-				trace!(
-					target: LOG_TARGET,
-					"pallet-mmr not detected at block {:?} ... (best finalized {:?})",
-					best_block,
-					notification.header.number()
-				);
-				None
-			},
+			_ => Ok(None),
 		}
 	}
 }
@@ -120,7 +176,8 @@ struct OffchainMmrBuilder<B: Block, BE: Backend<B>, C> {
 	backend: Arc<BE>,
 	client: Arc<C>,
 	offchain_db: OffchainDb<BE::OffchainStorage>,
-	indexing_prefix: Vec<u8>,
+	indexing_prefix: IndexingPrefix,
+	config: MmrGadgetConfig<B>,
 
 	_phantom: PhantomData<B>,
 }
@@ -135,32 +192,488 @@ where
 	async fn try_build(
 		self,
 		finality_notifications: &mut FinalityNotifications<B>,
-	) -> Option<OffchainMmr<B, BE, C>> {
+	) -> Result<OffchainMmr<B, BE, C>, Error> {
+		let mut absent_pallet_log = AbsentPalletLog::new(self.config.absent_pallet_log_level);
+		let consistency_check = self.config.startup_consistency_check;
 		while let Some(notification) = finality_notifications.next().await {
-			if let Some(first_mmr_block_num) = self.client.first_mmr_block_num(&notification) {
+			let first_mmr_block_num = detect_first_mmr_block::<B, BE, C>(
+				&*self.client,
+				*notification.header.number(),
+				self.config.on_inconsistent_leaf_count,
+			)?;
+			if let Some(first_mmr_block_num) = first_mmr_block_num {
 				let mut offchain_mmr = OffchainMmr::new(
 					self.backend,
 					self.client,
 					self.offchain_db,
 					self.indexing_prefix,
 					first_mmr_block_num,
+					self.config,
 				)?;
+				info!(
+					target: LOG_TARGET,
+					"MMR offchain gadget started with first MMR block #{:?}, indexing prefix 0x{}",
+					first_mmr_block_num,
+					HexDisplay::from(offchain_mmr.indexing_prefix()),
+				);
+				// `first_mmr_block_num` was just derived from this notification, no need to query
+				// the runtime for it again when processing it.
+				offchain_mmr.set_first_mmr_block_checked_at(notification.hash);
+				offchain_mmr.check_offchain_indexing(*notification.header.number());
 				// We need to make sure all blocks leading up to current notification
 				// have also been canonicalized.
 				offchain_mmr.canonicalize_catch_up(&notification);
 				// We have to canonicalize and prune the blocks in the finality
 				// notification that lead to building the offchain-mmr as well.
+				let block_num = *notification.header.number();
+				offchain_mmr.status().record_notification_received(block_num);
 				offchain_mmr.canonicalize_and_prune(notification);
-				return Some(offchain_mmr)
+				offchain_mmr.status().record_notification_processed(block_num);
+				offchain_mmr.finish_catch_up();
+				if consistency_check != StartupConsistencyCheck::Off {
+					match offchain_mmr.verify_canonical_entries() {
+						Err(e) if consistency_check == StartupConsistencyCheck::Error =>
+							return Err(e),
+						Err(e) => error!(target: LOG_TARGET, "{}", e),
+						Ok(()) => {},
+					}
+				}
+				return Ok(offchain_mmr)
 			}
+			absent_pallet_log.record_absent();
 		}
 
-		error!(
+		Err(Error::FinalityStreamClosed)
+	}
+}
+
+/// How the MMR nodes of stale forks are pruned from the offchain db.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruningMode {
+	/// Prune stale forks while processing the finality notification that made them stale.
+	Inline,
+	/// Hand the keys of stale forks over to a background task, keeping pruning off the
+	/// finality processing path.
+	///
+	/// The keys are sent through a queue holding at most `queue_size` finality notifications
+	/// worth of keys. Canonicalization waits for the background task when the queue is full.
+	Background {
+		/// Capacity of the pruning queue. `0` is raised to `1`.
+		queue_size: usize,
+	},
+}
+
+impl Default for PruningMode {
+	fn default() -> Self {
+		Self::Inline
+	}
+}
+
+/// What to do when the runtime reports more MMR leaves than there are blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnInconsistentLeafCount {
+	/// Log the inconsistency and treat `pallet-mmr` as not detected yet.
+	Skip,
+	/// Stop with an [`Error::InconsistentLeafCount`].
+	Error,
+}
+
+impl Default for OnInconsistentLeafCount {
+	fn default() -> Self {
+		Self::Skip
+	}
+}
+
+/// Whether to check, before following finality, that the offchain db holds a canonical entry for
+/// every MMR node of the canonicalized blocks (see [`OffchainMmr::verify_canonical_entries`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupConsistencyCheck {
+	/// Don't check.
+	Off,
+	/// Log missing entries and keep going.
+	Log,
+	/// Stop with an [`Error::InconsistentOffchainDb`] if entries are missing.
+	Error,
+}
+
+impl Default for StartupConsistencyCheck {
+	fn default() -> Self {
+		Self::Off
+	}
+}
+
+/// MMR nodes moved under canonical keys when a block is canonicalized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanonicalizeScope {
+	/// The leaf added by the block and the internal nodes added along with it.
+	LeavesAndNodes,
+	/// Only the leaf added by the block.
+	///
+	/// The fork-aware entries of the internal nodes are still removed, so consumers have to
+	/// recompute internal nodes from the leaves. Note that `pallet-mmr` can't generate proofs
+	/// without them.
+	LeavesOnly,
+}
+
+impl Default for CanonicalizeScope {
+	fn default() -> Self {
+		Self::LeavesAndNodes
+	}
+}
+
+impl CanonicalizeScope {
+	/// Number of nodes to canonicalize out of the `branch_len` nodes added by a block, the leaf
+	/// coming first.
+	fn canonicalized_len(&self, branch_len: usize) -> usize {
+		match self {
+			Self::LeavesAndNodes => branch_len,
+			Self::LeavesOnly => branch_len.min(1),
+		}
+	}
+}
+
+/// Order in which the backlog of finalized blocks is canonicalized when catching up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CatchUpOrder {
+	/// Oldest blocks first. `best_canonicalized` advances with every block.
+	Ascending,
+	/// Most recent blocks first, then backfill the older ones.
+	///
+	/// Recent MMR nodes become readable under canonical keys before the whole backlog is
+	/// processed. Since `best_canonicalized` tracks a contiguous canonical prefix, it only jumps
+	/// to the most recent block once the backfill completes: if the node stops in between, the
+	/// whole backlog is processed again on restart, and the blocks that were already
+	/// canonicalized are skipped as their fork-aware entries are gone.
+	Descending,
+}
+
+impl Default for CatchUpOrder {
+	fn default() -> Self {
+		Self::Ascending
+	}
+}
+
+/// Where [`MmrGadget::start_with_config`] processes the finality notifications.
+#[derive(Clone)]
+pub enum GadgetExecutor {
+	/// Within the future awaited by the caller.
+	InPlace,
+	/// On a blocking thread of the given runtime, which must have timers enabled.
+	///
+	/// Keeps slow offchain db writes from stalling the other tasks of the runtime.
+	Runtime(tokio::runtime::Handle),
+	/// On a single-threaded runtime of its own, driven by a dedicated OS thread.
+	DedicatedThread,
+}
+
+impl Default for GadgetExecutor {
+	fn default() -> Self {
+		Self::InPlace
+	}
+}
+
+/// Look up the first MMR block as seen from block `best_block`, handling inconsistent leaf
+/// counts according to `policy`.
+fn detect_first_mmr_block<B, BE, C>(
+	client: &C,
+	best_block: NumberFor<B>,
+	policy: OnInconsistentLeafCount,
+) -> Result<Option<NumberFor<B>>, Error>
+where
+	B: Block,
+	BE: Backend<B>,
+	C: MmrClient<B, BE>,
+	C::Api: MmrApi<B, MmrRootHash, NumberFor<B>>,
+{
+	match client.try_first_mmr_block_num_at(best_block) {
+		Err(e) if policy == OnInconsistentLeafCount::Skip => {
+			error!(target: LOG_TARGET, "Error calculating the first mmr block: {}", e);
+			Ok(None)
+		},
+		result => result,
+	}
+}
+
+/// Number of finality notifications without `pallet-mmr` after which the pallet is reported as
+/// absent.
+const ABSENT_PALLET_GRACE_NOTIFICATIONS: u32 = 10;
+
+/// Reports `pallet-mmr` as absent once, after [`ABSENT_PALLET_GRACE_NOTIFICATIONS`] finality
+/// notifications without it, instead of logging on every notification.
+struct AbsentPalletLog {
+	level: log::Level,
+	notifications: u32,
+}
+
+impl AbsentPalletLog {
+	fn new(level: log::Level) -> Self {
+		Self { level, notifications: 0 }
+	}
+
+	/// Record a finality notification without `pallet-mmr`. Returns whether it was logged.
+	fn record_absent(&mut self) -> bool {
+		self.notifications = self.notifications.saturating_add(1);
+		if self.notifications != ABSENT_PALLET_GRACE_NOTIFICATIONS {
+			return false
+		}
+		log::log!(
 			target: LOG_TARGET,
-			"Finality notifications stream closed unexpectedly. \
-			Couldn't build the canonicalization engine",
+			self.level,
+			"pallet-mmr not detected after {} finality notifications, the MMR gadget stays idle \
+			until it is.",
+			self.notifications,
 		);
-		None
+		true
+	}
+}
+
+/// Default for [`MmrGadgetConfig::max_buffered_notifications`].
+pub const DEFAULT_MAX_BUFFERED_NOTIFICATIONS: usize = 1024;
+
+/// Default for [`MmrGadgetConfig::duplicate_notification_window`].
+pub const DEFAULT_DUPLICATE_NOTIFICATION_WINDOW: usize = 16;
+
+/// Default for [`MmrGadgetConfig::max_offchain_key_len`].
+pub const DEFAULT_MAX_OFFCHAIN_KEY_LEN: usize = 1024;
+
+/// Default for [`MmrGadgetConfig::telemetry_interval`].
+pub const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Interval between two checks for the offchain storage while waiting for it
+/// (see [`MmrGadgetConfig::offchain_storage_timeout`]).
+const OFFCHAIN_STORAGE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of [`CanonicalizedEvent`]s kept for lagging subscribers of
+/// [`MmrGadgetConfig::subscribe_canonicalized`].
+pub const CANONICALIZED_EVENTS_CAPACITY: usize = 256;
+
+/// Configuration of the [`MmrGadget`].
+pub struct MmrGadgetConfig<B: Block> {
+	/// Number of most recent finalized blocks whose MMR nodes are kept under fork-aware keys.
+	///
+	/// Upon finalization of block `N`, only blocks up to `N - canonicalize_margin` are
+	/// canonicalized; the others are canonicalized once they age past the margin.
+	/// Defaults to `0`, i.e. blocks are canonicalized as soon as they are finalized.
+	pub canonicalize_margin: NumberFor<B>,
+	/// Number of times a block whose canonical entries couldn't be committed is retried, once
+	/// per finality notification.
+	///
+	/// Other blocks are canonicalized in the meantime, but the last canonicalized block only
+	/// moves past the failed one once it succeeds. When out of retries, the block is reported
+	/// with [`Error::CanonicalizationFailed`], see [`OffchainMmr::take_canonicalization_errors`],
+	/// and is only retried after a restart. Defaults to `3`.
+	pub max_canonicalize_retries: u32,
+	/// Block below which finalized blocks are neither canonicalized nor pruned.
+	///
+	/// Meant for nodes importing the canonical MMR entries of older blocks from a snapshot,
+	/// which can then skip the catch-up over that history. The last canonicalized block is
+	/// moved up to `start_height - 1` on startup. Defaults to `None`, i.e. the whole history
+	/// since the MMR pallet was added is processed.
+	pub start_height: Option<NumberFor<B>>,
+	/// How stale forks are pruned. Defaults to [`PruningMode::Inline`].
+	pub pruning: PruningMode,
+	/// Handle through which the gadget reports its state.
+	pub status: MmrGadgetStatus<B>,
+	/// Receiver of the messages sent through a [`MmrGadgetControl`], if the gadget should be
+	/// controllable at runtime.
+	///
+	/// Messages are only taken into account once `pallet-mmr` has been detected.
+	pub control: Option<mpsc::UnboundedReceiver<ControlMessage>>,
+	/// Maximum number of finality notifications buffered while the gadget is paused.
+	///
+	/// Once reached, further notifications are left in the finality stream until the gadget is
+	/// resumed. `0` is raised to `1`.
+	pub max_buffered_notifications: usize,
+	/// Number of recently processed finality notifications remembered, by block number and
+	/// hash, to drop exact duplicates, e.g. replayed after a restart.
+	///
+	/// Defaults to [`DEFAULT_DUPLICATE_NOTIFICATION_WINDOW`]. `0` disables the check.
+	pub duplicate_notification_window: usize,
+	/// Called with the number of canonical entries about to be written for a block, before
+	/// writing them.
+	///
+	/// Can be used to throttle writes, e.g. when the offchain storage is network-attached.
+	pub on_before_write: Option<Box<dyn FnMut(usize) + Send>>,
+	/// Path of a JSON [`Checkpoint`] file updated after each canonicalized block.
+	///
+	/// When the file exists at startup and points to a block of the canonical chain,
+	/// canonicalization resumes after that block, regardless of the progress recorded in the aux
+	/// db. Editing the file thus allows re-canonicalizing blocks.
+	pub checkpoint_path: Option<PathBuf>,
+	/// Sender of the [`CanonicalizedEvent`]s emitted after each canonicalized block.
+	///
+	/// See [`MmrGadgetConfig::subscribe_canonicalized`].
+	pub canonicalized_events: Option<broadcast::Sender<CanonicalizedEvent<B>>>,
+	/// What to do when the runtime reports more MMR leaves than there are blocks while
+	/// detecting `pallet-mmr`. Defaults to [`OnInconsistentLeafCount::Skip`].
+	pub on_inconsistent_leaf_count: OnInconsistentLeafCount,
+	/// How long to wait for the offchain storage to become available when starting.
+	///
+	/// Defaults to zero, i.e. the gadget doesn't start if there is no offchain storage.
+	pub offchain_storage_timeout: Duration,
+	/// Level of the log reporting that `pallet-mmr` wasn't detected yet.
+	///
+	/// The log is emitted once, after a few finality notifications without the pallet.
+	/// Defaults to [`log::Level::Info`].
+	pub absent_pallet_log_level: log::Level,
+	/// Registry of the Prometheus metrics measuring the offchain storage latency.
+	///
+	/// No metrics are collected if `None`, the default.
+	pub prometheus_registry: Option<Registry>,
+	/// Encoding of the canonical keys written by the gadget.
+	///
+	/// Defaults to [`CanonicalKeyEncoding::Scale`], the only encoding understood by
+	/// `pallet-mmr`.
+	pub canonical_key_encoding: CanonicalKeyEncoding,
+	/// Hash distinguishing the fork-aware keys of the nodes added by blocks of different forks.
+	///
+	/// Must match the keys written by `pallet-mmr`. Defaults to
+	/// [`ForkDiscriminator::ParentHash`].
+	pub fork_discriminator: ForkDiscriminator,
+	/// MMR nodes moved under canonical keys. Defaults to [`CanonicalizeScope::LeavesAndNodes`].
+	pub canonicalize_scope: CanonicalizeScope,
+	/// Source of the headers the client doesn't have, e.g. because they were offloaded.
+	///
+	/// Only headers are looked up there: the MMR nodes of the blocks still have to be in the
+	/// offchain db. Defaults to `None`, i.e. only the client is queried.
+	pub header_provider: Option<Arc<dyn HeaderProvider<B>>>,
+	/// Maximum number of threads reading MMR nodes from the offchain db in
+	/// [`OffchainMmr::verify_canonical_entries`] and [`OffchainMmr::read_canonical_nodes`].
+	///
+	/// Worth raising for offchain storages with a high latency, e.g. network-attached ones.
+	/// Defaults to 1, i.e. the nodes are read sequentially.
+	pub read_concurrency: usize,
+	/// Check of the canonical MMR entries against the MMR root reported by the runtime.
+	///
+	/// When set, the root implied by the canonical entries of the MMR peaks is computed with
+	/// this hasher after each canonicalized block, and compared with the `MmrApi::mmr_root` of
+	/// that block. The peaks are read back through [`CanonicalSink::read`], so the check is
+	/// skipped with sinks that can't be read from. Mismatches are logged as errors and counted by
+	/// the `substrate_mmr_gadget_root_mismatches` metric. Use [`Keccak256RootHasher`] for BEEFY.
+	/// Defaults to `None`, i.e. no check.
+	pub root_hasher: Option<Arc<dyn MmrRootHasher>>,
+	/// Order in which missed finalized blocks are canonicalized when catching up.
+	///
+	/// Defaults to [`CatchUpOrder::Ascending`].
+	pub catch_up_order: CatchUpOrder,
+	/// Maximum length of the offchain keys of the MMR nodes, in bytes.
+	///
+	/// Starting the gadget fails with [`Error::OffchainKeyTooLong`] if the keys built from the
+	/// indexing prefix would be longer, which usually points to a misconfigured prefix.
+	pub max_offchain_key_len: usize,
+	/// Destination of the [`TelemetryEvent`]s of the gadget, e.g. a
+	/// `sc_telemetry::TelemetryHandle` with the `telemetry` feature enabled.
+	///
+	/// Nothing is reported if `None`, the default.
+	pub telemetry: Option<Box<dyn MmrTelemetry<NumberFor<B>>>>,
+	/// Minimum interval between two [`TelemetryEvent::Canonicalized`] reports.
+	///
+	/// Defaults to [`DEFAULT_TELEMETRY_INTERVAL`].
+	pub telemetry_interval: Duration,
+	/// Whether to check the canonical entries of the offchain db once caught up, e.g. to detect
+	/// a partially wiped db.
+	///
+	/// The check reads every canonical entry, so it can take a while on long chains. Defaults to
+	/// [`StartupConsistencyCheck::Off`].
+	pub startup_consistency_check: StartupConsistencyCheck,
+	/// Where the finality notifications are processed.
+	///
+	/// Whatever the executor, [`MmrGadget::start_with_config`] only returns once the gadget
+	/// stops. Defaults to [`GadgetExecutor::InPlace`].
+	pub executor: GadgetExecutor,
+}
+
+impl<B: Block> Default for MmrGadgetConfig<B> {
+	fn default() -> Self {
+		Self {
+			canonicalize_margin: Zero::zero(),
+			max_canonicalize_retries: 3,
+			start_height: None,
+			pruning: Default::default(),
+			status: Default::default(),
+			control: None,
+			max_buffered_notifications: DEFAULT_MAX_BUFFERED_NOTIFICATIONS,
+			duplicate_notification_window: DEFAULT_DUPLICATE_NOTIFICATION_WINDOW,
+			on_before_write: None,
+			checkpoint_path: None,
+			canonicalized_events: None,
+			on_inconsistent_leaf_count: Default::default(),
+			offchain_storage_timeout: Duration::ZERO,
+			absent_pallet_log_level: log::Level::Info,
+			prometheus_registry: None,
+			canonical_key_encoding: Default::default(),
+			fork_discriminator: Default::default(),
+			canonicalize_scope: Default::default(),
+			header_provider: None,
+			root_hasher: None,
+			read_concurrency: 1,
+			catch_up_order: Default::default(),
+			max_offchain_key_len: DEFAULT_MAX_OFFCHAIN_KEY_LEN,
+			telemetry: None,
+			telemetry_interval: DEFAULT_TELEMETRY_INTERVAL,
+			startup_consistency_check: Default::default(),
+			executor: Default::default(),
+		}
+	}
+}
+
+impl<B: Block> MmrGadgetConfig<B> {
+	/// Subscribe to the [`CanonicalizedEvent`]s of the gadget started with this configuration.
+	///
+	/// Can be called any number of times before starting the gadget. Subscribers lagging behind
+	/// by more than [`CANONICALIZED_EVENTS_CAPACITY`] events miss the oldest ones.
+	pub fn subscribe_canonicalized(&mut self) -> broadcast::Receiver<CanonicalizedEvent<B>> {
+		self.canonicalized_events
+			.get_or_insert_with(|| broadcast::channel(CANONICALIZED_EVENTS_CAPACITY).0)
+			.subscribe()
+	}
+
+	/// Raise the thresholds set to zero to their minimum of `1`, with a warning.
+	fn clamp_thresholds(&mut self) {
+		if let PruningMode::Background { queue_size } = &mut self.pruning {
+			if *queue_size == 0 {
+				warn!(target: LOG_TARGET, "Pruning queue size can't be 0, using 1 instead.");
+				*queue_size = 1;
+			}
+		}
+		if self.max_buffered_notifications == 0 {
+			warn!(
+				target: LOG_TARGET,
+				"Maximum number of buffered notifications can't be 0, using 1 instead."
+			);
+			self.max_buffered_notifications = 1;
+		}
+	}
+}
+
+/// Call `get_storage` until it returns some offchain storage, for at most `timeout`.
+async fn wait_for_offchain_storage<S>(
+	mut get_storage: impl FnMut() -> Option<S>,
+	timeout: Duration,
+) -> Option<S> {
+	let deadline = Instant::now() + timeout;
+	loop {
+		if let Some(storage) = get_storage() {
+			return Some(storage)
+		}
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		if remaining.is_zero() {
+			return None
+		}
+		tokio::time::sleep(remaining.min(OFFCHAIN_STORAGE_POLL_INTERVAL)).await;
+	}
+}
+
+/// Delete the offchain entries received through `keys`, until the sending side is dropped.
+async fn prune_in_background<S: OffchainStorage>(
+	mut offchain_db: OffchainDb<S>,
+	mut keys: mpsc::Receiver<Vec<Vec<u8>>>,
+) {
+	while let Some(keys) = keys.next().await {
+		for key in keys {
+			offchain_db.local_storage_clear(StorageKind::PERSISTENT, &key);
+			debug!(target: LOG_TARGET, "Pruned elem with temp key {:?} in background", key);
+		}
 	}
 }
 
@@ -179,20 +692,290 @@ where
 	C: MmrClient<B, BE>,
 	C::Api: MmrApi<B, MmrRootHash, NumberFor<B>>,
 {
-	async fn run(mut self, builder: OffchainMmrBuilder<B, BE, C>) {
-		let mut offchain_mmr = match builder.try_build(&mut self.finality_notifications).await {
-			Some(offchain_mmr) => offchain_mmr,
-			None => return,
+	async fn run(mut self, mut builder: OffchainMmrBuilder<B, BE, C>) {
+		let offchain_db = builder.offchain_db.clone();
+		let status = builder.config.status.clone();
+		let pruning = builder.config.pruning;
+		let control = builder.config.control.take();
+		let max_buffered_notifications = builder.config.max_buffered_notifications;
+		let offchain_mmr = match builder.try_build(&mut self.finality_notifications).await {
+			Ok(offchain_mmr) => offchain_mmr,
+			Err(e) => {
+				if let Error::FinalityStreamClosed = e {
+					status.record_finality_stream_closed();
+				}
+				error!(
+					target: LOG_TARGET,
+					"Couldn't build the canonicalization engine: {}", e
+				);
+				return
+			},
 		};
 
-		while let Some(notification) = self.finality_notifications.next().await {
-			offchain_mmr.canonicalize_and_prune(notification);
+		match pruning {
+			PruningMode::Inline =>
+				self.process_finality_notifications(
+					offchain_mmr,
+					None,
+					control,
+					max_buffered_notifications,
+				)
+				.await,
+			PruningMode::Background { queue_size } => {
+				let (sender, receiver) = mpsc::channel(queue_size);
+				futures::join!(
+					self.process_finality_notifications(
+						offchain_mmr,
+						Some(sender),
+						control,
+						max_buffered_notifications,
+					),
+					prune_in_background(offchain_db, receiver),
+				);
+			},
 		}
 	}
 
+	/// Send the keys queued for pruning by `offchain_mmr` to the background pruning task, if any.
+	async fn send_prune_queue(
+		offchain_mmr: &mut OffchainMmr<B, BE, C>,
+		prune_sender: &mut Option<mpsc::Sender<Vec<Vec<u8>>>>,
+	) {
+		if let Some(sender) = prune_sender {
+			let keys = offchain_mmr.take_prune_queue();
+			if !keys.is_empty() && sender.send(keys).await.is_err() {
+				warn!(target: LOG_TARGET, "Background pruning task stopped unexpectedly.");
+			}
+		}
+	}
+
+	async fn process_finality_notifications(
+		&mut self,
+		mut offchain_mmr: OffchainMmr<B, BE, C>,
+		mut prune_sender: Option<mpsc::Sender<Vec<Vec<u8>>>>,
+		mut control: Option<mpsc::UnboundedReceiver<ControlMessage>>,
+		max_buffered_notifications: usize,
+	) {
+		Self::send_prune_queue(&mut offchain_mmr, &mut prune_sender).await;
+
+		let mut pending = VecDeque::new();
+		let mut paused = false;
+		loop {
+			// While paused, stop pulling notifications once the buffer is full. The remaining
+			// ones wait in the finality stream until the gadget is resumed.
+			let buffer_full = paused && pending.len() >= max_buffered_notifications;
+			let mut control_closed = false;
+			{
+				let next_notification = if buffer_full {
+					future::pending().right_future()
+				} else {
+					self.finality_notifications.next().left_future()
+				};
+				let next_message = match control.as_mut() {
+					Some(control) => control.next().left_future(),
+					None => future::pending().right_future(),
+				};
+				futures::select! {
+					notification = next_notification.fuse() => match notification {
+						Some(notification) => pending.push_back(notification),
+						None => {
+							offchain_mmr.status().record_finality_stream_closed();
+							return
+						},
+					},
+					message = next_message.fuse() => match message {
+						Some(ControlMessage::Pause) => {
+							debug!(target: LOG_TARGET, "MMR gadget paused.");
+							paused = true;
+						},
+						Some(ControlMessage::Resume) => {
+							debug!(target: LOG_TARGET, "MMR gadget resumed.");
+							paused = false;
+						},
+						None => control_closed = true,
+					},
+				}
+			}
+			if control_closed {
+				// Nobody is left to resume the gadget.
+				control = None;
+				paused = false;
+			}
+
+			// Also take the notifications that are already available, so that the status
+			// reflects how far behind the finality stream we are.
+			while !paused || pending.len() < max_buffered_notifications {
+				match self.finality_notifications.next().now_or_never() {
+					Some(Some(notification)) => pending.push_back(notification),
+					_ => break,
+				}
+			}
+			if let Some(latest) = pending.back() {
+				offchain_mmr.status().record_notification_received(*latest.header.number());
+			}
+			if paused {
+				continue
+			}
+
+			for notification in pending.drain(..) {
+				let block_num = *notification.header.number();
+				offchain_mmr.canonicalize_and_prune(notification);
+				offchain_mmr.status().record_notification_processed(block_num);
+				Self::send_prune_queue(&mut offchain_mmr, &mut prune_sender).await;
+			}
+			offchain_mmr.report_progress_if_due();
+		}
+	}
+
+	/// Create a builder for the MMR gadget, to set options before starting it.
+	pub fn builder(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: impl Into<IndexingPrefix>,
+	) -> MmrGadgetBuilder<B, BE, C> {
+		MmrGadgetBuilder::new(client, backend, indexing_prefix)
+	}
+
 	/// Create and run the MMR gadget.
-	pub async fn start(client: Arc<C>, backend: Arc<BE>, indexing_prefix: Vec<u8>) {
-		let offchain_db = match backend.offchain_storage() {
+	pub async fn start(client: Arc<C>, backend: Arc<BE>, indexing_prefix: impl Into<IndexingPrefix>)
+	where
+		BE: 'static,
+		C: 'static,
+	{
+		Self::builder(client, backend, indexing_prefix).start().await
+	}
+
+	/// Create and run the MMR gadget, reporting its state through `status`.
+	pub async fn start_with_status(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: impl Into<IndexingPrefix>,
+		status: MmrGadgetStatus<B>,
+	) where
+		BE: 'static,
+		C: 'static,
+	{
+		Self::builder(client, backend, indexing_prefix).status(status).start().await
+	}
+
+	/// Canonicalize the MMR nodes of all the blocks finalized so far, then return.
+	///
+	/// Unlike [`MmrGadget::start`], this doesn't follow finality afterwards, which suits one-shot
+	/// batch jobs. Stale forks aren't pruned.
+	pub fn run_catch_up_only(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: impl Into<IndexingPrefix>,
+		config: MmrGadgetConfig<B>,
+	) -> Result<CatchUpSummary, Error> {
+		let offchain_db = backend
+			.offchain_storage()
+			.map(OffchainDb::new)
+			.ok_or(Error::NoOffchainStorage)?;
+		let info = client.info();
+		let first_mmr_block_num = detect_first_mmr_block::<B, BE, C>(
+			&*client,
+			info.finalized_number,
+			config.on_inconsistent_leaf_count,
+		)?
+		.ok_or_else(|| Error::PalletNotDetected(format!("{:?}", info.finalized_number)))?;
+
+		let mut offchain_mmr = OffchainMmr::new(
+			backend,
+			client,
+			offchain_db,
+			indexing_prefix,
+			first_mmr_block_num,
+			config,
+		)?;
+		let summary =
+			offchain_mmr.canonicalize_finalized(info.finalized_hash, info.finalized_number);
+		info!(
+			target: LOG_TARGET,
+			"MMR catch-up canonicalized {} nodes of {} blocks up to block #{:?}",
+			summary.nodes,
+			summary.blocks,
+			info.finalized_number
+		);
+		Ok(summary)
+	}
+
+	/// Create and run the MMR gadget using the provided configuration.
+	///
+	/// The finality notifications are processed on the [`MmrGadgetConfig::executor`].
+	pub async fn start_with_config(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: impl Into<IndexingPrefix>,
+		mut config: MmrGadgetConfig<B>,
+	) where
+		BE: 'static,
+		C: 'static,
+	{
+		let indexing_prefix = indexing_prefix.into();
+		let done = match std::mem::take(&mut config.executor) {
+			GadgetExecutor::InPlace =>
+				return Self::run_with_config(client, backend, indexing_prefix, config).await,
+			GadgetExecutor::Runtime(handle) => {
+				let (done_sender, done) = oneshot::channel();
+				handle.clone().spawn_blocking(move || {
+					handle.block_on(Self::run_with_config(
+						client,
+						backend,
+						indexing_prefix,
+						config,
+					));
+					let _ = done_sender.send(());
+				});
+				done
+			},
+			GadgetExecutor::DedicatedThread => {
+				let (done_sender, done) = oneshot::channel();
+				let spawned =
+					std::thread::Builder::new().name("mmr-gadget".into()).spawn(move || {
+						Self::run_on_own_runtime(client, backend, indexing_prefix, config);
+						let _ = done_sender.send(());
+					});
+				if let Err(err) = spawned {
+					error!(target: LOG_TARGET, "Can't spawn the MMR gadget thread: {}", err);
+					return
+				}
+				done
+			},
+		};
+		if done.await.is_err() {
+			error!(target: LOG_TARGET, "MMR gadget stopped without completing.");
+		}
+	}
+
+	/// Run the gadget on a new single-threaded runtime, blocking the current thread until the
+	/// gadget stops.
+	fn run_on_own_runtime(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: IndexingPrefix,
+		config: MmrGadgetConfig<B>,
+	) {
+		match tokio::runtime::Builder::new_current_thread().enable_time().build() {
+			Ok(runtime) =>
+				runtime.block_on(Self::run_with_config(client, backend, indexing_prefix, config)),
+			Err(err) => error!(target: LOG_TARGET, "Can't build the MMR gadget runtime: {}", err),
+		}
+	}
+
+	async fn run_with_config(
+		client: Arc<C>,
+		backend: Arc<BE>,
+		indexing_prefix: IndexingPrefix,
+		mut config: MmrGadgetConfig<B>,
+	) {
+		config.clamp_thresholds();
+		let offchain_storage = wait_for_offchain_storage(
+			|| backend.offchain_storage(),
+			config.offchain_storage_timeout,
+		)
+		.await;
+		let offchain_db = match offchain_storage {
 			Some(offchain_storage) => OffchainDb::new(offchain_storage),
 			None => {
 				warn!(
@@ -214,6 +997,7 @@ where
 				client,
 				offchain_db,
 				indexing_prefix,
+				config,
 				_phantom: Default::default(),
 			})
 			.await
@@ -222,14 +1006,30 @@ where
 
 #[cfg(test)]
 mod tests {
-	use crate::test_utils::run_test_with_mmr_gadget;
+	use crate::{
+		test_utils::{
+			run_test_with_mmr_gadget, run_test_with_mmr_gadget_and_config,
+			run_test_with_mmr_gadget_and_status, wait_for_canonicalized, MockClient, MockClock,
+			MockRuntimeApi,
+		},
+		wait_for_offchain_storage, AbsentPalletLog, CanonicalizedEvent, CatchUpSummary, Error,
+		GadgetExecutor, MmrClient, MmrGadget, MmrGadgetConfig, MmrGadgetControl, MmrGadgetStatus,
+		MmrTelemetry, OffchainMmrBuilder, OnInconsistentLeafCount, PruningMode, TelemetryEvent,
+	};
+	use futures::{FutureExt, StreamExt};
+	use parking_lot::Mutex;
+	use sc_client_api::BlockchainEvents;
+	use sc_utils::mpsc::tracing_unbounded;
 	use sp_runtime::generic::BlockId;
-	use std::time::Duration;
+	use std::{cell::Cell, sync::Arc, time::Duration};
+	use substrate_test_runtime_client::{runtime::Block, Backend};
 
 	#[test]
 	fn mmr_first_block_is_computed_correctly() {
 		// Check the case where the first block is also the first block with MMR.
-		run_test_with_mmr_gadget(|client| async move {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
 			// G -> A1 -> A2
 			//      |
 			//      | -> first mmr block
@@ -238,14 +1038,16 @@ mod tests {
 			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
 
 			client.finalize_block(a1.hash(), Some(1));
-			tokio::time::sleep(Duration::from_millis(200)).await;
+			wait_for_canonicalized(&status_clone, 1).await;
 			// expected finalized heads: a1
 			client.assert_canonicalized(&[&a1]);
 			client.assert_not_pruned(&[&a2]);
 		});
 
 		// Check the case where the first block with MMR comes later.
-		run_test_with_mmr_gadget(|client| async move {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
 			// G -> A1 -> A2 -> A3 -> A4 -> A5 -> A6
 			//                        |
 			//                        | -> first mmr block
@@ -258,13 +1060,221 @@ mod tests {
 			let a6 = client.import_block(&BlockId::Hash(a5.hash()), b"a6", Some(2)).await;
 
 			client.finalize_block(a5.hash(), Some(2));
-			tokio::time::sleep(Duration::from_millis(200)).await;
+			wait_for_canonicalized(&status_clone, 5).await;
 			// expected finalized heads: a4, a5
 			client.assert_canonicalized(&[&a4, &a5]);
 			client.assert_not_pruned(&[&a6]);
 		});
 	}
 
+	#[test]
+	fn first_mmr_block_is_exposed_via_status() {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
+			// G -> A1 -> A2 -> A3 -> A4
+			//            |
+			//            | -> first mmr block
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", None).await;
+			assert_eq!(status_clone.first_mmr_block(), None);
+
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(0)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(1)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(2)).await;
+
+			client.finalize_block(a4.hash(), Some(3));
+			wait_for_canonicalized(&status_clone, 4).await;
+			assert_eq!(status_clone.first_mmr_block(), Some(2));
+			client.assert_canonicalized(&[&a2, &a3, &a4]);
+		});
+	}
+
+	#[test]
+	fn stall_detection_follows_clock() {
+		let clock = Arc::new(MockClock::default());
+		let status = MmrGadgetStatus::with_clock(clock.clone());
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
+			let threshold = Duration::from_secs(10);
+			assert!(!status_clone.is_stalled(threshold));
+			clock.advance(Duration::from_secs(11));
+			assert!(status_clone.is_stalled(threshold));
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			client.finalize_block(a1.hash(), Some(1));
+			wait_for_canonicalized(&status_clone, 1).await;
+			assert_eq!(status_clone.best_canonicalized(), Some(1));
+			assert!(!status_clone.is_stalled(threshold));
+
+			clock.advance(Duration::from_secs(5));
+			assert!(!status_clone.is_stalled(threshold));
+			clock.advance(Duration::from_secs(6));
+			assert!(status_clone.is_stalled(threshold));
+		});
+	}
+
+	#[test]
+	fn processing_lag_tracks_notification_backlog() {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
+			// G -> A1 -> A2 -> A3 -> A4
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+			client.finalize_block(a1.hash(), Some(1));
+			wait_for_canonicalized(&status_clone, 1).await;
+			client.assert_canonicalized(&[&a1]);
+			assert_eq!(status_clone.processing_lag(), 0);
+
+			// Stall the gadget while it processes a2, then finalize the rest in a burst.
+			client.stall_runtime_api(true);
+			client.finalize_block(a2.hash(), Some(2));
+			client.finalize_block(a3.hash(), Some(3));
+			client.finalize_block(a4.hash(), Some(4));
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			client.assert_not_canonicalized(&[&a2, &a3, &a4]);
+			assert!(status_clone.processing_lag() > 0);
+
+			client.stall_runtime_api(false);
+			wait_for_canonicalized(&status_clone, 4).await;
+			client.assert_canonicalized(&[&a2, &a3, &a4]);
+			assert_eq!(status_clone.processing_lag(), 0);
+		});
+	}
+
+	#[test]
+	fn first_mmr_block_is_looked_up_once_per_notification() {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
+			// G -> A1 -> A2 -> ... -> A10
+			//      |
+			//      | -> first mmr block
+
+			let mut blocks = Vec::new();
+			let mut parent = BlockId::Number(0);
+			for i in 0..10u64 {
+				let block =
+					client.import_block(&parent, format!("a{}", i + 1).as_bytes(), Some(i)).await;
+				parent = BlockId::Hash(block.hash());
+				blocks.push(block);
+			}
+
+			for (i, block) in blocks.iter().enumerate() {
+				client.finalize_block(block.hash(), Some(i as u64 + 1));
+				wait_for_canonicalized(&status_clone, i as u64 + 1).await;
+			}
+
+			client.assert_canonicalized(&blocks.iter().collect::<Vec<_>>());
+			// The notification that started the gadget is only looked up once, and so is every
+			// following one, to detect pallet resets.
+			assert_eq!(client.mmr_leaf_count_calls(), blocks.len());
+		});
+	}
+
+	#[test]
+	fn paused_gadget_buffers_notifications_until_resumed() {
+		let (control, control_receiver) = MmrGadgetControl::new();
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		let config = MmrGadgetConfig {
+			status,
+			control: Some(control_receiver),
+			max_buffered_notifications: 2,
+			..Default::default()
+		};
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2 -> A3 -> A4
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			client.finalize_block(a1.hash(), Some(1));
+			wait_for_canonicalized(&status_clone, 1).await;
+			client.assert_canonicalized(&[&a1]);
+
+			control.pause();
+			tokio::time::sleep(Duration::from_millis(50)).await;
+
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+			// Finalize the blocks one by one, so that there is one notification per block. Only
+			// 2 of them get buffered, the last one stays in the finality stream.
+			client.finalize_block(a2.hash(), Some(2));
+			client.finalize_block(a3.hash(), Some(3));
+			client.finalize_block(a4.hash(), Some(4));
+			tokio::time::sleep(Duration::from_millis(200)).await;
+			client.assert_not_canonicalized(&[&a2, &a3, &a4]);
+			assert_eq!(status_clone.best_canonicalized(), Some(1));
+			assert_eq!(status_clone.processing_lag(), 2);
+
+			control.resume();
+			wait_for_canonicalized(&status_clone, 4).await;
+			client.assert_canonicalized(&[&a2, &a3, &a4]);
+			assert_eq!(status_clone.best_canonicalized(), Some(4));
+			assert_eq!(status_clone.processing_lag(), 0);
+		});
+	}
+
+	#[test]
+	fn catch_up_only_canonicalizes_finalized_blocks() {
+		let client = Arc::new(MockClient::new());
+		let catch_up = |client: &Arc<MockClient>| {
+			MmrGadget::run_catch_up_only(
+				client.clone(),
+				client.backend.clone(),
+				MockRuntimeApi::INDEXING_PREFIX.to_vec(),
+				Default::default(),
+			)
+		};
+		// Nothing was finalized yet.
+		assert_eq!(catch_up(&client).unwrap(), CatchUpSummary::default());
+
+		// G -> A1 -> A2 -> A3 -> A4 -> A5
+		let (a1, a2, a3, a4, a5) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+			let a5 = client.import_block(&BlockId::Hash(a4.hash()), b"a5", Some(4)).await;
+			(a1, a2, a3, a4, a5)
+		});
+		client.finalize_block(a4.hash(), Some(4));
+
+		assert_eq!(catch_up(&client).unwrap(), CatchUpSummary { blocks: 4, nodes: 7 });
+		client.assert_canonicalized(&[&a1, &a2, &a3, &a4]);
+		client.assert_not_canonicalized(&[&a5]);
+
+		// Running it again resumes from where the previous run stopped.
+		client.finalize_block(a5.hash(), Some(5));
+		assert_eq!(catch_up(&client).unwrap(), CatchUpSummary { blocks: 1, nodes: 1 });
+		client.assert_canonicalized(&[&a5]);
+	}
+
+	#[test]
+	fn zero_thresholds_are_clamped() {
+		let mut config = MmrGadgetConfig::<Block> {
+			pruning: PruningMode::Background { queue_size: 0 },
+			max_buffered_notifications: 0,
+			..Default::default()
+		};
+		config.clamp_thresholds();
+		assert_eq!(config.pruning, PruningMode::Background { queue_size: 1 });
+		assert_eq!(config.max_buffered_notifications, 1);
+
+		let mut config = MmrGadgetConfig::<Block> {
+			pruning: PruningMode::Background { queue_size: 8 },
+			max_buffered_notifications: 16,
+			..Default::default()
+		};
+		config.clamp_thresholds();
+		assert_eq!(config.pruning, PruningMode::Background { queue_size: 8 });
+		assert_eq!(config.max_buffered_notifications, 16);
+	}
+
 	#[test]
 	fn does_not_panic_on_invalid_num_mmr_blocks() {
 		run_test_with_mmr_gadget(|client| async move {
@@ -282,4 +1292,264 @@ mod tests {
 			client.assert_not_canonicalized(&[&a1]);
 		});
 	}
+
+	#[test]
+	fn canonicalized_events_are_broadcast_in_order() {
+		let status = MmrGadgetStatus::default();
+		let mut config = MmrGadgetConfig { status: status.clone(), ..Default::default() };
+		let mut events = config.subscribe_canonicalized();
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2 -> A3
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			client.finalize_block(a3.hash(), Some(3));
+			wait_for_canonicalized(&status, 3).await;
+
+			let expected =
+				[(1, a1.hash(), vec![0]), (2, a2.hash(), vec![1, 2]), (3, a3.hash(), vec![3])];
+			for (block_number, block_hash, moved_positions) in expected {
+				assert_eq!(
+					events.try_recv().unwrap(),
+					CanonicalizedEvent { block_number, block_hash, moved_positions }
+				);
+			}
+			assert!(events.try_recv().is_err());
+		});
+	}
+
+	#[test]
+	fn inconsistent_leaf_count_follows_policy() {
+		let client = Arc::new(MockClient::new());
+		let catch_up = |on_inconsistent_leaf_count| {
+			MmrGadget::run_catch_up_only(
+				client.clone(),
+				client.backend.clone(),
+				MockRuntimeApi::INDEXING_PREFIX.to_vec(),
+				MmrGadgetConfig { on_inconsistent_leaf_count, ..Default::default() },
+			)
+		};
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		// The runtime claims there are 2 MMR blocks when in fact there is only 1.
+		client.finalize_block(a1.hash(), Some(2));
+
+		assert!(matches!(
+			catch_up(OnInconsistentLeafCount::Skip),
+			Err(Error::PalletNotDetected(_))
+		));
+		assert!(matches!(
+			catch_up(OnInconsistentLeafCount::Error),
+			Err(Error::InconsistentLeafCount(_))
+		));
+		client.assert_not_canonicalized(&[&a1]);
+	}
+
+	#[test]
+	fn gadget_stops_on_inconsistent_leaf_count_with_error_policy() {
+		let config = MmrGadgetConfig {
+			on_inconsistent_leaf_count: OnInconsistentLeafCount::Error,
+			..Default::default()
+		};
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			client.finalize_block(a1.hash(), Some(2));
+			tokio::time::sleep(Duration::from_millis(200)).await;
+
+			// With `Skip`, the gadget would start on this consistent notification.
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			client.finalize_block(a2.hash(), Some(2));
+			tokio::time::sleep(Duration::from_millis(200)).await;
+			client.assert_not_canonicalized(&[&a1, &a2]);
+		});
+	}
+
+	#[test]
+	fn waits_for_offchain_storage_up_to_timeout() {
+		let runtime = tokio::runtime::Runtime::new().unwrap();
+		let calls = Cell::new(0);
+		let flaky_storage = || {
+			calls.set(calls.get() + 1);
+			(calls.get() > 1).then_some(())
+		};
+
+		// Only checked once with the default timeout.
+		assert_eq!(
+			runtime.block_on(wait_for_offchain_storage(flaky_storage, Duration::ZERO)),
+			None
+		);
+		assert_eq!(calls.get(), 1);
+
+		calls.set(0);
+		assert_eq!(
+			runtime.block_on(wait_for_offchain_storage(flaky_storage, Duration::from_secs(5))),
+			Some(())
+		);
+		assert_eq!(calls.get(), 2);
+	}
+
+	#[test]
+	fn absent_pallet_is_logged_once() {
+		let mut absent_pallet_log = AbsentPalletLog::new(log::Level::Info);
+
+		let logged = (0..100).filter(|_| absent_pallet_log.record_absent()).count();
+		assert_eq!(logged, 1);
+	}
+
+	#[derive(Clone, Default)]
+	struct MockTelemetry(Arc<Mutex<Vec<TelemetryEvent<u64>>>>);
+
+	impl MmrTelemetry<u64> for MockTelemetry {
+		fn report(&self, event: TelemetryEvent<u64>) {
+			self.0.lock().push(event);
+		}
+	}
+
+	#[test]
+	fn progress_is_reported_to_telemetry() {
+		let telemetry = MockTelemetry::default();
+		let events = telemetry.0.clone();
+		let status = MmrGadgetStatus::default();
+		let config = MmrGadgetConfig {
+			status: status.clone(),
+			telemetry: Some(Box::new(telemetry)),
+			telemetry_interval: Duration::ZERO,
+			..Default::default()
+		};
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2 -> A3
+			//      |     |
+			//      |     | -> finalized after catching up
+			//      |
+			//      | -> first mmr block, finalized to start the gadget
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			client.finalize_block(a1.hash(), Some(1));
+			wait_for_canonicalized(&status, 1).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			client.finalize_block(a3.hash(), Some(3));
+			tokio::time::sleep(Duration::from_millis(200)).await;
+
+			assert_eq!(
+				*events.lock(),
+				vec![
+					TelemetryEvent::CaughtUp { blocks: 1, best_canonicalized: 1 },
+					TelemetryEvent::Canonicalized { best_canonicalized: 3 },
+				]
+			);
+		});
+	}
+
+	#[test]
+	fn mmr_enabled_from_block_one_is_canonicalized() {
+		let status = MmrGadgetStatus::<Block>::default();
+		run_test_with_mmr_gadget_and_status(status.clone(), |client| async move {
+			// G -> A1
+			//      |
+			//      | -> first mmr block, with a single leaf
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			client.finalize_block(a1.hash(), Some(1));
+			assert_eq!(
+				MmrClient::<Block, Backend>::try_first_mmr_block_num_at(&*client, 1).unwrap(),
+				Some(1)
+			);
+			wait_for_canonicalized(&status, 1).await;
+
+			// The catch-up stops at genesis instead of walking below it.
+			client.assert_canonicalized(&[&a1]);
+			assert_eq!(status.first_mmr_block(), Some(1));
+			assert_eq!(status.best_canonicalized(), Some(1));
+		});
+	}
+
+	#[test]
+	fn closed_finality_stream_is_reported() {
+		let client = Arc::new(MockClient::new());
+		let run_gadget = |finality_notifications, status: &MmrGadgetStatus<Block>| {
+			let gadget = MmrGadget::<Block, Backend, MockClient> {
+				finality_notifications,
+				_phantom: Default::default(),
+			};
+			futures::executor::block_on(gadget.run(OffchainMmrBuilder {
+				backend: client.backend.clone(),
+				client: client.clone(),
+				offchain_db: client.offchain_db(),
+				indexing_prefix: MockRuntimeApi::INDEXING_PREFIX.to_vec().into(),
+				config: MmrGadgetConfig { status: status.clone(), ..Default::default() },
+				_phantom: Default::default(),
+			}))
+		};
+
+		// Closed before `pallet-mmr` is detected.
+		let status = MmrGadgetStatus::default();
+		let (sender, receiver) = tracing_unbounded("mpsc_mmr_gadget_test", 100);
+		drop(sender);
+		assert!(status.is_finality_stream_open());
+		run_gadget(receiver, &status);
+		assert!(!status.is_finality_stream_open());
+
+		// Closed while following finality.
+		let status = MmrGadgetStatus::default();
+		let mut finality_notifications = client.finality_notification_stream();
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		client.finalize_block(a1.hash(), Some(1));
+		let (sender, receiver) = tracing_unbounded("mpsc_mmr_gadget_test", 100);
+		sender
+			.unbounded_send(futures::executor::block_on(finality_notifications.next()).unwrap())
+			.unwrap();
+		drop(sender);
+		assert!(status.is_finality_stream_open());
+		run_gadget(receiver, &status);
+		client.assert_canonicalized(&[&a1]);
+		assert!(!status.is_finality_stream_open());
+	}
+
+	#[test]
+	fn wait_for_canonicalized_resolves_once_block_is_canonicalized() {
+		let status = MmrGadgetStatus::<Block>::default();
+		let waiting = status.wait_for_canonicalized(3);
+		futures::pin_mut!(waiting);
+		let mut cx = futures::task::Context::from_waker(futures::task::noop_waker_ref());
+
+		assert!(waiting.poll_unpin(&mut cx).is_pending());
+		status.record_canonicalized(2);
+		assert!(waiting.poll_unpin(&mut cx).is_pending());
+		status.record_canonicalized(3);
+		assert!(waiting.poll_unpin(&mut cx).is_ready());
+
+		// Already canonicalized blocks don't wait.
+		futures::executor::block_on(status.wait_for_canonicalized(1));
+	}
+
+	#[test]
+	fn gadget_runs_on_provided_executor() {
+		let runtime = tokio::runtime::Runtime::new().unwrap();
+		let executors =
+			[GadgetExecutor::Runtime(runtime.handle().clone()), GadgetExecutor::DedicatedThread];
+		for executor in executors {
+			let status = MmrGadgetStatus::<Block>::default();
+			let config = MmrGadgetConfig { status: status.clone(), executor, ..Default::default() };
+			run_test_with_mmr_gadget_and_config(config, |client| async move {
+				// G -> A1 -> A2
+
+				let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+				let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+				client.finalize_block(a2.hash(), Some(2));
+				status.wait_for_canonicalized(2).await;
+
+				client.assert_canonicalized(&[&a1, &a2]);
+			});
+		}
+		// The gadget never stops, don't wait for its blocking thread.
+		runtime.shutdown_background();
+	}
 }
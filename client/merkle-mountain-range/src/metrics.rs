@@ -0,0 +1,85 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! MMR gadget Prometheus metrics definition.
+
+use prometheus::{
+	register, Counter, CounterVec, Histogram, HistogramOpts, Opts, PrometheusError, Registry, U64,
+};
+
+/// Buckets of the offchain storage latency histograms, in seconds.
+const LATENCY_BUCKETS: [f64; 9] =
+	[0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+/// MMR gadget metrics exposed through Prometheus.
+pub(crate) struct Metrics {
+	/// Time spent reading from the offchain storage.
+	pub offchain_read_time: Histogram,
+	/// Time spent writing canonical entries.
+	pub offchain_write_time: Histogram,
+	/// Time spent deleting from the offchain storage.
+	pub offchain_delete_time: Histogram,
+	/// Number of canonicalized MMR nodes, labeled by `kind`: `leaf` or internal `node`.
+	pub canonicalized_entries: CounterVec<U64>,
+	/// Number of canonicalized blocks whose canonical MMR root didn't match the runtime one.
+	pub root_mismatches: Counter<U64>,
+}
+
+impl Metrics {
+	pub(crate) fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+		let histogram = |name: &str, help: &str| -> Result<Histogram, PrometheusError> {
+			register(
+				Histogram::with_opts(
+					HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec()),
+				)?,
+				registry,
+			)
+		};
+		Ok(Self {
+			offchain_read_time: histogram(
+				"substrate_mmr_gadget_offchain_read_time",
+				"Time taken by the MMR gadget to read an offchain storage entry",
+			)?,
+			offchain_write_time: histogram(
+				"substrate_mmr_gadget_offchain_write_time",
+				"Time taken by the MMR gadget to write a canonical entry",
+			)?,
+			offchain_delete_time: histogram(
+				"substrate_mmr_gadget_offchain_delete_time",
+				"Time taken by the MMR gadget to delete an offchain storage entry",
+			)?,
+			canonicalized_entries: register(
+				CounterVec::new(
+					Opts::new(
+						"substrate_mmr_gadget_canonicalized_entries",
+						"Number of MMR nodes canonicalized by the MMR gadget",
+					),
+					&["kind"],
+				)?,
+				registry,
+			)?,
+			root_mismatches: register(
+				Counter::new(
+					"substrate_mmr_gadget_root_mismatches",
+					"Number of blocks whose canonical MMR entries don't match the runtime MMR root",
+				)?,
+				registry,
+			)?,
+		})
+	}
+}
@@ -21,28 +21,148 @@
 
 #![warn(missing_docs)]
 
-use crate::{aux_schema, MmrClient, LOG_TARGET};
+pub mod keys;
+
+use crate::{
+	aux_schema, metrics::Metrics, peaks_for_leaf_count, snapshot, CanonicalSink, CanonicalizeScope,
+	CatchUpOrder, Checkpoint, Error, IndexingPrefix, MmrClient, MmrGadgetConfig, MmrGadgetStatus,
+	PruningMode, TelemetryEvent, LOG_TARGET,
+};
 use beefy_primitives::MmrRootHash;
-use log::{debug, error, info, warn};
+use log::{debug, error, info, trace, warn};
 use sc_client_api::{Backend, FinalityNotification};
 use sc_offchain::OffchainDb;
-use sp_blockchain::{CachedHeaderMetadata, ForkBackend};
-use sp_core::offchain::{DbExternalities, StorageKind};
-use sp_mmr_primitives::{utils, utils::NodesUtils, MmrApi, NodeIndex};
+use sp_api::ProvideRuntimeApi;
+use sp_blockchain::{
+	Backend as BlockchainBackend, CachedHeaderMetadata, ForkBackend, HeaderBackend,
+};
+use sp_core::{
+	hexdisplay::HexDisplay,
+	offchain::{DbExternalities, StorageKind},
+};
+use sp_mmr_primitives::{utils, utils::NodesUtils, LeafIndex, MmrApi, NodeIndex};
 use sp_runtime::{
-	traits::{Block, Header, NumberFor, One},
+	generic::BlockId,
+	traits::{Block, Header, NumberFor, One, Zero},
 	Saturating,
 };
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+	collections::{BTreeMap, HashSet, VecDeque},
+	fmt,
+	io::{self, Read, Write},
+	sync::Arc,
+	time::Instant,
+};
+
+/// Number of MMR nodes read at once by [`OffchainMmr::export_canonical`].
+const SNAPSHOT_CHUNK_SIZE: NodeIndex = 1024;
+
+/// Number of MMR node positions checked at once by [`OffchainMmr::verify_canonical_entries`].
+const VERIFY_CHUNK_SIZE: usize = 1024;
+
+/// Number of MMR node positions whose canonical entries [`OffchainMmr::migrate_prefix`] commits
+/// at once.
+const MIGRATION_BATCH_SIZE: NodeIndex = 1024;
+
+/// Options for [`OffchainMmr::migrate_prefix`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrefixMigration {
+	/// Also migrate the fork-aware entries of finalized blocks that weren't canonicalized yet.
+	pub include_fork_entries: bool,
+	/// Remove the entries stored under the old prefix once they have been copied.
+	pub delete_old: bool,
+}
+
+/// Outcome of [`OffchainMmr::canonicalize_finalized`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CatchUpSummary {
+	/// Number of blocks whose MMR nodes were canonicalized.
+	pub blocks: usize,
+	/// Number of MMR nodes moved under canonical keys.
+	pub nodes: usize,
+}
+
+/// Emitted after the MMR nodes added by a block were canonicalized.
+///
+/// With the `serde-events` feature, events can be serialized to forward them to another process.
+/// The block hash is then encoded as a `0x`-prefixed hex string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-events", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde-events", serde(bound = ""))]
+pub struct CanonicalizedEvent<B: Block> {
+	/// Number of the canonicalized block.
+	pub block_number: NumberFor<B>,
+	/// Hash of the canonicalized block.
+	pub block_hash: B::Hash,
+	/// Positions of the MMR nodes moved under canonical keys.
+	pub moved_positions: Vec<NodeIndex>,
+}
+
+/// Number of MMR entries found in the offchain db by [`OffchainMmr::entry_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntryStats {
+	/// Number of entries stored under canonical keys.
+	pub canonical: usize,
+	/// Number of entries stored under fork-aware keys, i.e. not canonicalized or pruned yet.
+	pub fork: usize,
+}
+
+/// Key operations of the canonicalization of a block, computed by
+/// [`OffchainMmr::debug_replay_block`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+	/// Fork-aware keys read, with the position of the MMR node they hold.
+	pub fork_keys_read: Vec<(NodeIndex, Vec<u8>)>,
+	/// Canonical keys written, or that would be written when not committing, with the position
+	/// of the MMR node they hold.
+	pub canonical_keys_written: Vec<(NodeIndex, Vec<u8>)>,
+	/// Positions of the MMR nodes added by the block that have no fork-aware entry.
+	pub missing_positions: Vec<NodeIndex>,
+	/// Whether the canonical entries were actually written.
+	pub committed: bool,
+}
 
 /// `OffchainMMR` exposes MMR offchain canonicalization and pruning logic.
-pub struct OffchainMmr<B: Block, BE: Backend<B>, C> {
+///
+/// Canonicalized entries are written to `S`, the node offchain db by default
+/// (see [`OffchainMmr::with_sink`]).
+pub struct OffchainMmr<
+	B: Block,
+	BE: Backend<B>,
+	C,
+	S = OffchainDb<<BE as Backend<B>>::OffchainStorage>,
+> {
 	backend: Arc<BE>,
 	client: Arc<C>,
 	offchain_db: OffchainDb<BE::OffchainStorage>,
-	indexing_prefix: Vec<u8>,
+	canonical_sink: S,
+	indexing_prefix: IndexingPrefix,
 	first_mmr_block: NumberFor<B>,
+	/// Finalized block at which `first_mmr_block` was last looked up through the runtime API.
+	first_mmr_block_checked_at: Option<B::Hash>,
 	best_canonicalized: NumberFor<B>,
+	config: MmrGadgetConfig<B>,
+	/// Stale blocks whose pruning is postponed until the finalized blocks at the same height
+	/// have been canonicalized (see `MmrGadgetConfig::canonicalize_margin`).
+	deferred_prune: Vec<B::Hash>,
+	/// Keys of stale fork entries waiting to be pruned by a background task
+	/// (see [`PruningMode::Background`]).
+	prune_queue: Vec<Vec<u8>>,
+	metrics: Option<Metrics>,
+	/// `best_canonicalized` when the catch-up started, until it is reported as complete.
+	catch_up_from: Option<NumberFor<B>>,
+	/// When the progress was last reported to `config.telemetry`.
+	last_telemetry_report: Instant,
+	/// Blocks whose canonical entries couldn't be committed, with their hash and the number of
+	/// failed attempts.
+	failed_blocks: BTreeMap<NumberFor<B>, (B::Hash, u32)>,
+	/// Newest canonicalized block, held back from `best_canonicalized` by the failed blocks.
+	held_watermark: Option<(NumberFor<B>, B::Hash)>,
+	/// Blocks out of retries, see [`OffchainMmr::take_canonicalization_errors`].
+	canonicalization_errors: Vec<Error>,
+	/// Number and hash of the blocks of the last finality notifications, oldest first (see
+	/// `MmrGadgetConfig::duplicate_notification_window`).
+	recent_notifications: VecDeque<(NumberFor<B>, B::Hash)>,
 }
 
 impl<B, BE, C> OffchainMmr<B, BE, C>
@@ -52,34 +172,193 @@ where
 	C: MmrClient<B, BE>,
 	C::Api: MmrApi<B, MmrRootHash, NumberFor<B>>,
 {
+	/// Create a new `OffchainMmr` for an MMR whose first leaf was added by `first_mmr_block`.
+	///
+	/// Progress persisted in the aux db by a previous run is resumed, if any.
+	///
+	/// Fails if `first_mmr_block` is the genesis block, which can't add MMR leaves, if the keys
+	/// built from `indexing_prefix` would exceed `MmrGadgetConfig::max_offchain_key_len`, or if
+	/// the gadget state can't be loaded from the aux db.
 	pub fn new(
 		backend: Arc<BE>,
 		client: Arc<C>,
 		offchain_db: OffchainDb<BE::OffchainStorage>,
-		indexing_prefix: Vec<u8>,
+		indexing_prefix: impl Into<IndexingPrefix>,
 		first_mmr_block: NumberFor<B>,
-	) -> Option<Self> {
+		config: MmrGadgetConfig<B>,
+	) -> Result<Self, Error> {
+		if first_mmr_block.is_zero() {
+			return Err(Error::InvalidFirstMmrBlock(
+				"the genesis block can't add leaves to the MMR".into(),
+			))
+		}
+		let indexing_prefix = indexing_prefix.into();
+		check_offchain_key_len::<B>(&indexing_prefix, &config)?;
+		// `first_mmr_block` is at least 1, so this is at least the genesis block, which stops the
+		// catch-up from walking further down the chain.
 		let mut best_canonicalized = first_mmr_block.saturating_sub(One::one());
-		best_canonicalized = aux_schema::load_or_init_state::<B, BE>(&*backend, best_canonicalized)
-			.map_err(|e| error!(target: LOG_TARGET, "Error loading state from aux db: {:?}", e))
-			.ok()?;
+		best_canonicalized =
+			aux_schema::load_or_init_state::<B, BE>(&*backend, best_canonicalized)?;
+		if let Some(path) = &config.checkpoint_path {
+			let checkpoint = Checkpoint::<NumberFor<B>, B::Hash>::read(path)
+				.map_err(|e| Error::Checkpoint(e.to_string()))?;
+			if let Some(checkpoint) = checkpoint {
+				if client.hash(checkpoint.block_number).ok().flatten() ==
+					Some(checkpoint.block_hash)
+				{
+					info!(
+						target: LOG_TARGET,
+						"Resuming MMR canonicalization from checkpoint at block {:?}",
+						checkpoint.block_number
+					);
+					best_canonicalized =
+						checkpoint.block_number.max(first_mmr_block.saturating_sub(One::one()));
+				} else {
+					warn!(
+						target: LOG_TARGET,
+						"Ignoring MMR checkpoint at block {:?}: not part of the canonical chain.",
+						checkpoint.block_number
+					);
+				}
+			}
+		}
+		best_canonicalized = best_canonicalized.max(start_watermark::<B>(&config));
+		config.status.set_first_mmr_block(first_mmr_block);
+		let metrics =
+			config.prometheus_registry.as_ref().map(Metrics::register).and_then(|result| {
+				match result {
+					Ok(metrics) => Some(metrics),
+					Err(e) => {
+						debug!(target: LOG_TARGET, "Failed to register metrics: {:?}", e);
+						None
+					},
+				}
+			});
 
-		Some(Self {
+		Ok(Self {
 			backend,
 			client,
+			canonical_sink: offchain_db.clone(),
 			offchain_db,
 			indexing_prefix,
 			first_mmr_block,
+			first_mmr_block_checked_at: None,
 			best_canonicalized,
+			config,
+			deferred_prune: Vec::new(),
+			prune_queue: Vec::new(),
+			metrics,
+			catch_up_from: Some(best_canonicalized),
+			last_telemetry_report: Instant::now(),
+			failed_blocks: BTreeMap::new(),
+			held_watermark: None,
+			canonicalization_errors: Vec::new(),
+			recent_notifications: VecDeque::new(),
 		})
 	}
 
-	fn node_temp_offchain_key(&self, pos: NodeIndex, parent_hash: B::Hash) -> Vec<u8> {
-		NodesUtils::node_temp_offchain_key::<B::Header>(&self.indexing_prefix, pos, parent_hash)
+	/// Write the canonicalized entries to `sink` instead of the node offchain db.
+	///
+	/// The fork-aware entries written by `pallet-mmr` are still read from, and pruned in, the
+	/// offchain db.
+	pub fn with_sink<S: CanonicalSink>(self, sink: S) -> OffchainMmr<B, BE, C, S> {
+		OffchainMmr {
+			backend: self.backend,
+			client: self.client,
+			offchain_db: self.offchain_db,
+			canonical_sink: sink,
+			indexing_prefix: self.indexing_prefix,
+			first_mmr_block: self.first_mmr_block,
+			first_mmr_block_checked_at: self.first_mmr_block_checked_at,
+			best_canonicalized: self.best_canonicalized,
+			config: self.config,
+			deferred_prune: self.deferred_prune,
+			prune_queue: self.prune_queue,
+			metrics: self.metrics,
+			catch_up_from: self.catch_up_from,
+			last_telemetry_report: self.last_telemetry_report,
+			failed_blocks: self.failed_blocks,
+			held_watermark: self.held_watermark,
+			canonicalization_errors: self.canonicalization_errors,
+			recent_notifications: self.recent_notifications,
+		}
+	}
+}
+
+/// Make sure that the offchain keys of the MMR nodes, built from `indexing_prefix`, don't exceed
+/// `config.max_offchain_key_len`.
+///
+/// The length of the keys doesn't depend on the node position, so checking the keys of the first
+/// node is enough.
+fn check_offchain_key_len<B: Block>(
+	indexing_prefix: &IndexingPrefix,
+	config: &MmrGadgetConfig<B>,
+) -> Result<(), Error> {
+	let prefix = indexing_prefix.as_bytes();
+	let key_len = keys::max_key_len::<B::Header>(prefix, &config.canonical_key_encoding);
+	if key_len > config.max_offchain_key_len {
+		return Err(Error::OffchainKeyTooLong(format!(
+			"keys built from a {} byte indexing prefix take up to {} bytes, more than the maximum \
+			of {}",
+			prefix.len(),
+			key_len,
+			config.max_offchain_key_len
+		)))
+	}
+	Ok(())
+}
+
+impl<B, BE, C, S> OffchainMmr<B, BE, C, S>
+where
+	BE: Backend<B>,
+	B: Block,
+	C: MmrClient<B, BE>,
+	C::Api: MmrApi<B, MmrRootHash, NumberFor<B>>,
+	S: CanonicalSink,
+{
+	/// Record that `first_mmr_block` was derived from the runtime state at block `hash`, so
+	/// that it isn't looked up again when processing the finality notification for `hash`.
+	pub(crate) fn set_first_mmr_block_checked_at(&mut self, hash: B::Hash) {
+		self.first_mmr_block_checked_at = Some(hash);
+	}
+
+	pub(crate) fn indexing_prefix(&self) -> &[u8] {
+		self.indexing_prefix.as_bytes()
+	}
+
+	pub(crate) fn status(&self) -> &MmrGadgetStatus<B> {
+		&self.config.status
+	}
+
+	/// Hash discriminating the fork-aware keys of the nodes added by the block of `header`.
+	fn fork_hash(&self, header: &CachedHeaderMetadata<B>) -> B::Hash {
+		self.config.fork_discriminator.select(header.hash, header.parent)
+	}
+
+	fn node_temp_offchain_key(&self, pos: NodeIndex, fork_hash: B::Hash) -> Vec<u8> {
+		keys::fork_key::<B::Header>(self.indexing_prefix.as_bytes(), pos, fork_hash)
 	}
 
 	fn node_canon_offchain_key(&self, pos: NodeIndex) -> Vec<u8> {
-		NodesUtils::node_canon_offchain_key(&self.indexing_prefix, pos)
+		self.config
+			.canonical_key_encoding
+			.canonical_key(self.indexing_prefix.as_bytes(), pos)
+	}
+
+	fn offchain_get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+		let _timer = self.metrics.as_ref().map(|metrics| metrics.offchain_read_time.start_timer());
+		self.offchain_db.local_storage_get(StorageKind::PERSISTENT, key)
+	}
+
+	fn offchain_set(&mut self, key: &[u8], value: &[u8]) {
+		let _timer = self.metrics.as_ref().map(|metrics| metrics.offchain_write_time.start_timer());
+		self.offchain_db.local_storage_set(StorageKind::PERSISTENT, key, value);
+	}
+
+	fn offchain_clear(&mut self, key: &[u8]) {
+		let _timer =
+			self.metrics.as_ref().map(|metrics| metrics.offchain_delete_time.start_timer());
+		self.offchain_db.local_storage_clear(StorageKind::PERSISTENT, key);
 	}
 
 	fn write_gadget_state_or_log(&self) {
@@ -90,14 +369,25 @@ where
 		}
 	}
 
+	/// Metadata of the header of block `hash`, looked up in the client, then in
+	/// `MmrGadgetConfig::header_provider`.
+	fn header_metadata(&self, hash: B::Hash) -> Option<CachedHeaderMetadata<B>> {
+		self.client.header_metadata(hash).ok().or_else(|| {
+			self.config
+				.header_provider
+				.as_ref()
+				.and_then(|provider| provider.header_metadata(hash))
+		})
+	}
+
 	fn header_metadata_or_log(
 		&self,
 		hash: B::Hash,
 		action: &str,
 	) -> Option<CachedHeaderMetadata<B>> {
-		match self.client.header_metadata(hash) {
-			Ok(header) => Some(header),
-			_ => {
+		match self.header_metadata(hash) {
+			Some(header) => Some(header),
+			None => {
 				debug!(
 					target: LOG_TARGET,
 					"Block {} not found. Couldn't {} associated branch.", hash, action
@@ -141,36 +431,73 @@ where
 			Some(header) => header,
 			_ => return,
 		};
+		if self.config.start_height.map_or(false, |start| header.number < start) {
+			debug!(
+				target: LOG_TARGET,
+				"Not pruning block #{:?} below the start height", header.number
+			);
+			return
+		}
 
 		// We prune the leaf associated with the provided block and all the nodes added by that
 		// leaf.
-		let stale_nodes = match self.right_branch_ending_in_block_or_log(header.number, action) {
-			Some(nodes) => nodes,
-			None => {
-				// If we can't convert the block number to a leaf index, the chain state is probably
-				// corrupted. We only log the error, hoping that the chain state will be fixed.
-				return
-			},
-		};
+		for temp_key in self.fork_key_range(&self.fork_hash(&header), header.number) {
+			match self.config.pruning {
+				PruningMode::Inline => {
+					self.offchain_clear(&temp_key);
+					debug!(target: LOG_TARGET, "Pruned elem with temp key {:?}", temp_key);
+				},
+				PruningMode::Background { .. } => {
+					debug!(
+						target: LOG_TARGET,
+						"Queued elem with temp key {:?} for pruning", temp_key
+					);
+					self.prune_queue.push(temp_key);
+				},
+			}
+		}
+	}
 
-		for pos in stale_nodes {
-			let temp_key = self.node_temp_offchain_key(pos, header.parent);
-			self.offchain_db.local_storage_clear(StorageKind::PERSISTENT, &temp_key);
-			debug!(target: LOG_TARGET, "Pruned elem at pos {} with temp key {:?}", pos, temp_key);
+	/// Fork-aware keys of the MMR nodes added by block `block_num` of the fork identified by
+	/// `fork_hash`.
+	///
+	/// `fork_hash` is the parent hash of the block, or its own hash, depending on
+	/// `MmrGadgetConfig::fork_discriminator`. These are exactly the keys deleted when that block
+	/// gets pruned as part of a stale fork. Nothing is deleted by this method.
+	pub fn fork_key_range(&self, fork_hash: &B::Hash, block_num: NumberFor<B>) -> Vec<Vec<u8>> {
+		match self.right_branch_ending_in_block_or_log(block_num, "prune") {
+			Some(nodes) => nodes
+				.into_iter()
+				.map(|pos| self.node_temp_offchain_key(pos, *fork_hash))
+				.collect(),
+			// If we can't convert the block number to a leaf index, the chain state is probably
+			// corrupted. We only log the error, hoping that the chain state will be fixed.
+			None => Vec::new(),
 		}
 	}
 
-	fn canonicalize_branch(&mut self, block_hash: B::Hash) {
+	/// Take the keys of the stale fork entries that are waiting to be pruned.
+	///
+	/// Only used with [`PruningMode::Background`], where deleting these entries is up to the
+	/// caller.
+	pub fn take_prune_queue(&mut self) -> Vec<Vec<u8>> {
+		std::mem::take(&mut self.prune_queue)
+	}
+
+	/// Returns the number of MMR nodes moved under canonical keys.
+	///
+	/// `self.best_canonicalized` is only moved to the block if `advance_watermark` is set.
+	fn canonicalize_branch(&mut self, block_hash: B::Hash, advance_watermark: bool) -> usize {
 		let action = "canonicalize";
 		let header = match self.header_metadata_or_log(block_hash, action) {
 			Some(header) => header,
-			_ => return,
+			_ => return 0,
 		};
 
 		// Don't canonicalize branches corresponding to blocks for which the MMR pallet
 		// wasn't yet initialized.
 		if header.number < self.first_mmr_block {
-			return
+			return 0
 		}
 
 		// We "canonicalize" the leaf associated with the provided block
@@ -180,26 +507,26 @@ where
 			None => {
 				// If we can't convert the block number to a leaf index, the chain state is probably
 				// corrupted. We only log the error, hoping that the chain state will be fixed.
-				self.best_canonicalized = header.number;
-				return
+				if advance_watermark {
+					self.best_canonicalized = header.number;
+				}
+				return 0
 			},
 		};
 
-		for pos in to_canon_nodes {
-			let temp_key = self.node_temp_offchain_key(pos, header.parent);
-			if let Some(elem) =
-				self.offchain_db.local_storage_get(StorageKind::PERSISTENT, &temp_key)
-			{
-				let canon_key = self.node_canon_offchain_key(pos);
-				self.offchain_db.local_storage_set(StorageKind::PERSISTENT, &canon_key, &elem);
-				self.offchain_db.local_storage_clear(StorageKind::PERSISTENT, &temp_key);
-				debug!(
-					target: LOG_TARGET,
-					"Moved elem at pos {} from temp key {:?} to canon key {:?}",
-					pos,
-					temp_key,
-					canon_key
-				);
+		let fork_hash = self.fork_hash(&header);
+		let canonicalized = self.config.canonicalize_scope.canonicalized_len(to_canon_nodes.len());
+		let mut to_canon_elems = Vec::with_capacity(canonicalized);
+		let mut dropped_temp_keys = Vec::new();
+		for (idx, pos) in to_canon_nodes.into_iter().enumerate() {
+			let temp_key = self.node_temp_offchain_key(pos, fork_hash);
+			if idx >= canonicalized {
+				// Out of the canonicalization scope, the fork-aware entry is only removed.
+				dropped_temp_keys.push(temp_key);
+				continue
+			}
+			if let Some(elem) = self.offchain_get(&temp_key) {
+				to_canon_elems.push((pos, temp_key, elem));
 			} else {
 				debug!(
 					target: LOG_TARGET,
@@ -207,53 +534,442 @@ where
 				);
 			}
 		}
-		if self.best_canonicalized != header.number.saturating_sub(One::one()) {
+
+		let moved = to_canon_elems.len();
+		if let Some(on_before_write) = self.config.on_before_write.as_mut() {
+			on_before_write(moved);
+		}
+		let mut moved_positions = Vec::with_capacity(moved);
+		let mut temp_keys = Vec::with_capacity(moved);
+		self.canonical_sink.begin_block();
+		for (pos, temp_key, elem) in to_canon_elems {
+			moved_positions.push(pos);
+			let canon_key = self.node_canon_offchain_key(pos);
+			{
+				let _timer =
+					self.metrics.as_ref().map(|metrics| metrics.offchain_write_time.start_timer());
+				self.canonical_sink.write(&canon_key, &elem);
+			}
+			debug!(
+				target: LOG_TARGET,
+				"Moved elem at pos {} from temp key {:?} to canon key {:?}",
+				pos,
+				temp_key,
+				canon_key
+			);
+			temp_keys.push(temp_key);
+		}
+		if let Err(e) = self.canonical_sink.commit_block() {
+			self.record_canonicalization_failure(header.number, block_hash, e);
+			return 0
+		}
+		self.failed_blocks.remove(&header.number);
+		if let Some(metrics) = &self.metrics {
+			for pos in &moved_positions {
+				let kind = if is_leaf_pos(*pos) { "leaf" } else { "node" };
+				metrics.canonicalized_entries.with_label_values(&[kind]).inc();
+			}
+		}
+		// Only clear the fork-aware entries once the canonical ones are committed, so that the
+		// block can be canonicalized again if the node crashes in between.
+		for temp_key in temp_keys.into_iter().chain(dropped_temp_keys) {
+			self.offchain_clear(&temp_key);
+		}
+		self.check_root(block_hash, header.number);
+		if advance_watermark {
+			if self.failed_blocks.is_empty() &&
+				self.best_canonicalized != header.number.saturating_sub(One::one())
+			{
+				warn!(
+					target: LOG_TARGET,
+					"Detected canonicalization skip: best {:?} current {:?}.",
+					self.best_canonicalized,
+					header.number,
+				);
+			}
+			self.advance_watermark(header.number, block_hash);
+		}
+		if let Some(events) = &self.config.canonicalized_events {
+			// Sending only fails when there are no subscribers.
+			let _ = events.send(CanonicalizedEvent {
+				block_number: header.number,
+				block_hash,
+				moved_positions,
+			});
+		}
+		moved
+	}
+
+	/// Record that the blocks up to `block_number` were canonicalized.
+	///
+	/// The watermark is held back while an older block is waiting to be retried.
+	fn advance_watermark(&mut self, block_number: NumberFor<B>, block_hash: B::Hash) {
+		let (block_number, block_hash) = match self.held_watermark.take() {
+			Some((held_number, held_hash)) if held_number > block_number =>
+				(held_number, held_hash),
+			_ => (block_number, block_hash),
+		};
+		if self.failed_blocks.keys().next().map_or(false, |failed| *failed < block_number) {
+			self.held_watermark = Some((block_number, block_hash));
+			return
+		}
+		self.best_canonicalized = block_number;
+		self.config.status.record_canonicalized(block_number);
+		self.write_checkpoint_or_log(block_number, block_hash);
+	}
+
+	/// Record that the canonical entries of block `block_hash`, with number `block_number`,
+	/// couldn't be committed because of `error`.
+	fn record_canonicalization_failure(
+		&mut self,
+		block_number: NumberFor<B>,
+		block_hash: B::Hash,
+		error: Error,
+	) {
+		let attempts = {
+			let (_, attempts) = self.failed_blocks.entry(block_number).or_insert((block_hash, 0));
+			*attempts = attempts.saturating_add(1);
+			*attempts
+		};
+		if attempts > self.config.max_canonicalize_retries {
+			let error = Error::CanonicalizationFailed(format!(
+				"block #{:?} ({:?}) after {} attempts: {}",
+				block_number, block_hash, attempts, error
+			));
+			error!(target: LOG_TARGET, "{}", error);
+			self.canonicalization_errors.push(error);
+		} else {
 			warn!(
 				target: LOG_TARGET,
-				"Detected canonicalization skip: best {:?} current {:?}.",
-				self.best_canonicalized,
-				header.number,
+				"Couldn't canonicalize block #{:?} ({:?}), will retry: {}",
+				block_number,
+				block_hash,
+				error
 			);
 		}
-		self.best_canonicalized = header.number;
+	}
+
+	/// Canonicalize again the blocks that failed to, unless they are out of retries.
+	fn retry_failed_blocks(&mut self) {
+		let retries: Vec<_> = self
+			.failed_blocks
+			.values()
+			.filter(|(_, attempts)| *attempts <= self.config.max_canonicalize_retries)
+			.map(|(hash, _)| *hash)
+			.collect();
+		for hash in retries {
+			self.canonicalize_branch(hash, true);
+		}
+	}
+
+	/// Whether block `block_number` was already canonicalized, or is waiting to be retried.
+	fn is_settled(&self, block_number: NumberFor<B>) -> bool {
+		block_number <= self.best_canonicalized ||
+			self.held_watermark.map_or(false, |(held, _)| block_number <= held) ||
+			self.failed_blocks.contains_key(&block_number)
+	}
+
+	/// Compare the MMR root implied by the canonical entries of the MMR peaks as of block
+	/// `block_hash`, with number `block_num`, with the root reported by the runtime.
+	///
+	/// Returns whether they match, or `None` if `MmrGadgetConfig::root_hasher` isn't set or
+	/// either root couldn't be computed, e.g. because the peaks aren't all canonical.
+	fn check_root(&mut self, block_hash: B::Hash, block_num: NumberFor<B>) -> Option<bool> {
+		let hasher = self.config.root_hasher.clone()?;
+		let mut peaks = Vec::new();
+		for pos in peaks_for_leaf_count(self.leaf_count_at(block_num)) {
+			let canon_key = self.node_canon_offchain_key(pos);
+			match self.canonical_sink.read(&canon_key) {
+				Some(peak) => peaks.push(peak),
+				None => {
+					debug!(
+						target: LOG_TARGET,
+						"Canonical MMR peak at pos {} missing, skipping root check of block {:?}",
+						pos,
+						block_num
+					);
+					return None
+				},
+			}
+		}
+		let canonical_root = match hasher.root(&peaks) {
+			Some(root) => root,
+			None => {
+				debug!(
+					target: LOG_TARGET,
+					"Couldn't decode the canonical MMR peaks of block {:?}", block_num
+				);
+				return None
+			},
+		};
+		let runtime_root = match self.client.runtime_api().mmr_root(&BlockId::hash(block_hash)) {
+			Ok(Ok(root)) => root,
+			other => {
+				debug!(
+					target: LOG_TARGET,
+					"Couldn't get the MMR root of block {:?} from the runtime: {:?}", block_num, other
+				);
+				return None
+			},
+		};
+		if canonical_root == runtime_root {
+			return Some(true)
+		}
+		error!(
+			target: LOG_TARGET,
+			"Canonical MMR entries of block {:?} ({:?}) don't match the runtime MMR root: {:?} \
+			instead of {:?}",
+			block_num,
+			block_hash,
+			canonical_root,
+			runtime_root,
+		);
+		if let Some(metrics) = &self.metrics {
+			metrics.root_mismatches.inc();
+		}
+		Some(false)
+	}
+
+	/// Errors reporting the blocks out of canonicalization retries since the last call.
+	///
+	/// See `MmrGadgetConfig::max_canonicalize_retries`.
+	pub fn take_canonicalization_errors(&mut self) -> Vec<Error> {
+		std::mem::take(&mut self.canonicalization_errors)
+	}
+
+	fn write_checkpoint_or_log(&self, block_number: NumberFor<B>, block_hash: B::Hash) {
+		if let Some(path) = &self.config.checkpoint_path {
+			if let Err(e) = (Checkpoint { block_number, block_hash }).write(path) {
+				warn!(target: LOG_TARGET, "Error saving checkpoint to {:?}: {}", path, e);
+			}
+		}
 	}
 
 	/// In case of missed finality notifications (node restarts for example),
 	/// make sure to also canon everything leading up to `notification.tree_route`.
 	pub fn canonicalize_catch_up(&mut self, notification: &FinalityNotification<B>) {
+		let target = self.canonicalization_target(*notification.header.number());
 		let first = notification.tree_route.first().unwrap_or(&notification.hash);
-		if let Some(mut header) = self.header_metadata_or_log(*first, "canonicalize") {
-			let mut to_canon = VecDeque::<<B as Block>::Hash>::new();
-			// Walk up the chain adding all blocks newer than `self.best_canonicalized`.
-			loop {
-				header = match self.header_metadata_or_log(header.parent, "canonicalize") {
-					Some(header) => header,
-					_ => break,
-				};
-				if header.number <= self.best_canonicalized {
-					break
-				}
-				to_canon.push_front(header.hash);
-			}
+		if let Some(header) = self.header_metadata_or_log(*first, "canonicalize") {
 			// Canonicalize all blocks leading up to current finality notification.
-			for hash in to_canon.drain(..) {
-				self.canonicalize_branch(hash);
-			}
+			self.canonicalize_ancestry(header.parent, target);
 			self.write_gadget_state_or_log();
 		}
 	}
 
-	fn handle_potential_pallet_reset(&mut self, notification: &FinalityNotification<B>) {
-		if let Some(first_mmr_block_num) = self.client.first_mmr_block_num(&notification) {
+	/// Whether the catch-up was reported as complete through [`OffchainMmr::finish_catch_up`].
+	pub fn is_caught_up(&self) -> bool {
+		self.catch_up_from.is_none()
+	}
+
+	/// Report that the blocks finalized before the gadget started were canonicalized, and that
+	/// the gadget is now following finality.
+	///
+	/// Only the first call logs a summary of the catch-up. Returns whether it did.
+	pub(crate) fn finish_catch_up(&mut self) -> bool {
+		let from = match self.catch_up_from.take() {
+			Some(from) => from,
+			None => return false,
+		};
+		let blocks = self.best_canonicalized.saturating_sub(from);
+		info!(
+			target: LOG_TARGET,
+			"MMR offchain gadget caught up: canonicalized {:?} blocks up to #{:?}",
+			blocks,
+			self.best_canonicalized,
+		);
+		if let Some(telemetry) = &self.config.telemetry {
+			telemetry.report(TelemetryEvent::CaughtUp {
+				blocks,
+				best_canonicalized: self.best_canonicalized,
+			});
+			self.last_telemetry_report = Instant::now();
+		}
+		true
+	}
+
+	/// Report the last canonicalized block to `MmrGadgetConfig::telemetry`, unless it was
+	/// reported less than `MmrGadgetConfig::telemetry_interval` ago.
+	pub(crate) fn report_progress_if_due(&mut self) {
+		let telemetry = match &self.config.telemetry {
+			Some(telemetry) => telemetry,
+			None => return,
+		};
+		if self.last_telemetry_report.elapsed() < self.config.telemetry_interval {
+			return
+		}
+		telemetry
+			.report(TelemetryEvent::Canonicalized { best_canonicalized: self.best_canonicalized });
+		self.last_telemetry_report = Instant::now();
+	}
+
+	/// Highest block that may be canonicalized upon receiving `notification`.
+	fn canonicalization_target(&self, finalized: NumberFor<B>) -> NumberFor<B> {
+		finalized.saturating_sub(self.config.canonicalize_margin)
+	}
+
+	/// Check that the MMR nodes of the oldest finalized block that isn't canonicalized yet, with
+	/// `finalized` the number of the finalized head, are in the offchain db.
+	///
+	/// They are missing when the node runs with the offchain indexing API disabled, in which
+	/// case `pallet-mmr` can't write them and the gadget has nothing to do. This is reported
+	/// with an error and through [`MmrGadgetStatus::is_offchain_indexing_missing`].
+	pub(crate) fn check_offchain_indexing(&mut self, finalized: NumberFor<B>) -> bool {
+		let block_num =
+			self.best_canonicalized.saturating_add(One::one()).max(self.first_mmr_block);
+		if block_num > finalized {
+			return true
+		}
+		let header = match self.client.hash(block_num).ok().flatten() {
+			Some(hash) => match self.header_metadata_or_log(hash, "check") {
+				Some(header) => header,
+				None => return true,
+			},
+			None => return true,
+		};
+		let leaf_pos = match self.right_branch_ending_in_block_or_log(block_num, "check") {
+			Some(nodes) => nodes[0],
+			None => return true,
+		};
+		let temp_key = self.node_temp_offchain_key(leaf_pos, self.fork_hash(&header));
+		let canon_key = self.node_canon_offchain_key(leaf_pos);
+		if self.offchain_get(&temp_key).is_some() || self.offchain_get(&canon_key).is_some() {
+			return true
+		}
+		error!(
+			target: LOG_TARGET,
+			"MMR leaf of finalized block #{:?} not found in the offchain db: the offchain indexing \
+			API is likely disabled, in which case the MMR gadget has nothing to do. Start the node \
+			with `--enable-offchain-indexing true`.",
+			block_num,
+		);
+		self.config.status.record_offchain_indexing_missing();
+		false
+	}
+
+	/// Canonicalize the blocks of the chain ending in `head` that are newer than
+	/// `self.best_canonicalized`, up to and including block number `target`.
+	///
+	/// The blocks are processed in the order given by `MmrGadgetConfig::catch_up_order`.
+	///
+	/// If a block of that chain turns out not to be finalized, e.g. because `head` was a stale
+	/// block, the remaining blocks are abandoned and the catch-up restarts from the current
+	/// finalized head. Nothing is written for the abandoned blocks.
+	fn canonicalize_ancestry(&mut self, head: B::Hash, target: NumberFor<B>) -> CatchUpSummary {
+		let mut to_canon = VecDeque::new();
+		let mut hash = head;
+		while let Some(header) = self.header_metadata_or_log(hash, "canonicalize") {
+			if header.number <= self.best_canonicalized {
+				break
+			}
+			if header.number <= target && !self.is_settled(header.number) {
+				to_canon.push_front((header.hash, header.number));
+			}
+			hash = header.parent;
+		}
+
+		let descending = self.config.catch_up_order == CatchUpOrder::Descending;
+		if descending {
+			to_canon.make_contiguous().reverse();
+		}
+		let newest = to_canon.front().copied();
+		let mut complete = true;
+		let mut summary = CatchUpSummary::default();
+		for (hash, number) in to_canon {
+			if !self.is_finalized(hash, number) {
+				let info = self.client.info();
+				warn!(
+					target: LOG_TARGET,
+					"Block {:?} (#{:?}) isn't finalized, restarting catch-up from {:?}.",
+					hash,
+					number,
+					info.finalized_hash,
+				);
+				if info.finalized_hash != head {
+					let target =
+						info.finalized_number.saturating_sub(self.config.canonicalize_margin);
+					let restarted = self.canonicalize_ancestry(info.finalized_hash, target);
+					summary.blocks += restarted.blocks;
+					summary.nodes += restarted.nodes;
+				}
+				complete = false;
+				break
+			}
+			summary.blocks += 1;
+			summary.nodes += self.canonicalize_branch(hash, !descending);
+		}
+		// Blocks canonicalized newest first only count as canonicalized once the whole backlog
+		// is, so that `best_canonicalized` never skips over a block left behind.
+		match newest {
+			Some((hash, number)) if descending && complete => self.advance_watermark(number, hash),
+			_ => {},
+		}
+		summary
+	}
+
+	/// Whether block `hash`, with number `number`, is part of the finalized chain.
+	fn is_finalized(&self, hash: B::Hash, number: NumberFor<B>) -> bool {
+		number <= self.client.info().finalized_number &&
+			self.client.hash(number).ok().flatten() == Some(hash)
+	}
+
+	/// Canonicalize the blocks up to the finalized block `head`, without waiting for a finality
+	/// notification.
+	///
+	/// Blocks held back by `MmrGadgetConfig::canonicalize_margin` are left untouched, and stale
+	/// forks aren't pruned.
+	pub fn canonicalize_finalized(
+		&mut self,
+		head: B::Hash,
+		head_num: NumberFor<B>,
+	) -> CatchUpSummary {
+		let target = head_num.saturating_sub(self.config.canonicalize_margin);
+		let summary = self.canonicalize_ancestry(head, target);
+		self.write_gadget_state_or_log();
+		summary
+	}
+
+	/// Whether pruning the stale block `hash` has to wait for finalized blocks held back by
+	/// `MmrGadgetConfig::canonicalize_margin`.
+	///
+	/// A stale block sharing its parent with a finalized block uses the same fork-aware keys, so
+	/// it can only be pruned once that finalized block has been canonicalized.
+	fn should_defer_pruning(&self, hash: &B::Hash, finalized: NumberFor<B>) -> bool {
+		if self.config.canonicalize_margin.is_zero() {
+			return false
+		}
+		match self.header_metadata(*hash) {
+			Some(header) => header.number > self.best_canonicalized && header.number <= finalized,
+			None => false,
+		}
+	}
+
+	/// Detect a reset of `pallet-mmr` by looking up the first MMR block as seen from the finalized
+	/// block `finalized_hash`.
+	///
+	/// A reset can happen at any block, so the runtime is queried once per finalized block. Only
+	/// blocks already looked up, such as the one that started the gadget, are skipped.
+	fn handle_potential_pallet_reset(&mut self, finalized_hash: B::Hash, finalized: NumberFor<B>) {
+		if self.first_mmr_block_checked_at == Some(finalized_hash) {
+			return
+		}
+		self.first_mmr_block_checked_at = Some(finalized_hash);
+
+		if let Some(first_mmr_block_num) = self.client.first_mmr_block_num_at(finalized) {
 			if first_mmr_block_num != self.first_mmr_block {
 				info!(
 					target: LOG_TARGET,
 					"pallet-mmr reset detected at block {:?} with new genesis at block {:?}",
-					notification.header.number(),
+					finalized,
 					first_mmr_block_num
 				);
 				self.first_mmr_block = first_mmr_block_num;
-				self.best_canonicalized = first_mmr_block_num.saturating_sub(One::one());
+				self.config.status.set_first_mmr_block(first_mmr_block_num);
+				self.best_canonicalized = first_mmr_block_num
+					.saturating_sub(One::one())
+					.max(start_watermark::<B>(&self.config));
+				self.failed_blocks.clear();
+				self.held_watermark = None;
 				self.write_gadget_state_or_log();
 			}
 		}
@@ -262,68 +978,691 @@ where
 	/// Move leafs and nodes added by finalized blocks in offchain db from _fork-aware key_ to
 	/// _canonical key_.
 	/// Prune leafs and nodes added by stale blocks in offchain db from _fork-aware key_.
-	pub fn canonicalize_and_prune(&mut self, notification: FinalityNotification<B>) {
+	///
+	/// Returns `false` if the notification was dropped, being a duplicate of a recent one.
+	pub fn canonicalize_and_prune(&mut self, notification: FinalityNotification<B>) -> bool {
+		let number = *notification.header.number();
+		if self.is_duplicate_notification(number, notification.hash) {
+			trace!(
+				target: LOG_TARGET,
+				"Dropping duplicate finality notification for block {:?} (#{:?})",
+				notification.hash,
+				number
+			);
+			return false
+		}
+		self.process_finalized(
+			notification.hash,
+			number,
+			&notification.tree_route,
+			&notification.stale_heads,
+		);
+		true
+	}
+
+	/// Whether a finality notification for block `hash`, with number `number`, is among the
+	/// recent ones. If not, it is remembered as the most recent one.
+	fn is_duplicate_notification(&mut self, number: NumberFor<B>, hash: B::Hash) -> bool {
+		let window = self.config.duplicate_notification_window;
+		if window == 0 {
+			return false
+		}
+		if self.recent_notifications.contains(&(number, hash)) {
+			return true
+		}
+		if self.recent_notifications.len() >= window {
+			self.recent_notifications.pop_front();
+		}
+		self.recent_notifications.push_back((number, hash));
+		false
+	}
+
+	/// Canonicalize the MMR nodes of the blocks up to block `hash`, with number `number`, as if
+	/// a finality notification had been received for it.
+	///
+	/// Meant for tests, which can then skip building finality notifications. The blocks since
+	/// the last canonicalized one are treated as implicitly finalized, and no stale fork is
+	/// pruned apart from the ones whose pruning was deferred.
+	pub fn canonicalize_up_to(&mut self, number: NumberFor<B>, hash: B::Hash) {
+		let mut tree_route = VecDeque::new();
+		let mut ancestor = self.header_metadata_or_log(hash, "canonicalize").map(|h| h.parent);
+		while let Some(header) = ancestor.and_then(|hash| self.header_metadata(hash)) {
+			if header.number <= self.best_canonicalized {
+				break
+			}
+			tree_route.push_front(header.hash);
+			ancestor = Some(header.parent);
+		}
+		self.process_finalized(hash, number, tree_route.make_contiguous(), &[]);
+	}
+
+	/// Handle the finalization of block `finalized_hash`, with number `finalized`.
+	///
+	/// `tree_route` lists the implicitly finalized blocks, from oldest to newest, and
+	/// `stale_heads` the heads of the forks made stale.
+	fn process_finalized(
+		&mut self,
+		finalized_hash: B::Hash,
+		finalized: NumberFor<B>,
+		tree_route: &[B::Hash],
+		stale_heads: &[B::Hash],
+	) {
 		// Update the first MMR block in case of a pallet reset.
-		self.handle_potential_pallet_reset(&notification);
+		self.handle_potential_pallet_reset(finalized_hash, finalized);
+		self.retry_failed_blocks();
 
 		// Move offchain MMR nodes for finalized blocks to canonical keys.
-		for hash in notification.tree_route.iter().chain(std::iter::once(&notification.hash)) {
-			self.canonicalize_branch(*hash);
+		if self.config.canonicalize_margin.is_zero() {
+			for hash in tree_route.iter().chain(std::iter::once(&finalized_hash)) {
+				let settled = self
+					.header_metadata(*hash)
+					.map_or(false, |header| self.is_settled(header.number));
+				if !settled {
+					self.canonicalize_branch(*hash, true);
+				}
+			}
+		} else {
+			let target = self.canonicalization_target(finalized);
+			self.canonicalize_ancestry(finalized_hash, target);
 		}
 		self.write_gadget_state_or_log();
 
 		// Remove offchain MMR nodes for stale forks.
-		let stale_forks = self.client.expand_forks(&notification.stale_heads).unwrap_or_else(
-			|(stale_forks, e)| {
+		let stale_forks =
+			self.client.expand_forks(stale_heads).unwrap_or_else(|(stale_forks, e)| {
 				warn!(target: LOG_TARGET, "{:?}", e);
 				stale_forks
-			},
-		);
-		for hash in stale_forks.iter() {
-			self.prune_branch(hash);
+			});
+		// The blocks enacted by this notification can't be part of a stale fork, even when the
+		// notification spans a reorg of the best chain.
+		let enacted = tree_route
+			.iter()
+			.chain(std::iter::once(&finalized_hash))
+			.collect::<HashSet<_>>();
+		let stale_forks = stale_forks.into_iter().filter(|hash| !enacted.contains(hash));
+		let deferred = std::mem::take(&mut self.deferred_prune);
+		for hash in deferred.into_iter().chain(stale_forks) {
+			if self.should_defer_pruning(&hash, finalized) {
+				self.deferred_prune.push(hash);
+			} else {
+				self.prune_branch(&hash);
+			}
 		}
 	}
-}
-
-#[cfg(test)]
-mod tests {
-	use crate::test_utils::{run_test_with_mmr_gadget, run_test_with_mmr_gadget_pre_post};
-	use parking_lot::Mutex;
-	use sp_runtime::generic::BlockId;
-	use std::{sync::Arc, time::Duration};
 
-	#[test]
-	fn canonicalize_and_prune_works_correctly() {
-		run_test_with_mmr_gadget(|client| async move {
-			//                     -> D4 -> D5
-			// G -> A1 -> A2 -> A3 -> A4
-			//   -> B1 -> B2 -> B3
-			//   -> C1
+	/// Move MMR offchain entries written under `old_prefix` to the current indexing prefix.
+	///
+	/// Canonical entries, keyed with [`MmrGadgetConfig::canonical_key_encoding`] under both
+	/// prefixes, are migrated for all the MMR nodes added by blocks up to `up_to`, capped to the
+	/// last finalized block. They are written through the [`CanonicalSink`], committing
+	/// every `MIGRATION_BATCH_SIZE` positions, and only deleted under `old_prefix` once
+	/// committed. The migration stops at the first failed commit. Fork-aware entries stay in
+	/// the offchain db, where the runtime reads them.
+	/// Entries missing under `old_prefix` are skipped, so the migration can safely be run again,
+	/// for example after an interruption. Old entries are kept unless
+	/// [`PrefixMigration::delete_old`] is set.
+	///
+	/// Returns the number of migrated entries.
+	pub fn migrate_prefix(
+		&mut self,
+		old_prefix: &[u8],
+		up_to: NumberFor<B>,
+		options: PrefixMigration,
+	) -> usize {
+		if old_prefix == self.indexing_prefix.as_bytes() {
+			return 0
+		}
 
-			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
-			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
-			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
-			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+		let up_to = up_to.min(self.client.info().finalized_number);
+		let size = match self.mmr_size_at_or_log(up_to, "migrate") {
+			Some(size) => size,
+			None => return 0,
+		};
+		let mut migrated = 0;
+		let mut from = 0;
+		while from < size {
+			let to = from.saturating_add(MIGRATION_BATCH_SIZE).min(size);
+			let mut old_keys = Vec::new();
+			self.canonical_sink.begin_block();
+			for pos in from..to {
+				let old_key = self.config.canonical_key_encoding.canonical_key(old_prefix, pos);
+				if let Some(elem) = self.offchain_get(&old_key) {
+					let canon_key = self.node_canon_offchain_key(pos);
+					self.canonical_sink.write(&canon_key, &elem);
+					old_keys.push(old_key);
+				}
+			}
+			if let Err(e) = self.canonical_sink.commit_block() {
+				error!(
+					target: LOG_TARGET,
+					"Couldn't migrate the canonical MMR entries from pos {}: {}", from, e
+				);
+				return migrated
+			}
+			migrated += old_keys.len();
+			if options.delete_old {
+				for old_key in old_keys {
+					self.offchain_clear(&old_key);
+				}
+			}
+			from = to;
+		}
 
-			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
-			let b2 = client.import_block(&BlockId::Hash(b1.hash()), b"b2", Some(1)).await;
-			let b3 = client.import_block(&BlockId::Hash(b2.hash()), b"b3", Some(2)).await;
+		if options.include_fork_entries {
+			let mut block_num = self.best_canonicalized;
+			while block_num < up_to {
+				block_num = block_num.saturating_add(One::one());
+				let fork_keys = self.fork_keys_for_migration(old_prefix, block_num);
+				for (old_key, new_key) in fork_keys.into_iter().flatten() {
+					if self.migrate_entry(&old_key, &new_key, options.delete_old) {
+						migrated += 1;
+					}
+				}
+			}
+		}
+		info!(
+			target: LOG_TARGET,
+			"Migrated {} MMR offchain entries up to block {:?} to the current indexing prefix",
+			migrated,
+			up_to
+		);
+		migrated
+	}
 
-			let c1 = client.import_block(&BlockId::Number(0), b"c1", Some(0)).await;
+	/// Copy the offchain db entry at `old_key` to `new_key`, deleting it if `delete_old` is set.
+	/// Returns whether there was an entry to copy.
+	fn migrate_entry(&mut self, old_key: &[u8], new_key: &[u8], delete_old: bool) -> bool {
+		let elem = match self.offchain_get(old_key) {
+			Some(elem) => elem,
+			None => return false,
+		};
+		self.offchain_set(new_key, &elem);
+		if delete_old {
+			self.offchain_clear(old_key);
+		}
+		true
+	}
 
-			let d4 = client.import_block(&BlockId::Hash(a3.hash()), b"d4", Some(3)).await;
-			let d5 = client.import_block(&BlockId::Hash(d4.hash()), b"d5", Some(4)).await;
+	/// Number of MMR nodes stored under canonical keys, i.e. the size of the MMR as of the last
+	/// canonicalized block.
+	///
+	/// Saturates to [`NodeIndex::MAX`] if the size doesn't fit.
+	pub fn canonical_size(&self) -> NodeIndex {
+		self.mmr_size_at_or_log(self.best_canonicalized, "measure")
+			.unwrap_or(NodeIndex::MAX)
+	}
 
-			client.finalize_block(a3.hash(), Some(3));
-			tokio::time::sleep(Duration::from_millis(200)).await;
-			// expected finalized heads: a1, a2, a3
-			client.assert_canonicalized(&[&a1, &a2, &a3]);
-			// expected stale heads: c1
-			// expected pruned heads because of temp key collision: b1
-			client.assert_pruned(&[&c1, &b1]);
+	/// Positions of the MMR nodes canonicalized by the blocks with numbers in `(from, to]`, in
+	/// ascending order.
+	///
+	/// Derived from the size of the MMR at both heights, without reading the offchain db. `to` is
+	/// capped to the last canonicalized block, and only leaves are listed with
+	/// [`CanonicalizeScope::LeavesOnly`].
+	pub fn canonical_delta(&self, from: NumberFor<B>, to: NumberFor<B>) -> Vec<NodeIndex> {
+		let action = "list";
+		let positions = match (
+			self.mmr_size_at_or_log(from, action),
+			self.mmr_size_at_or_log(to.min(self.best_canonicalized), action),
+		) {
+			(Some(from), Some(to)) => from..to,
+			_ => return Vec::new(),
+		};
+		match self.config.canonicalize_scope {
+			CanonicalizeScope::LeavesAndNodes => positions.collect(),
+			CanonicalizeScope::LeavesOnly => positions.filter(|pos| is_leaf_pos(*pos)).collect(),
+		}
+	}
 
-			client.finalize_block(d5.hash(), Some(5));
-			tokio::time::sleep(Duration::from_millis(200)).await;
+	/// Check that the offchain db holds a canonical entry for every MMR node added up to the last
+	/// canonicalized block, or every leaf with [`CanonicalizeScope::LeavesOnly`].
+	///
+	/// Only meaningful when canonical entries are written to the offchain db, i.e. without
+	/// [`OffchainMmr::with_sink`]. Fails with [`Error::InconsistentOffchainDb`] if entries are
+	/// missing, e.g. because the offchain db was partially wiped.
+	pub fn verify_canonical_entries(&self) -> Result<(), Error> {
+		let positions: Box<dyn Iterator<Item = NodeIndex>> = match self.config.canonicalize_scope {
+			CanonicalizeScope::LeavesAndNodes => Box::new(0..self.canonical_size()),
+			CanonicalizeScope::LeavesOnly =>
+				Box::new((0..self.leaf_count_at(self.best_canonicalized)).map(leaf_pos)),
+		};
+		// Check the positions a chunk at a time, only keeping whether each entry is present.
+		let mut positions = positions.peekable();
+		let (mut expected, mut found) = (0, 0);
+		while positions.peek().is_some() {
+			let chunk: Vec<_> = positions.by_ref().take(VERIFY_CHUNK_SIZE).collect();
+			expected += chunk.len();
+			found += self
+				.map_canonical_nodes(chunk, |node| node.is_some())
+				.into_iter()
+				.filter(|present| *present)
+				.count();
+		}
+		if found != expected {
+			return Err(Error::InconsistentOffchainDb(format!(
+				"found {} canonical entries, expected {} up to block #{:?}",
+				found, expected, self.best_canonicalized
+			)))
+		}
+		Ok(())
+	}
+
+	/// Read the canonical MMR nodes at `positions`, in the same order.
+	///
+	/// The reads are spread over up to `MmrGadgetConfig::read_concurrency` threads.
+	pub fn read_canonical_nodes(
+		&self,
+		positions: impl IntoIterator<Item = NodeIndex>,
+	) -> Vec<Option<Vec<u8>>> {
+		self.map_canonical_nodes(positions, |node| node)
+	}
+
+	/// Read the canonical MMR nodes at `positions` and map them through `f`, in the same order.
+	///
+	/// Each node is dropped once mapped, see [`OffchainMmr::read_canonical_nodes`] for the
+	/// concurrency.
+	fn map_canonical_nodes<T: Send>(
+		&self,
+		positions: impl IntoIterator<Item = NodeIndex>,
+		f: impl Fn(Option<Vec<u8>>) -> T + Sync,
+	) -> Vec<T> {
+		let keys: Vec<_> =
+			positions.into_iter().map(|pos| self.node_canon_offchain_key(pos)).collect();
+		let read = |mut offchain_db: OffchainDb<BE::OffchainStorage>, keys: &[Vec<u8>]| {
+			keys.iter()
+				.map(|key| f(offchain_db.local_storage_get(StorageKind::PERSISTENT, key)))
+				.collect::<Vec<_>>()
+		};
+
+		let concurrency = self.config.read_concurrency.max(1);
+		if concurrency == 1 || keys.len() < 2 {
+			return read(self.offchain_db.clone(), &keys)
+		}
+		let chunk_size = (keys.len() + concurrency - 1) / concurrency;
+		std::thread::scope(|scope| {
+			let readers: Vec<_> = keys
+				.chunks(chunk_size)
+				.map(|chunk| {
+					let offchain_db = self.offchain_db.clone();
+					scope.spawn(move || read(offchain_db, chunk))
+				})
+				.collect();
+			readers
+				.into_iter()
+				.flat_map(|reader| reader.join().expect("reading the offchain db doesn't panic"))
+				.collect()
+		})
+	}
+
+	/// Write the canonical MMR nodes, up to the last canonicalized block, to `writer` as a
+	/// snapshot that [`OffchainMmr::import_canonical`] can load on another node.
+	///
+	/// Nodes are streamed in ascending position order, skipping the ones missing from the
+	/// offchain db, e.g. the internal nodes with [`CanonicalizeScope::LeavesOnly`]. Returns the
+	/// number of exported nodes.
+	pub fn export_canonical(&self, mut writer: impl Write) -> Result<u64, Error> {
+		let to_error = |e: io::Error| Error::Snapshot(e.to_string());
+		snapshot::write_header(&mut writer).map_err(to_error)?;
+		let size = self.canonical_size();
+		let mut exported = 0;
+		let mut from = 0;
+		while from < size {
+			let to = from.saturating_add(SNAPSHOT_CHUNK_SIZE).min(size);
+			for (pos, node) in (from..to).zip(self.read_canonical_nodes(from..to)) {
+				if let Some(node) = node {
+					snapshot::write_node(&mut writer, pos, &node).map_err(to_error)?;
+					exported += 1;
+				}
+			}
+			from = to;
+		}
+		writer.flush().map_err(to_error)?;
+		Ok(exported)
+	}
+
+	/// Write the nodes of a snapshot made by [`OffchainMmr::export_canonical`] under canonical
+	/// keys.
+	///
+	/// The nodes are written to the canonical sink as a single batch, once the whole snapshot has
+	/// been read. The last canonicalized block isn't changed: use
+	/// `MmrGadgetConfig::start_height` to skip the blocks covered by the snapshot. Returns the
+	/// number of imported nodes.
+	pub fn import_canonical(&mut self, mut reader: impl Read) -> Result<u64, Error> {
+		let to_error = |e: io::Error| Error::Snapshot(e.to_string());
+		snapshot::read_header(&mut reader).map_err(to_error)?;
+		let mut nodes = Vec::new();
+		while let Some(node) = snapshot::read_node(&mut reader).map_err(to_error)? {
+			nodes.push(node);
+		}
+		self.canonical_sink.begin_block();
+		for (pos, node) in &nodes {
+			let canon_key = self.node_canon_offchain_key(*pos);
+			self.canonical_sink.write(&canon_key, node);
+		}
+		self.canonical_sink.commit_block()?;
+		info!(target: LOG_TARGET, "Imported {} canonical MMR nodes from a snapshot", nodes.len());
+		Ok(nodes.len() as u64)
+	}
+
+	/// Copy the canonical MMR nodes added by block `block_num` back under the fork-aware keys
+	/// derived from `fork_hash`, see [`OffchainMmr::fork_key_range`].
+	///
+	/// This reconstructs the entries the block had before being canonicalized, which is only
+	/// meant for debugging and recovery: it is never run by the gadget. Canonical entries are read
+	/// from the offchain db and kept. Returns the number of rehydrated entries.
+	pub fn rehydrate_fork(&mut self, block_num: NumberFor<B>, fork_hash: B::Hash) -> usize {
+		let nodes = match self.right_branch_ending_in_block_or_log(block_num, "rehydrate") {
+			Some(nodes) => nodes,
+			None => return 0,
+		};
+
+		let mut rehydrated = 0;
+		for pos in nodes {
+			let canon_key = self.node_canon_offchain_key(pos);
+			if let Some(elem) = self.offchain_get(&canon_key) {
+				let temp_key = self.node_temp_offchain_key(pos, fork_hash);
+				self.offchain_set(&temp_key, &elem);
+				rehydrated += 1;
+			} else {
+				debug!(
+					target: LOG_TARGET,
+					"Couldn't rehydrate elem at pos {} using canon key {:?}", pos, canon_key
+				);
+			}
+		}
+		rehydrated
+	}
+
+	/// Compute the key operations canonicalizing block `hash`, with number `block_num`, would
+	/// perform, without writing anything.
+	///
+	/// Meant for debugging a block suspected to be canonicalized incorrectly. Returns `None` if
+	/// the block is unknown or didn't add MMR leaves. See
+	/// [`OffchainMmr::debug_replay_block_and_commit`] for also writing the canonical entries.
+	pub fn debug_replay_block(
+		&self,
+		block_num: NumberFor<B>,
+		hash: B::Hash,
+	) -> Option<ReplayReport> {
+		self.replay_block(block_num, hash).map(|(report, _)| report)
+	}
+
+	/// Same as [`OffchainMmr::debug_replay_block`], but also writes the canonical entries.
+	///
+	/// Fork-aware entries are kept, and the progress of the gadget isn't updated.
+	pub fn debug_replay_block_and_commit(
+		&mut self,
+		block_num: NumberFor<B>,
+		hash: B::Hash,
+	) -> Option<ReplayReport> {
+		let (mut report, elems) = self.replay_block(block_num, hash)?;
+		self.canonical_sink.begin_block();
+		for ((_, canon_key), elem) in report.canonical_keys_written.iter().zip(elems) {
+			self.canonical_sink.write(canon_key, &elem);
+		}
+		report.committed = match self.canonical_sink.commit_block() {
+			Ok(()) => true,
+			Err(e) => {
+				warn!(target: LOG_TARGET, "Couldn't commit the replay of block {:?}: {}", hash, e);
+				false
+			},
+		};
+		Some(report)
+	}
+
+	/// Returns the uncommitted report of the replay of block `hash`, along with the MMR nodes to
+	/// write under the canonical keys of the report.
+	fn replay_block(
+		&self,
+		block_num: NumberFor<B>,
+		hash: B::Hash,
+	) -> Option<(ReplayReport, Vec<Vec<u8>>)> {
+		let header = self.header_metadata_or_log(hash, "replay")?;
+		let nodes = self.right_branch_ending_in_block_or_log(block_num, "replay")?;
+		let mut offchain_db = self.offchain_db.clone();
+
+		let fork_hash = self.fork_hash(&header);
+		let canonicalized = self.config.canonicalize_scope.canonicalized_len(nodes.len());
+		let mut report = ReplayReport::default();
+		let mut elems = Vec::new();
+		for (idx, pos) in nodes.into_iter().enumerate() {
+			let temp_key = self.node_temp_offchain_key(pos, fork_hash);
+			match offchain_db.local_storage_get(StorageKind::PERSISTENT, &temp_key) {
+				Some(elem) if idx < canonicalized => {
+					report.canonical_keys_written.push((pos, self.node_canon_offchain_key(pos)));
+					elems.push(elem);
+				},
+				Some(_) => {},
+				None => report.missing_positions.push(pos),
+			}
+			report.fork_keys_read.push((pos, temp_key));
+		}
+		Some((report, elems))
+	}
+
+	/// Count the MMR entries added by the blocks with numbers in `from..=to`.
+	///
+	/// Fork-aware entries are looked up for every block known to the backend in that range,
+	/// finalized or not. This is a read-only scan, meant to check that pruning keeps up.
+	pub fn entry_stats(&self, from: NumberFor<B>, to: NumberFor<B>) -> EntryStats {
+		let from = from.max(self.first_mmr_block);
+		let mut offchain_db = self.offchain_db.clone();
+		let mut is_present =
+			|key: &[u8]| offchain_db.local_storage_get(StorageKind::PERSISTENT, key).is_some();
+		let mut stats = EntryStats::default();
+
+		// Fork-aware keys depend on the parent of the block that added the node, so walk down
+		// every fork to find the blocks in range.
+		let leaves = self.backend.blockchain().leaves().unwrap_or_else(|e| {
+			debug!(target: LOG_TARGET, "Couldn't get the chain leaves: {:?}", e);
+			Vec::new()
+		});
+		let mut blocks = HashSet::new();
+		for leaf in leaves {
+			let mut hash = leaf;
+			while let Some(header) = self.header_metadata(hash) {
+				if header.number < from ||
+					(header.number <= to &&
+						!blocks.insert((header.number, self.fork_hash(&header))))
+				{
+					break
+				}
+				hash = header.parent;
+			}
+		}
+		for (block_num, fork_hash) in blocks {
+			for pos in self.right_branch_ending_in_block(block_num).unwrap_or_default() {
+				stats.fork += is_present(&self.node_temp_offchain_key(pos, fork_hash)) as usize;
+			}
+		}
+
+		let mut block_num = from;
+		while block_num <= to {
+			for pos in self.right_branch_ending_in_block(block_num).unwrap_or_default() {
+				stats.canonical += is_present(&self.node_canon_offchain_key(pos)) as usize;
+			}
+			if block_num == to {
+				break
+			}
+			block_num = block_num.saturating_add(One::one());
+		}
+		stats
+	}
+
+	fn right_branch_ending_in_block(&self, block_num: NumberFor<B>) -> Option<Vec<NodeIndex>> {
+		utils::block_num_to_leaf_index::<B::Header>(block_num, self.first_mmr_block)
+			.ok()
+			.map(NodesUtils::right_branch_ending_in_leaf)
+	}
+
+	/// Numbers of the first MMR block and of the last canonicalized block, or `None` if no block
+	/// was canonicalized yet.
+	pub fn canonicalized_range(&self) -> Option<(NumberFor<B>, NumberFor<B>)> {
+		(self.best_canonicalized >= self.first_mmr_block)
+			.then(|| (self.first_mmr_block, self.best_canonicalized))
+	}
+
+	/// Numbers of the first and last finalized blocks whose MMR nodes weren't canonicalized yet,
+	/// or `None` if every finalized block was canonicalized.
+	///
+	/// These are typically blocks held back by `MmrGadgetConfig::canonicalize_margin`.
+	pub fn pending_blocks(&self) -> Option<(NumberFor<B>, NumberFor<B>)> {
+		let finalized = self.client.info().finalized_number;
+		let first_pending =
+			self.best_canonicalized.saturating_add(One::one()).max(self.first_mmr_block);
+		(first_pending <= finalized).then(|| (first_pending, finalized))
+	}
+
+	/// Number of MMR leaves once block `block_num` has been imported.
+	fn leaf_count_at(&self, block_num: NumberFor<B>) -> LeafIndex {
+		match utils::block_num_to_leaf_index::<B::Header>(block_num, self.first_mmr_block) {
+			Ok(leaf_idx) => leaf_idx.saturating_add(1),
+			// `block_num` predates the MMR pallet.
+			Err(_) => 0,
+		}
+	}
+
+	/// Size of the MMR once block `block_num` has been imported.
+	///
+	/// Logs and returns `None` if it overflows a [`NodeIndex`], which can only happen with
+	/// block numbers far beyond the ones of any real chain.
+	fn mmr_size_at_or_log(&self, block_num: NumberFor<B>, action: &str) -> Option<NodeIndex> {
+		let size = mmr_size(self.leaf_count_at(block_num));
+		if size.is_none() {
+			warn!(
+				target: LOG_TARGET,
+				"Size of the MMR at block #{:?} overflows, skipping {}.", block_num, action
+			);
+		}
+		size
+	}
+
+	/// Pairs of old and new fork-aware keys of the nodes added by finalized block `block_num`.
+	fn fork_keys_for_migration(
+		&self,
+		old_prefix: &[u8],
+		block_num: NumberFor<B>,
+	) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+		let action = "migrate";
+		let hash = self.client.hash(block_num).ok().flatten()?;
+		let header = self.header_metadata_or_log(hash, action)?;
+		let nodes = self.right_branch_ending_in_block_or_log(block_num, action)?;
+		let fork_hash = self.fork_hash(&header);
+		Some(
+			nodes
+				.into_iter()
+				.map(|pos| {
+					(
+						keys::fork_key::<B::Header>(old_prefix, pos, fork_hash),
+						self.node_temp_offchain_key(pos, fork_hash),
+					)
+				})
+				.collect(),
+		)
+	}
+}
+
+/// Only prints the canonicalization progress, leaving out the client, backend and storage.
+impl<B: Block, BE: Backend<B>, C, S> fmt::Debug for OffchainMmr<B, BE, C, S> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("OffchainMmr")
+			.field("first_mmr_block", &self.first_mmr_block)
+			.field("best_canonicalized", &self.best_canonicalized)
+			.field(
+				"indexing_prefix",
+				&format_args!("0x{}", HexDisplay::from(self.indexing_prefix.as_bytes())),
+			)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Last canonicalized block implied by `MmrGadgetConfig::start_height`, i.e. the block right
+/// below it.
+fn start_watermark<B: Block>(config: &MmrGadgetConfig<B>) -> NumberFor<B> {
+	config
+		.start_height
+		.map_or_else(Zero::zero, |start| start.saturating_sub(One::one()))
+}
+
+/// Position of the leaf with index `leaf_idx` in the MMR, `None` if it overflows.
+fn leaf_pos(leaf_idx: LeafIndex) -> Option<NodeIndex> {
+	leaf_idx.checked_mul(2).map(|pos| pos - NodeIndex::from(leaf_idx.count_ones()))
+}
+
+/// Whether the MMR node at `pos` is a leaf, rather than an internal node.
+fn is_leaf_pos(pos: NodeIndex) -> bool {
+	leaf_pos(NodesUtils::leaf_index_that_added_node(pos)) == Some(pos)
+}
+
+/// Number of nodes of an MMR with `leaf_count` leaves, `None` if it overflows.
+///
+/// Same as [`NodesUtils::size`], which overflows for more than `NodeIndex::MAX / 2` leaves.
+fn mmr_size(leaf_count: LeafIndex) -> Option<NodeIndex> {
+	// The next leaf is appended right after the last node.
+	leaf_pos(leaf_count)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		is_leaf_pos, leaf_pos, mmr_size, CatchUpSummary, EntryStats, OffchainMmr, PrefixMigration,
+		ReplayReport,
+	};
+	use crate::{
+		test_utils::{
+			run_test_with_mmr_gadget_and_config, run_test_with_mmr_gadget_and_status,
+			run_test_with_mmr_gadget_pre_post_and_status, wait_for_canonicalized, MmrBlock,
+			MockClient, MockRuntimeApi, OffchainKeyType,
+		},
+		CanonicalKeyEncoding, CanonicalSink, CanonicalizeScope, CatchUpOrder, Checkpoint, Error,
+		ForkDiscriminator, HeaderProvider, MmrGadgetConfig, MmrGadgetStatus, MmrRootHasher,
+		PruningMode,
+	};
+	use beefy_primitives::MmrRootHash;
+	use codec::Encode;
+	use futures::StreamExt;
+	use parking_lot::Mutex;
+	use sc_client_api::{AuxStore, BlockchainEvents};
+	use sp_blockchain::CachedHeaderMetadata;
+	use sp_core::offchain::{DbExternalities, StorageKind};
+	use sp_mmr_primitives::utils::NodesUtils;
+	use sp_runtime::{generic::BlockId, traits::Header as HeaderT};
+	use std::{collections::HashMap, sync::Arc, time::Duration};
+	use substrate_test_runtime_client::runtime::{Block, Hash, Header};
+
+	#[test]
+	fn canonicalize_and_prune_works_correctly() {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
+			//                     -> D4 -> D5
+			// G -> A1 -> A2 -> A3 -> A4
+			//   -> B1 -> B2 -> B3
+			//   -> C1
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+
+			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
+			let b2 = client.import_block(&BlockId::Hash(b1.hash()), b"b2", Some(1)).await;
+			let b3 = client.import_block(&BlockId::Hash(b2.hash()), b"b3", Some(2)).await;
+
+			let c1 = client.import_block(&BlockId::Number(0), b"c1", Some(0)).await;
+
+			let d4 = client.import_block(&BlockId::Hash(a3.hash()), b"d4", Some(3)).await;
+			let d5 = client.import_block(&BlockId::Hash(d4.hash()), b"d5", Some(4)).await;
+
+			client.finalize_block(a3.hash(), Some(3));
+			wait_for_canonicalized(&status_clone, 3).await;
+			// expected finalized heads: a1, a2, a3
+			client.assert_canonicalized(&[&a1, &a2, &a3]);
+			// expected stale heads: c1
+			// expected pruned heads because of temp key collision: b1
+			client.assert_pruned(&[&c1, &b1]);
+
+			client.finalize_block(d5.hash(), Some(5));
+			wait_for_canonicalized(&status_clone, 5).await;
 			// expected finalized heads: d4, d5,
 			client.assert_canonicalized(&[&d4, &d5]);
 			// expected stale heads: b1, b2, b3, a4
@@ -331,9 +1670,138 @@ mod tests {
 		})
 	}
 
+	#[test]
+	fn canonicalize_and_prune_handles_reorg_spanning_notification() {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
+			// G -> A1 -> A2 -> A3
+			//   -> B1 -> B2 -> B3 -> B4
+
+			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
+			let b2 = client.import_block(&BlockId::Hash(b1.hash()), b"b2", Some(1)).await;
+			let b3 = client.import_block(&BlockId::Hash(b2.hash()), b"b3", Some(2)).await;
+			let b4 = client.import_block(&BlockId::Hash(b3.hash()), b"b4", Some(3)).await;
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+
+			// A single notification finalizes the whole A branch, retracting the longer B one.
+			client.finalize_block(a3.hash(), Some(3));
+			wait_for_canonicalized(&status_clone, 3).await;
+			client.assert_canonicalized(&[&a1, &a2, &a3]);
+			client.assert_pruned(&[&b1, &b2, &b3, &b4]);
+		})
+	}
+
+	#[test]
+	fn background_pruning_works_correctly() {
+		let status = MmrGadgetStatus::default();
+		let config = MmrGadgetConfig {
+			status: status.clone(),
+			pruning: PruningMode::Background { queue_size: 2 },
+			..Default::default()
+		};
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2 -> A3
+			//   -> B1 -> B2
+			//   -> C1
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+
+			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
+			let b2 = client.import_block(&BlockId::Hash(b1.hash()), b"b2", Some(1)).await;
+
+			let c1 = client.import_block(&BlockId::Number(0), b"c1", Some(0)).await;
+
+			client.finalize_block(a3.hash(), Some(3));
+			wait_for_canonicalized(&status, 3).await;
+			// Give the background task time to perform the deletes it was sent.
+			tokio::time::sleep(Duration::from_millis(50)).await;
+			// b1 and c1 share their temp keys with a1. Had they been pruned before a1 was
+			// canonicalized, a1 would be missing its canonical entries.
+			client.assert_canonicalized(&[&a1, &a2, &a3]);
+			// The deletes performed by the background task eventually landed.
+			client.assert_pruned(&[&b1, &b2, &c1]);
+		})
+	}
+
+	#[test]
+	fn on_before_write_sees_entry_count_per_block() {
+		let counts = Arc::new(Mutex::new(Vec::new()));
+		let counts_clone = counts.clone();
+		let status = MmrGadgetStatus::default();
+		let config = MmrGadgetConfig {
+			status: status.clone(),
+			on_before_write: Some(Box::new(move |count| counts_clone.lock().push(count))),
+			..Default::default()
+		};
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2 -> A3 -> A4
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+
+			client.finalize_block(a4.hash(), Some(4));
+			wait_for_canonicalized(&status, 4).await;
+			client.assert_canonicalized(&[&a1, &a2, &a3, &a4]);
+			// a1 adds leaf 0, a2 adds leaf 1 and node 2, a3 adds leaf 3, a4 adds leaf 4 and
+			// nodes 5 and 6.
+			assert_eq!(*counts.lock(), vec![1, 2, 1, 3]);
+		})
+	}
+
+	#[test]
+	fn canonicalize_margin_holds_back_recent_blocks() {
+		let status = MmrGadgetStatus::default();
+		let config = MmrGadgetConfig {
+			status: status.clone(),
+			canonicalize_margin: 2,
+			..Default::default()
+		};
+		run_test_with_mmr_gadget_and_config(config, |client| async move {
+			// G -> A1 -> A2 -> A3 -> A4 -> A5 -> A6
+			//   -> B1
+			//                        -> C4
+
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+			let a5 = client.import_block(&BlockId::Hash(a4.hash()), b"a5", Some(4)).await;
+			let a6 = client.import_block(&BlockId::Hash(a5.hash()), b"a6", Some(5)).await;
+
+			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
+			let c4 = client.import_block(&BlockId::Hash(a3.hash()), b"c4", Some(3)).await;
+
+			client.finalize_block(a5.hash(), Some(5));
+			wait_for_canonicalized(&status, 3).await;
+			// only blocks up to a5 - 2 are canonicalized.
+			client.assert_canonicalized(&[&a1, &a2, &a3]);
+			// the two most recent finalized blocks are still fork-aware, even though c4 (which
+			// shares its fork-aware keys with a4) is stale.
+			client.assert_not_canonicalized(&[&a4, &a5]);
+			client.assert_pruned(&[&b1]);
+
+			client.finalize_block(a6.hash(), Some(6));
+			wait_for_canonicalized(&status, 4).await;
+			client.assert_canonicalized(&[&a4]);
+			client.assert_not_canonicalized(&[&a5, &a6]);
+			// c4 is pruned now that a4 has been canonicalized.
+			client.assert_pruned(&[&c4]);
+		})
+	}
+
 	#[test]
 	fn canonicalize_and_prune_handles_pallet_reset() {
-		run_test_with_mmr_gadget(|client| async move {
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_and_status(status, |client| async move {
 			// G -> A1 -> A2 -> A3 -> A4 -> A5
 			//      |           |
 			//      |           | -> pallet reset
@@ -347,7 +1815,7 @@ mod tests {
 			let a5 = client.import_block(&BlockId::Hash(a4.hash()), b"a5", Some(2)).await;
 
 			client.finalize_block(a1.hash(), Some(1));
-			tokio::time::sleep(Duration::from_millis(200)).await;
+			wait_for_canonicalized(&status_clone, 1).await;
 			// expected finalized heads: a1
 			client.assert_canonicalized(&[&a1]);
 			// a2 shouldn't be either canonicalized or pruned. It should be handled as part of the
@@ -355,7 +1823,7 @@ mod tests {
 			client.assert_not_canonicalized(&[&a2]);
 
 			client.finalize_block(a5.hash(), Some(3));
-			tokio::time::sleep(Duration::from_millis(200)).await;
+			wait_for_canonicalized(&status_clone, 5).await;
 			//expected finalized heads: a3, a4, a5,
 			client.assert_canonicalized(&[&a3, &a4, &a5]);
 		})
@@ -365,7 +1833,10 @@ mod tests {
 	fn canonicalize_catchup_works_correctly() {
 		let mmr_blocks = Arc::new(Mutex::new(vec![]));
 		let mmr_blocks_ref = mmr_blocks.clone();
-		run_test_with_mmr_gadget_pre_post(
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_pre_post_and_status(
+			status,
 			|client| async move {
 				// G -> A1 -> A2
 				//      |     |
@@ -399,7 +1870,7 @@ mod tests {
 				let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
 
 				client.finalize_block(a4.hash(), Some(4));
-				tokio::time::sleep(Duration::from_millis(200)).await;
+				wait_for_canonicalized(&status_clone, 4).await;
 				// expected finalized heads: a1, a2 _and_ a3, a4.
 				client.assert_canonicalized(&[&a1, &a2, &a3, &a4]);
 			},
@@ -410,7 +1881,10 @@ mod tests {
 	fn canonicalize_catchup_works_correctly_with_pallet_reset() {
 		let mmr_blocks = Arc::new(Mutex::new(vec![]));
 		let mmr_blocks_ref = mmr_blocks.clone();
-		run_test_with_mmr_gadget_pre_post(
+		let status = MmrGadgetStatus::default();
+		let status_clone = status.clone();
+		run_test_with_mmr_gadget_pre_post_and_status(
+			status,
 			|client| async move {
 				// G -> A1 -> A2
 				//      |     |
@@ -445,7 +1919,7 @@ mod tests {
 				let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(2)).await;
 
 				client.finalize_block(a4.hash(), Some(3));
-				tokio::time::sleep(Duration::from_millis(200)).await;
+				wait_for_canonicalized(&status_clone, 4).await;
 				// a1 shouldn't be either canonicalized or pruned. It should be handled as part of
 				// the reset process. Checking only that it wasn't pruned. Because of temp key
 				// collision with a2 we can't check that it wasn't canonicalized.
@@ -455,4 +1929,1486 @@ mod tests {
 			},
 		)
 	}
+
+	#[test]
+	fn migrate_prefix_moves_entries_to_current_prefix() {
+		const OLD_PREFIX: &[u8] = b"old_mmr";
+		let client = Arc::new(MockClient::new());
+		// G -> A1 -> A2 -> A3
+		//      |           |
+		//      |           | -> finalized, not canonicalized yet
+		//      |
+		//      | -> first mmr block
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", None).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", None).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", None).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a3.hash(), None);
+
+		// a1 and a2 were canonicalized under the old prefix (3 nodes), a3 wasn't (1 node).
+		let mut offchain_db = client.offchain_db();
+		let old_entries = vec![
+			NodesUtils::node_canon_offchain_key(OLD_PREFIX, 0),
+			NodesUtils::node_canon_offchain_key(OLD_PREFIX, 1),
+			NodesUtils::node_canon_offchain_key(OLD_PREFIX, 2),
+			NodesUtils::node_temp_offchain_key::<Header>(OLD_PREFIX, 3, a3.parent_hash()),
+		];
+		for key in &old_entries {
+			offchain_db.local_storage_set(StorageKind::PERSISTENT, key, key);
+		}
+		let new_entries = vec![
+			a1.get_offchain_key(0, OffchainKeyType::Canon),
+			a2.get_offchain_key(1, OffchainKeyType::Canon),
+			a2.get_offchain_key(2, OffchainKeyType::Canon),
+			a3.get_offchain_key(3, OffchainKeyType::Temp),
+		];
+
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		let options = PrefixMigration { include_fork_entries: true, delete_old: false };
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 3, options), 4);
+		for (old_key, new_key) in old_entries.iter().zip(new_entries.iter()) {
+			let val = offchain_db.local_storage_get(StorageKind::PERSISTENT, new_key);
+			assert_eq!(val.as_ref(), Some(old_key));
+			assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, old_key).is_some());
+		}
+
+		// The migration can be run again, this time deleting the old entries.
+		let options = PrefixMigration { include_fork_entries: true, delete_old: true };
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 3, options), 4);
+		for (old_key, new_key) in old_entries.iter().zip(new_entries.iter()) {
+			assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, new_key).is_some());
+			assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, old_key).is_none());
+		}
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 3, options), 0);
+	}
+
+	#[test]
+	fn migrate_prefix_stops_at_last_finalized_block() {
+		const OLD_PREFIX: &[u8] = b"old_mmr";
+		let client = Arc::new(MockClient::new());
+		// G -> A1 -> A2 -> A3
+		//            |
+		//            | -> finalized
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", None).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", None).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", None).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a2.hash(), None);
+
+		let mut offchain_db = client.offchain_db();
+		for pos in 0..4 {
+			let old_key = NodesUtils::node_canon_offchain_key(OLD_PREFIX, pos);
+			offchain_db.local_storage_set(StorageKind::PERSISTENT, &old_key, &old_key);
+		}
+
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		// Migrating up to a3 stops at a2.
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 3, Default::default()), 3);
+		for key in [
+			a1.get_offchain_key(0, OffchainKeyType::Canon),
+			a2.get_offchain_key(1, OffchainKeyType::Canon),
+			a2.get_offchain_key(2, OffchainKeyType::Canon),
+		] {
+			assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &key).is_some());
+		}
+		let a3_key = a3.get_offchain_key(3, OffchainKeyType::Canon);
+		assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &a3_key).is_none());
+	}
+
+	#[test]
+	fn offchain_mmr_new_reports_failure_reason() {
+		let client = Arc::new(MockClient::new());
+		let new_offchain_mmr = |first_mmr_block| {
+			OffchainMmr::<Block, _, _>::new(
+				client.backend.clone(),
+				client.clone(),
+				client.offchain_db(),
+				MockRuntimeApi::INDEXING_PREFIX.to_vec(),
+				first_mmr_block,
+				Default::default(),
+			)
+		};
+
+		assert!(matches!(new_offchain_mmr(0), Err(Error::InvalidFirstMmrBlock(_))));
+
+		// Corrupt the aux db by storing an unsupported schema version.
+		client
+			.backend
+			.insert_aux(&[(&b"mmr_auxschema_version"[..], &2u32.encode()[..])], &[])
+			.unwrap();
+		assert!(matches!(new_offchain_mmr(1), Err(Error::AuxSchema(_))));
+	}
+
+	#[test]
+	fn offchain_mmr_new_rejects_overlong_indexing_prefix() {
+		let client = Arc::new(MockClient::new());
+		let new_offchain_mmr = |prefix_len| {
+			OffchainMmr::<Block, _, _>::new(
+				client.backend.clone(),
+				client.clone(),
+				client.offchain_db(),
+				vec![b'x'; prefix_len],
+				1,
+				MmrGadgetConfig { max_offchain_key_len: 64, ..Default::default() },
+			)
+		};
+
+		assert!(new_offchain_mmr(16).is_ok());
+		match new_offchain_mmr(64) {
+			Err(e @ Error::OffchainKeyTooLong(_)) => assert_eq!(
+				e.to_string(),
+				"MMR offchain keys too long: keys built from a 64 byte indexing prefix take up to \
+				106 bytes, more than the maximum of 64"
+			),
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[derive(Clone, Default)]
+	struct MemorySink(Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>);
+
+	impl CanonicalSink for MemorySink {
+		fn write(&mut self, key: &[u8], value: &[u8]) {
+			self.0.lock().insert(key.to_vec(), value.to_vec());
+		}
+
+		fn delete(&mut self, key: &[u8]) {
+			self.0.lock().remove(key);
+		}
+
+		fn read(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+			self.0.lock().get(key).cloned()
+		}
+	}
+
+	#[test]
+	fn canonical_entries_are_written_to_custom_sink() {
+		let client = Arc::new(MockClient::new());
+		let sink = MemorySink::default();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink.clone());
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			(a1, a2)
+		});
+		client.finalize_block(a2.hash(), Some(2));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a2 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+
+		// Nodes 0 (a1), 1 and 2 (a2) landed in the sink.
+		let entries = sink.0.lock().clone();
+		assert_eq!(entries.len(), 3);
+		for (block, pos) in [(&a1, 0), (&a2, 1), (&a2, 2)] {
+			let key = block.get_offchain_key(pos, OffchainKeyType::Canon);
+			assert_eq!(entries.get(&key), Some(&block.leaf_data));
+		}
+		// Nothing was written under canonical keys in the offchain db, while the fork-aware
+		// entries were removed from it.
+		client.check_offchain_storage(OffchainKeyType::Canon, &[&a1, &a2], |val, _block| {
+			assert!(val.is_none());
+		});
+		client.assert_pruned(&[&a1, &a2]);
+	}
+
+	/// Sink applying the writes of a block only once it is committed.
+	#[derive(Clone, Default)]
+	struct TransactionalSink {
+		pending: Vec<Vec<u8>>,
+		committed: Arc<Mutex<Vec<Vec<Vec<u8>>>>>,
+	}
+
+	impl CanonicalSink for TransactionalSink {
+		fn write(&mut self, key: &[u8], _value: &[u8]) {
+			self.pending.push(key.to_vec());
+		}
+
+		fn delete(&mut self, _key: &[u8]) {}
+
+		fn begin_block(&mut self) {
+			assert!(self.pending.is_empty());
+		}
+
+		fn commit_block(&mut self) -> Result<(), Error> {
+			self.committed.lock().push(std::mem::take(&mut self.pending));
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn canonical_entries_are_committed_once_per_block() {
+		let client = Arc::new(MockClient::new());
+		let sink = TransactionalSink::default();
+		let committed = sink.committed.clone();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink);
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2 -> A3
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a3.hash(), Some(3));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a3 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+
+		// One batch per block, holding all the nodes added by that block.
+		let key = |block: &MmrBlock, pos| block.get_offchain_key(pos, OffchainKeyType::Canon);
+		assert_eq!(
+			*committed.lock(),
+			vec![vec![key(&a1, 0)], vec![key(&a2, 1), key(&a2, 2)], vec![key(&a3, 3)]]
+		);
+		client.assert_pruned(&[&a1, &a2, &a3]);
+	}
+
+	#[test]
+	fn migrate_prefix_writes_canonical_entries_to_sink() {
+		const OLD_PREFIX: &[u8] = b"old_mmr";
+		let client = Arc::new(MockClient::new());
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", None).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", None).await;
+			(a1, a2)
+		});
+		client.finalize_block(a2.hash(), None);
+
+		let mut offchain_db = client.offchain_db();
+		for pos in 0..3 {
+			let old_key = NodesUtils::node_canon_offchain_key(OLD_PREFIX, pos);
+			offchain_db.local_storage_set(StorageKind::PERSISTENT, &old_key, &old_key);
+		}
+		let sink = MemorySink::default();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink.clone());
+
+		let options = PrefixMigration { include_fork_entries: false, delete_old: true };
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 2, options), 3);
+		let entries = sink.0.lock().clone();
+		assert_eq!(entries.len(), 3);
+		for (block, pos) in [(&a1, 0), (&a2, 1), (&a2, 2)] {
+			let key = block.get_offchain_key(pos, OffchainKeyType::Canon);
+			let old_key = NodesUtils::node_canon_offchain_key(OLD_PREFIX, pos);
+			assert_eq!(entries.get(&key), Some(&old_key));
+			assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &key).is_none());
+			assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &old_key).is_none());
+		}
+	}
+
+	#[test]
+	fn canonical_keys_follow_configured_encoding() {
+		let client = Arc::new(MockClient::new());
+		let sink = MemorySink::default();
+		let config = MmrGadgetConfig {
+			canonical_key_encoding: CanonicalKeyEncoding::FixedWidthBigEndian {
+				separator: b"/".to_vec(),
+			},
+			..Default::default()
+		};
+		let mut offchain_mmr = client.offchain_mmr(1, config).with_sink(sink.clone());
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		client.finalize_block(a1.hash(), Some(1));
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+
+		let key = [MockRuntimeApi::INDEXING_PREFIX, b"/", &0u64.to_be_bytes()[..]].concat();
+		assert_eq!(sink.0.lock().get(&key), Some(&a1.leaf_data));
+	}
+
+	#[test]
+	fn canonical_size_follows_canonicalization() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		let mut finality_notifications = client.finality_notification_stream();
+		assert_eq!(offchain_mmr.canonical_size(), 0);
+
+		// G -> A1 -> A2 -> A3 -> A4
+		let (a3, a4) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+			(a3, a4)
+		});
+
+		// 3 leaves: nodes 0 to 3.
+		client.finalize_block(a3.hash(), Some(3));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a3 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+		assert_eq!(offchain_mmr.canonical_size(), 4);
+
+		// 4 leaves: nodes 0 to 6.
+		client.finalize_block(a4.hash(), Some(4));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a4 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+		assert_eq!(offchain_mmr.canonical_size(), 7);
+	}
+
+	#[test]
+	fn debug_output_shows_progress_only() {
+		let client = Arc::new(MockClient::new());
+		let offchain_mmr = client.offchain_mmr(3, Default::default());
+
+		let debug = format!("{:?}", offchain_mmr);
+		assert_eq!(
+			debug,
+			"OffchainMmr { first_mmr_block: 3, best_canonicalized: 2, \
+			indexing_prefix: 0x6d6d725f74657374, .. }"
+		);
+	}
+
+	#[test]
+	fn fork_key_range_matches_pruned_keys() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig {
+			pruning: PruningMode::Background { queue_size: 1 },
+			..Default::default()
+		};
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2
+		//   -> B1 -> B2
+		let (a2, b1, b2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
+			let b2 = client.import_block(&BlockId::Hash(b1.hash()), b"b2", Some(1)).await;
+			(a2, b1, b2)
+		});
+		client.finalize_block(a2.hash(), Some(2));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a2 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+
+		// With background pruning, the keys to delete are handed over instead of being deleted.
+		let mut pruned = offchain_mmr.take_prune_queue();
+		let mut expected = offchain_mmr.fork_key_range(&b1.parent_hash(), 1);
+		expected.extend(offchain_mmr.fork_key_range(&b2.parent_hash(), 2));
+		pruned.sort();
+		expected.sort();
+		assert_eq!(pruned, expected);
+		assert_eq!(
+			offchain_mmr.fork_key_range(&b2.parent_hash(), 2),
+			vec![
+				b2.get_offchain_key(1, OffchainKeyType::Temp),
+				b2.get_offchain_key(2, OffchainKeyType::Temp),
+			]
+		);
+	}
+
+	#[test]
+	fn checkpoint_survives_restart() {
+		let client = Arc::new(MockClient::new());
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("mmr-checkpoint.json");
+		let config =
+			|| MmrGadgetConfig { checkpoint_path: Some(path.clone()), ..Default::default() };
+
+		// G -> A1 -> A2 -> A3
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a3.hash(), Some(3));
+
+		let mut offchain_mmr = client.offchain_mmr(1, config());
+		offchain_mmr.canonicalize_finalized(a3.hash(), 3);
+		assert_eq!(
+			Checkpoint::read(&path).unwrap(),
+			Some(Checkpoint { block_number: 3, block_hash: a3.hash() })
+		);
+		drop(offchain_mmr);
+
+		// Simulate an operator resetting the checkpoint to a1 and a restart.
+		Checkpoint { block_number: 1, block_hash: a1.hash() }.write(&path).unwrap();
+		client.undo_block_canonicalization(&a2);
+		client.undo_block_canonicalization(&a3);
+		let mut offchain_mmr = client.offchain_mmr(1, config());
+		assert_eq!(
+			offchain_mmr.canonicalize_finalized(a3.hash(), 3),
+			CatchUpSummary { blocks: 2, nodes: 3 }
+		);
+		client.assert_canonicalized(&[&a1, &a2, &a3]);
+
+		// A checkpoint that isn't on the canonical chain is ignored.
+		Checkpoint { block_number: 1, block_hash: a2.hash() }.write(&path).unwrap();
+		let mut offchain_mmr = client.offchain_mmr(1, config());
+		assert_eq!(offchain_mmr.canonicalize_finalized(a3.hash(), 3), CatchUpSummary::default());
+	}
+
+	#[test]
+	fn pending_blocks_lists_blocks_held_back_by_margin() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig { canonicalize_margin: 2, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2 -> A3 -> A4
+		let a4 = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await
+		});
+		assert_eq!(offchain_mmr.pending_blocks(), None);
+
+		client.finalize_block(a4.hash(), Some(4));
+		assert_eq!(offchain_mmr.pending_blocks(), Some((1, 4)));
+
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a4 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+		assert_eq!(offchain_mmr.pending_blocks(), Some((3, 4)));
+	}
+
+	#[test]
+	fn canonicalized_range_spans_first_mmr_block_to_last_canonicalized() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+
+		// G -> A1 -> A2 -> A3
+		let (a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			(a2, a3)
+		});
+		assert_eq!(offchain_mmr.canonicalized_range(), None);
+
+		client.finalize_block(a2.hash(), Some(2));
+		offchain_mmr.canonicalize_finalized(a2.hash(), 2);
+		assert_eq!(offchain_mmr.canonicalized_range(), Some((1, 2)));
+
+		client.finalize_block(a3.hash(), Some(3));
+		offchain_mmr.canonicalize_finalized(a3.hash(), 3);
+		assert_eq!(offchain_mmr.canonicalized_range(), Some((1, 3)));
+	}
+
+	#[test]
+	fn rehydrate_fork_restores_fork_entries() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			(a1, a2)
+		});
+		client.finalize_block(a2.hash(), Some(2));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a2 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+		client.assert_canonicalized(&[&a1, &a2]);
+
+		// A2 added a leaf and a parent node.
+		assert_eq!(offchain_mmr.rehydrate_fork(2, a1.hash()), 2);
+		client.assert_not_pruned(&[&a2]);
+		client.assert_pruned(&[&a1]);
+		client.check_offchain_storage(OffchainKeyType::Canon, &[&a2], |val, block| {
+			assert_eq!(val.as_ref(), Some(&block.leaf_data));
+		});
+	}
+
+	#[test]
+	fn migrate_prefix_commits_canonical_entries_in_batches() {
+		const OLD_PREFIX: &[u8] = b"old_mmr";
+		let client = Arc::new(MockClient::new());
+		let sink = TransactionalSink::default();
+		let committed = sink.committed.clone();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink);
+
+		// 1025 leaves, i.e. 2049 nodes, take 3 batches.
+		let mut offchain_db = client.offchain_db();
+		for pos in 0..2049 {
+			let old_key = NodesUtils::node_canon_offchain_key(OLD_PREFIX, pos);
+			offchain_db.local_storage_set(StorageKind::PERSISTENT, &old_key, &old_key);
+		}
+		let last = futures::executor::block_on(async {
+			let mut last = client.import_block(&BlockId::Number(0), b"a1", None).await;
+			for _ in 1..1025 {
+				last = client.import_block(&BlockId::Hash(last.hash()), b"a", None).await;
+			}
+			last
+		});
+		client.finalize_block(last.hash(), None);
+
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 1025, Default::default()), 2049);
+		let batch_sizes: Vec<_> = committed.lock().iter().map(|batch| batch.len()).collect();
+		assert_eq!(batch_sizes, vec![1024, 1024, 1]);
+	}
+
+	#[test]
+	fn catch_up_abandons_blocks_that_are_not_finalized() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+
+		// G -> A1 -> A2 -> A3
+		//   -> B1 -> B2 -> B3
+		let (a1, a2, a3, b1, b2, b3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let b1 = client.import_block(&BlockId::Number(0), b"b1", Some(0)).await;
+			let b2 = client.import_block(&BlockId::Hash(b1.hash()), b"b2", Some(1)).await;
+			let b3 = client.import_block(&BlockId::Hash(b2.hash()), b"b3", Some(2)).await;
+			(a1, a2, a3, b1, b2, b3)
+		});
+		// The A fork gets finalized while catching up to the B fork.
+		client.finalize_block(a3.hash(), Some(3));
+
+		assert_eq!(
+			offchain_mmr.canonicalize_finalized(b3.hash(), 3),
+			CatchUpSummary { blocks: 3, nodes: 4 }
+		);
+		client.assert_canonicalized(&[&a1, &a2, &a3]);
+		client.assert_not_pruned(&[&b2, &b3]);
+	}
+
+	#[test]
+	fn offchain_latency_histograms_record_observations() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig {
+			prometheus_registry: Some(prometheus::Registry::new()),
+			..Default::default()
+		};
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		client.finalize_block(a1.hash(), Some(1));
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+		client.assert_canonicalized(&[&a1]);
+
+		let metrics = offchain_mmr.metrics.as_ref().expect("a registry was provided");
+		assert!(metrics.offchain_read_time.get_sample_count() > 0);
+		assert!(metrics.offchain_write_time.get_sample_count() > 0);
+		assert!(metrics.offchain_delete_time.get_sample_count() > 0);
+	}
+
+	#[test]
+	fn migrate_prefix_follows_configured_encoding() {
+		const OLD_PREFIX: &[u8] = b"old_mmr";
+		let client = Arc::new(MockClient::new());
+		let encoding = CanonicalKeyEncoding::FixedWidthBigEndian { separator: b"/".to_vec() };
+		let config =
+			MmrGadgetConfig { canonical_key_encoding: encoding.clone(), ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+
+		// G -> A1
+		let a1 = futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", None));
+		client.finalize_block(a1.hash(), None);
+		let mut offchain_db = client.offchain_db();
+		let old_key = encoding.canonical_key(OLD_PREFIX, 0);
+		offchain_db.local_storage_set(StorageKind::PERSISTENT, &old_key, b"elem");
+
+		let options = PrefixMigration { include_fork_entries: false, delete_old: true };
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 1, options), 1);
+		let new_key = encoding.canonical_key(MockRuntimeApi::INDEXING_PREFIX, 0);
+		assert_eq!(
+			offchain_db.local_storage_get(StorageKind::PERSISTENT, &new_key),
+			Some(b"elem".to_vec())
+		);
+		assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &old_key).is_none());
+	}
+
+	#[test]
+	fn entry_stats_counts_canonical_and_fork_entries() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+
+		// G -> A1 -> A2
+		//         -> B2
+		let (a1, a2, b2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let b2 = client.import_block(&BlockId::Hash(a1.hash()), b"b2", Some(1)).await;
+			(a1, a2, b2)
+		});
+		client.finalize_block(a1.hash(), Some(1));
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+		client.assert_canonicalized(&[&a1]);
+		client.assert_not_pruned(&[&a2, &b2]);
+
+		// A1 added node 0. A2 and B2 both added nodes 1 and 2, under the same fork-aware keys
+		// since they share their parent.
+		assert_eq!(offchain_mmr.entry_stats(1, 2), EntryStats { canonical: 1, fork: 2 });
+		assert_eq!(offchain_mmr.entry_stats(2, 2), EntryStats { canonical: 0, fork: 2 });
+	}
+
+	#[test]
+	fn descending_catch_up_canonicalizes_recent_blocks_first() {
+		let client = Arc::new(MockClient::new());
+		let sink = TransactionalSink::default();
+		let committed = sink.committed.clone();
+		let config =
+			MmrGadgetConfig { catch_up_order: CatchUpOrder::Descending, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config).with_sink(sink);
+
+		// G -> A1 -> A2 -> A3
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a3.hash(), Some(3));
+
+		assert_eq!(
+			offchain_mmr.canonicalize_finalized(a3.hash(), 3),
+			CatchUpSummary { blocks: 3, nodes: 4 }
+		);
+		// A3 is committed, hence readable, before A2 and A1 are backfilled.
+		let key = |block: &MmrBlock, pos| block.get_offchain_key(pos, OffchainKeyType::Canon);
+		assert_eq!(
+			*committed.lock(),
+			vec![vec![key(&a3, 3)], vec![key(&a2, 1), key(&a2, 2)], vec![key(&a1, 0)]]
+		);
+		client.assert_pruned(&[&a1, &a2, &a3]);
+		assert_eq!(offchain_mmr.best_canonicalized, 3);
+		assert_eq!(offchain_mmr.status().best_canonicalized(), Some(3));
+	}
+
+	#[test]
+	fn catch_up_completion_is_reported_once() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2 -> A3
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a3.hash(), Some(3));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a3 was finalized");
+		assert!(!offchain_mmr.is_caught_up());
+
+		offchain_mmr.canonicalize_catch_up(&notification);
+		offchain_mmr.canonicalize_and_prune(notification);
+		client.assert_canonicalized(&[&a1, &a2, &a3]);
+		assert!(offchain_mmr.finish_catch_up());
+		assert!(offchain_mmr.is_caught_up());
+
+		// Steady-state processing doesn't report it again.
+		assert!(!offchain_mmr.finish_catch_up());
+	}
+
+	#[test]
+	fn debug_replay_block_reports_key_operations() {
+		let client = Arc::new(MockClient::new());
+		let sink = MemorySink::default();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink.clone());
+
+		// G -> A1 -> A2
+		let a2 = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await
+		});
+		client.finalize_block(a2.hash(), Some(2));
+		// Lose the fork-aware entry of the parent node added by A2.
+		client.offchain_db().local_storage_clear(
+			StorageKind::PERSISTENT,
+			&a2.get_offchain_key(2, OffchainKeyType::Temp),
+		);
+
+		let temp = |pos| (pos, a2.get_offchain_key(pos, OffchainKeyType::Temp));
+		let canon = |pos| (pos, a2.get_offchain_key(pos, OffchainKeyType::Canon));
+		let expected = ReplayReport {
+			fork_keys_read: vec![temp(1), temp(2)],
+			canonical_keys_written: vec![canon(1)],
+			missing_positions: vec![2],
+			committed: false,
+		};
+		assert_eq!(offchain_mmr.debug_replay_block(2, a2.hash()), Some(expected.clone()));
+		assert!(sink.0.lock().is_empty());
+
+		assert_eq!(
+			offchain_mmr.debug_replay_block_and_commit(2, a2.hash()),
+			Some(ReplayReport { committed: true, ..expected })
+		);
+		assert_eq!(sink.0.lock().get(&canon(1).1), Some(&a2.leaf_data));
+		assert!(client
+			.offchain_db()
+			.local_storage_get(StorageKind::PERSISTENT, &temp(1).1)
+			.is_some());
+		assert_eq!(offchain_mmr.best_canonicalized, 0);
+	}
+
+	#[test]
+	fn verify_canonical_entries_detects_missing_entries() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+
+		// G -> A1 -> A2 -> A3
+		let (a1, a2, a3) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			(a1, a2, a3)
+		});
+		client.finalize_block(a3.hash(), Some(3));
+		offchain_mmr.canonicalize_finalized(a3.hash(), 3);
+		client.assert_canonicalized(&[&a1, &a2, &a3]);
+		assert!(offchain_mmr.verify_canonical_entries().is_ok());
+
+		// Wipe the canonical entries added by A2.
+		client.undo_block_canonicalization(&a2);
+		client.assert_not_canonicalized(&[&a2]);
+		match offchain_mmr.verify_canonical_entries() {
+			Err(e @ Error::InconsistentOffchainDb(_)) => assert_eq!(
+				e.to_string(),
+				"Inconsistent MMR offchain db: found 2 canonical entries, expected 4 up to block #3"
+			),
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn fork_keys_follow_configured_discriminator() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		let fork_keys_read = |fork_discriminator| {
+			let config = MmrGadgetConfig { fork_discriminator, ..Default::default() };
+			client
+				.offchain_mmr(1, config)
+				.debug_replay_block(1, a1.hash())
+				.expect("a1 is known")
+				.fork_keys_read
+		};
+
+		// The default matches the keys written by the pallet.
+		assert_eq!(
+			fork_keys_read(ForkDiscriminator::ParentHash),
+			vec![(0, a1.get_offchain_key(0, OffchainKeyType::Temp))]
+		);
+		assert_eq!(
+			fork_keys_read(ForkDiscriminator::BlockHash),
+			vec![(
+				0,
+				NodesUtils::node_temp_offchain_key::<Header>(
+					MockRuntimeApi::INDEXING_PREFIX,
+					0,
+					a1.hash()
+				)
+			)]
+		);
+	}
+
+	#[test]
+	fn leaves_only_scope_prunes_internal_nodes() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig {
+			canonicalize_scope: CanonicalizeScope::LeavesOnly,
+			..Default::default()
+		};
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			(a1, a2)
+		});
+		client.finalize_block(a2.hash(), Some(2));
+		assert_eq!(
+			offchain_mmr.canonicalize_finalized(a2.hash(), 2),
+			CatchUpSummary { blocks: 2, nodes: 2 }
+		);
+
+		// A2 added leaf 1 and internal node 2: only the leaf is canonical, both are pruned.
+		let mut offchain_db = client.offchain_db();
+		let mut get = |key: Vec<u8>| offchain_db.local_storage_get(StorageKind::PERSISTENT, &key);
+		assert_eq!(get(a1.get_offchain_key(0, OffchainKeyType::Canon)), Some(a1.leaf_data.clone()));
+		assert_eq!(get(a2.get_offchain_key(1, OffchainKeyType::Canon)), Some(a2.leaf_data.clone()));
+		assert_eq!(get(a2.get_offchain_key(2, OffchainKeyType::Canon)), None);
+		client.assert_pruned(&[&a1, &a2]);
+		assert!(offchain_mmr.verify_canonical_entries().is_ok());
+	}
+
+	struct MockHeaderProvider(CachedHeaderMetadata<Block>);
+
+	impl HeaderProvider<Block> for MockHeaderProvider {
+		fn header_metadata(&self, hash: Hash) -> Option<CachedHeaderMetadata<Block>> {
+			(hash == self.0.hash).then(|| self.0.clone())
+		}
+	}
+
+	#[test]
+	fn headers_missing_from_client_are_read_from_provider() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		// Offloaded block sharing the fork-aware keys of A1, unknown to the client.
+		let offloaded = Header::new(
+			1,
+			Default::default(),
+			Default::default(),
+			a1.parent_hash(),
+			Default::default(),
+		);
+		let offloaded_hash = offloaded.hash();
+
+		let offchain_mmr = client.offchain_mmr(1, Default::default());
+		assert_eq!(offchain_mmr.debug_replay_block(1, offloaded_hash), None);
+
+		let config = MmrGadgetConfig {
+			header_provider: Some(Arc::new(MockHeaderProvider((&offloaded).into()))),
+			..Default::default()
+		};
+		let offchain_mmr = client.offchain_mmr(1, config);
+		let report =
+			offchain_mmr.debug_replay_block(1, offloaded_hash).expect("header is provided");
+		assert_eq!(report.fork_keys_read, vec![(0, a1.get_offchain_key(0, OffchainKeyType::Temp))]);
+		assert!(report.missing_positions.is_empty());
+	}
+
+	#[test]
+	fn read_canonical_nodes_is_independent_of_concurrency() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1 -> A2 -> A3 -> A4 -> A5
+		let mut parent = BlockId::Number(0);
+		let mut blocks = Vec::new();
+		for (i, name) in [b"a1", b"a2", b"a3", b"a4", b"a5"].into_iter().enumerate() {
+			let block =
+				futures::executor::block_on(client.import_block(&parent, name, Some(i as u64)));
+			parent = BlockId::Hash(block.hash());
+			blocks.push(block);
+		}
+		client.finalize_block(blocks[4].hash(), Some(5));
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		offchain_mmr.canonicalize_finalized(blocks[4].hash(), 5);
+
+		// Include positions past the canonicalized ones.
+		let canonical_size = offchain_mmr.canonical_size();
+		assert_eq!(canonical_size, 8);
+		let positions = 0..canonical_size + 3;
+		let sequential = offchain_mmr.read_canonical_nodes(positions.clone());
+		assert_eq!(sequential.iter().filter(|node| node.is_some()).count(), 8);
+		for read_concurrency in [2, 3, 16] {
+			let config = MmrGadgetConfig { read_concurrency, ..Default::default() };
+			let offchain_mmr = client.offchain_mmr(1, config);
+			assert_eq!(offchain_mmr.read_canonical_nodes(positions.clone()), sequential);
+		}
+	}
+
+	#[test]
+	fn leaf_positions_are_told_apart_from_internal_nodes() {
+		let leaves: Vec<_> = (0..16).filter(|pos| is_leaf_pos(*pos)).collect();
+		assert_eq!(leaves, vec![0, 1, 3, 4, 7, 8, 10, 11, 15]);
+	}
+
+	#[test]
+	fn mmr_arithmetic_does_not_overflow() {
+		for leaf_count in [0, 1, 2, 5, 1 << 40] {
+			assert_eq!(mmr_size(leaf_count), Some(NodesUtils::new(leaf_count).size()));
+		}
+		assert_eq!(leaf_pos(0), Some(0));
+		assert_eq!(leaf_pos(1), Some(1));
+		assert_eq!(leaf_pos(u64::MAX / 2), Some(u64::MAX - 64));
+		assert_eq!(leaf_pos(u64::MAX / 2 + 1), None);
+		assert_eq!(mmr_size(u64::MAX), None);
+	}
+
+	#[test]
+	fn canonicalization_ranges_handle_boundary_block_numbers() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		client.finalize_block(a1.hash(), Some(1));
+		let config = MmrGadgetConfig { canonicalize_margin: u64::MAX, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		// The margin holds back every block.
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+		client.assert_not_canonicalized(&[&a1]);
+		assert_eq!(offchain_mmr.canonical_size(), 0);
+
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+		client.assert_canonicalized(&[&a1]);
+		for (from, to) in [(0, 0), (0, 1), (1, 0), (0, u64::MAX), (u64::MAX, u64::MAX)] {
+			let expected =
+				if (from, to) == (0, 1) || (from, to) == (0, u64::MAX) { vec![0] } else { vec![] };
+			assert_eq!(offchain_mmr.canonical_delta(from, to), expected, "{} -> {}", from, to);
+		}
+		assert_eq!(offchain_mmr.migrate_prefix(b"old", u64::MAX, Default::default()), 0);
+	}
+
+	#[test]
+	fn canonicalized_entries_are_counted_by_kind() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig {
+			prometheus_registry: Some(prometheus::Registry::new()),
+			..Default::default()
+		};
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		let canonicalized = |offchain_mmr: &OffchainMmr<_, _, _>, kind| {
+			let metrics = offchain_mmr.metrics.as_ref().expect("a registry was provided");
+			metrics.canonicalized_entries.with_label_values(&[kind]).get()
+		};
+
+		// G -> A1 -> A2 -> A3
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		let a2 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a1.hash()),
+			b"a2",
+			Some(1),
+		));
+		let a3 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a2.hash()),
+			b"a3",
+			Some(2),
+		));
+		client.finalize_block(a3.hash(), Some(3));
+		offchain_mmr.canonicalize_finalized(a3.hash(), 3);
+		// Leaves 0 and 1, node 2 and leaf 3.
+		assert_eq!(canonicalized(&offchain_mmr, "leaf"), 3);
+		assert_eq!(canonicalized(&offchain_mmr, "node"), 1);
+
+		// A4 adds leaf 4 and nodes 5 and 6.
+		let a4 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a3.hash()),
+			b"a4",
+			Some(3),
+		));
+		client.finalize_block(a4.hash(), Some(4));
+		offchain_mmr.canonicalize_finalized(a4.hash(), 4);
+		assert_eq!(canonicalized(&offchain_mmr, "leaf"), 4);
+		assert_eq!(canonicalized(&offchain_mmr, "node"), 3);
+	}
+
+	#[test]
+	fn canonicalize_up_to_canonicalizes_the_given_height() {
+		let client = Arc::new(MockClient::new());
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+
+		// G -> A1 -> A2 -> A3
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		let a2 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a1.hash()),
+			b"a2",
+			Some(1),
+		));
+		let a3 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a2.hash()),
+			b"a3",
+			Some(2),
+		));
+
+		client.finalize_block(a2.hash(), Some(2));
+		offchain_mmr.canonicalize_up_to(2, a2.hash());
+		client.assert_canonicalized(&[&a1, &a2]);
+		client.assert_not_canonicalized(&[&a3]);
+		assert_eq!(offchain_mmr.best_canonicalized, 2);
+
+		client.finalize_block(a3.hash(), Some(3));
+		offchain_mmr.canonicalize_up_to(3, a3.hash());
+		client.assert_canonicalized(&[&a1, &a2, &a3]);
+		assert_eq!(offchain_mmr.best_canonicalized, 3);
+	}
+
+	#[test]
+	fn missing_offchain_indexing_is_reported() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1 -> A2, with the MMR leaves of A1 missing from the offchain db.
+		let a1 = futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", None));
+		let a2 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a1.hash()),
+			b"a2",
+			Some(1),
+		));
+		client.finalize_block(a2.hash(), Some(2));
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		assert!(!offchain_mmr.check_offchain_indexing(2));
+		assert!(offchain_mmr.status().is_offchain_indexing_missing());
+
+		// Nothing is reported once the leaves are there.
+		let client = Arc::new(MockClient::new());
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		client.finalize_block(a1.hash(), Some(1));
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		assert!(offchain_mmr.check_offchain_indexing(1));
+		assert!(!offchain_mmr.status().is_offchain_indexing_missing());
+	}
+
+	#[cfg(feature = "serde-events")]
+	#[test]
+	fn canonicalized_event_serde_round_trip() {
+		use super::CanonicalizedEvent;
+
+		let event = CanonicalizedEvent::<Block> {
+			block_number: 3,
+			block_hash: Hash::repeat_byte(0xab),
+			moved_positions: vec![4, 5, 6],
+		};
+
+		let json = serde_json::to_value(&event).unwrap();
+		assert_eq!(
+			json,
+			serde_json::json!({
+				"block_number": 3,
+				"block_hash": format!("0x{}", "ab".repeat(32)),
+				"moved_positions": [4, 5, 6],
+			})
+		);
+		assert_eq!(serde_json::from_value::<CanonicalizedEvent<Block>>(json).unwrap(), event);
+	}
+
+	#[test]
+	fn canonical_delta_matches_mmr_growth() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1 -> A2 -> A3 -> A4
+		let mut parent = BlockId::Number(0);
+		let mut blocks = Vec::new();
+		for (i, name) in [b"a1", b"a2", b"a3", b"a4"].into_iter().enumerate() {
+			let block =
+				futures::executor::block_on(client.import_block(&parent, name, Some(i as u64)));
+			parent = BlockId::Hash(block.hash());
+			blocks.push(block);
+		}
+		client.finalize_block(blocks[3].hash(), Some(4));
+		let config = MmrGadgetConfig { canonicalize_margin: 1, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		offchain_mmr.canonicalize_finalized(blocks[3].hash(), 4);
+
+		// A2 and A3 add leaf 1 and node 2, then leaf 3.
+		let growth: Vec<_> = (1..3).flat_map(NodesUtils::right_branch_ending_in_leaf).collect();
+		assert_eq!(growth, vec![1, 2, 3]);
+		assert_eq!(offchain_mmr.canonical_delta(1, 3), growth);
+		assert_eq!(offchain_mmr.canonical_delta(0, 3), vec![0, 1, 2, 3]);
+		// A4 is held back by the margin.
+		assert_eq!(offchain_mmr.canonical_delta(2, 4), vec![3]);
+		assert_eq!(offchain_mmr.canonical_delta(3, 4), Vec::<u64>::new());
+		assert_eq!(offchain_mmr.canonical_delta(3, 1), Vec::<u64>::new());
+
+		let config = MmrGadgetConfig {
+			canonicalize_scope: CanonicalizeScope::LeavesOnly,
+			..Default::default()
+		};
+		let offchain_mmr = client.offchain_mmr(1, config);
+		assert_eq!(offchain_mmr.canonical_delta(1, 3), vec![1, 3]);
+	}
+
+	/// Fails to commit the blocks writing `failing_key` while `failures_left` isn't zero.
+	struct FlakySink {
+		failing_key: Vec<u8>,
+		failures_left: usize,
+		pending: Vec<Vec<u8>>,
+		committed: Arc<Mutex<Vec<Vec<u8>>>>,
+	}
+
+	impl CanonicalSink for FlakySink {
+		fn write(&mut self, key: &[u8], _value: &[u8]) {
+			self.pending.push(key.to_vec());
+		}
+
+		fn delete(&mut self, _key: &[u8]) {}
+
+		fn commit_block(&mut self) -> Result<(), Error> {
+			let pending = std::mem::take(&mut self.pending);
+			if self.failures_left > 0 && pending.contains(&self.failing_key) {
+				self.failures_left -= 1;
+				return Err(Error::CanonicalSink("transient failure".into()))
+			}
+			self.committed.lock().extend(pending);
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn failed_block_is_retried_without_stalling_later_blocks() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1 -> A2 -> A3 -> A4
+		let mut parent = BlockId::Number(0);
+		let mut blocks = Vec::new();
+		for (i, name) in [b"a1", b"a2", b"a3", b"a4"].into_iter().enumerate() {
+			let block =
+				futures::executor::block_on(client.import_block(&parent, name, Some(i as u64)));
+			parent = BlockId::Hash(block.hash());
+			blocks.push(block);
+		}
+		let (a1, a2, a3, a4) = (&blocks[0], &blocks[1], &blocks[2], &blocks[3]);
+		let canon_key = |block: &MmrBlock, pos| block.get_offchain_key(pos, OffchainKeyType::Canon);
+		let sink = FlakySink {
+			failing_key: canon_key(a2, 1),
+			failures_left: 2,
+			pending: Vec::new(),
+			committed: Default::default(),
+		};
+		let committed = sink.committed.clone();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink);
+
+		// A2 fails a first time.
+		client.finalize_block(a2.hash(), Some(2));
+		offchain_mmr.canonicalize_up_to(2, a2.hash());
+		assert_eq!(*committed.lock(), vec![canon_key(a1, 0)]);
+		assert_eq!(offchain_mmr.best_canonicalized, 1);
+
+		// A2 fails again, A3 is canonicalized but the watermark stays behind A2.
+		client.finalize_block(a3.hash(), Some(3));
+		offchain_mmr.canonicalize_up_to(3, a3.hash());
+		assert_eq!(*committed.lock(), vec![canon_key(a1, 0), canon_key(a3, 3)]);
+		assert_eq!(offchain_mmr.best_canonicalized, 1);
+		assert_eq!(offchain_mmr.status().best_canonicalized(), Some(1));
+		client.assert_not_pruned(&[a2]);
+
+		// A2 succeeds on its second retry, and the watermark catches up.
+		client.finalize_block(a4.hash(), Some(4));
+		offchain_mmr.canonicalize_up_to(4, a4.hash());
+		assert_eq!(
+			*committed.lock(),
+			vec![
+				canon_key(a1, 0),
+				canon_key(a3, 3),
+				canon_key(a2, 1),
+				canon_key(a2, 2),
+				canon_key(a4, 4),
+				canon_key(a4, 5),
+				canon_key(a4, 6),
+			]
+		);
+		assert_eq!(offchain_mmr.best_canonicalized, 4);
+		assert!(offchain_mmr.take_canonicalization_errors().is_empty());
+	}
+
+	#[test]
+	fn failed_block_is_reported_when_out_of_retries() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1 -> A2
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		let a2 = futures::executor::block_on(client.import_block(
+			&BlockId::Hash(a1.hash()),
+			b"a2",
+			Some(1),
+		));
+		let sink = FlakySink {
+			failing_key: a1.get_offchain_key(0, OffchainKeyType::Canon),
+			failures_left: usize::MAX,
+			pending: Vec::new(),
+			committed: Default::default(),
+		};
+		let config = MmrGadgetConfig { max_canonicalize_retries: 1, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config).with_sink(sink);
+
+		client.finalize_block(a1.hash(), Some(1));
+		offchain_mmr.canonicalize_up_to(1, a1.hash());
+		assert!(offchain_mmr.take_canonicalization_errors().is_empty());
+
+		client.finalize_block(a2.hash(), Some(2));
+		offchain_mmr.canonicalize_up_to(2, a2.hash());
+		let errors = offchain_mmr.take_canonicalization_errors();
+		assert!(
+			matches!(errors.as_slice(), [Error::CanonicalizationFailed(msg)] if msg.contains("#1")),
+			"{:?}",
+			errors
+		);
+		assert_eq!(offchain_mmr.best_canonicalized, 0);
+		client.assert_not_pruned(&[&a1]);
+	}
+
+	#[test]
+	fn migrate_prefix_stops_at_failed_commit() {
+		const OLD_PREFIX: &[u8] = b"old_mmr";
+		let client = Arc::new(MockClient::new());
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", None).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			(a1, a2)
+		});
+		client.finalize_block(a2.hash(), None);
+
+		let mut offchain_db = client.offchain_db();
+		let old_key = NodesUtils::node_canon_offchain_key(OLD_PREFIX, 0);
+		offchain_db.local_storage_set(StorageKind::PERSISTENT, &old_key, b"elem");
+		let sink = FlakySink {
+			failing_key: a1.get_offchain_key(0, OffchainKeyType::Canon),
+			failures_left: 1,
+			pending: Vec::new(),
+			committed: Default::default(),
+		};
+		let committed = sink.committed.clone();
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default()).with_sink(sink);
+
+		// Neither the old entry nor the fork-aware ones are touched by a failed migration.
+		let options = PrefixMigration { include_fork_entries: true, delete_old: true };
+		let fork_key =
+			NodesUtils::node_temp_offchain_key::<Header>(OLD_PREFIX, 1, a2.parent_hash());
+		offchain_db.local_storage_set(StorageKind::PERSISTENT, &fork_key, b"fork");
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 2, options), 0);
+		assert!(committed.lock().is_empty());
+		assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &old_key).is_some());
+		assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &fork_key).is_some());
+
+		// The migration can be run again.
+		assert_eq!(offchain_mmr.migrate_prefix(OLD_PREFIX, 2, options), 2);
+		assert_eq!(*committed.lock(), vec![a1.get_offchain_key(0, OffchainKeyType::Canon)]);
+		assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &old_key).is_none());
+		assert!(offchain_db.local_storage_get(StorageKind::PERSISTENT, &fork_key).is_none());
+	}
+
+	#[test]
+	fn blocks_below_start_height_are_ignored() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig { start_height: Some(4), ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		let mut finality_notifications = client.finality_notification_stream();
+		assert_eq!(offchain_mmr.best_canonicalized, 3);
+
+		// G -> A1 -> A2 -> A3 -> A4
+		//         -> B2 -> B3
+		let blocks = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			let a3 = client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await;
+			let a4 = client.import_block(&BlockId::Hash(a3.hash()), b"a4", Some(3)).await;
+			let b2 = client.import_block(&BlockId::Hash(a1.hash()), b"b2", Some(1)).await;
+			let b3 = client.import_block(&BlockId::Hash(b2.hash()), b"b3", Some(2)).await;
+			[a1, a2, a3, a4, b2, b3]
+		});
+		let [a1, a2, a3, a4, b2, b3] = &blocks;
+		client.finalize_block(a4.hash(), Some(4));
+		let notification =
+			futures::executor::block_on(finality_notifications.next()).expect("a4 was finalized");
+		offchain_mmr.canonicalize_and_prune(notification);
+
+		client.assert_canonicalized(&[a4]);
+		client.assert_not_canonicalized(&[a1, a3]);
+		// B2 shares its fork-aware keys with A2, and overwrote them.
+		client.check_offchain_storage(OffchainKeyType::Canon, &[a2], |val, _block| {
+			assert!(val.is_none());
+		});
+		client.assert_not_pruned(&[b2, b3]);
+		assert_eq!(offchain_mmr.best_canonicalized, 4);
+	}
+
+	/// Hashes the concatenation of the peaks.
+	struct ConcatRootHasher;
+
+	impl MmrRootHasher for ConcatRootHasher {
+		fn root(&self, peaks: &[Vec<u8>]) -> Option<MmrRootHash> {
+			Some(sp_core::keccak_256(&peaks.concat()).into())
+		}
+	}
+
+	#[test]
+	fn tampered_canonical_entries_do_not_match_runtime_root() {
+		let client = Arc::new(MockClient::new());
+		let config = MmrGadgetConfig {
+			prometheus_registry: Some(prometheus::Registry::new()),
+			root_hasher: Some(Arc::new(ConcatRootHasher)),
+			..Default::default()
+		};
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		let mismatches = |offchain_mmr: &OffchainMmr<_, _, _>| {
+			offchain_mmr
+				.metrics
+				.as_ref()
+				.expect("a registry was provided")
+				.root_mismatches
+				.get()
+		};
+		let set_runtime_root = |peaks: &[&[u8]]| {
+			let peaks = peaks.iter().map(|peak| peak.to_vec()).collect::<Vec<_>>();
+			client.runtime_api_params.lock().mmr_root = ConcatRootHasher.root(&peaks);
+		};
+
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			(a1, a2)
+		});
+
+		// Leaf 0 is the only peak.
+		client.finalize_block(a1.hash(), Some(1));
+		set_runtime_root(&[&a1.leaf_data[..]]);
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+		assert_eq!(mismatches(&offchain_mmr), 0);
+
+		// Node 2, the only peak once A2 is imported, is tampered with before being canonicalized.
+		client.offchain_db().local_storage_set(
+			StorageKind::PERSISTENT,
+			&a2.get_offchain_key(2, OffchainKeyType::Temp),
+			b"tampered",
+		);
+		client.finalize_block(a2.hash(), Some(2));
+		set_runtime_root(&[&a2.leaf_data[..]]);
+		offchain_mmr.canonicalize_finalized(a2.hash(), 2);
+		assert_eq!(mismatches(&offchain_mmr), 1);
+	}
+
+	#[test]
+	fn root_is_checked_against_peaks_read_from_custom_sink() {
+		let client = Arc::new(MockClient::new());
+		let config =
+			MmrGadgetConfig { root_hasher: Some(Arc::new(ConcatRootHasher)), ..Default::default() };
+		let sink = MemorySink::default();
+		let mut offchain_mmr = client.offchain_mmr(1, config).with_sink(sink.clone());
+
+		// G -> A1
+		let a1 =
+			futures::executor::block_on(client.import_block(&BlockId::Number(0), b"a1", Some(0)));
+		client.finalize_block(a1.hash(), Some(1));
+		client.runtime_api_params.lock().mmr_root = ConcatRootHasher.root(&[a1.leaf_data.to_vec()]);
+		offchain_mmr.canonicalize_finalized(a1.hash(), 1);
+
+		// Leaf 0, the only peak, was only written to the sink.
+		let canon_key = a1.get_offchain_key(0, OffchainKeyType::Canon);
+		assert_eq!(
+			client.offchain_db().local_storage_get(StorageKind::PERSISTENT, &canon_key),
+			None
+		);
+		assert_eq!(offchain_mmr.check_root(a1.hash(), 1), Some(true));
+
+		sink.0.lock().insert(canon_key, b"tampered".to_vec());
+		assert_eq!(offchain_mmr.check_root(a1.hash(), 1), Some(false));
+	}
+
+	#[test]
+	fn duplicate_finality_notifications_are_dropped() {
+		let client = Arc::new(MockClient::new());
+		let mut finality_notifications = client.finality_notification_stream();
+
+		// G -> A1 -> A2
+		let (a1, a2) = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			(a1, a2)
+		});
+		client.finalize_block(a1.hash(), Some(1));
+		client.finalize_block(a2.hash(), Some(2));
+		let mut next_notification =
+			|| futures::executor::block_on(finality_notifications.next()).unwrap();
+		let (n1, n2) = (next_notification(), next_notification());
+
+		let config = MmrGadgetConfig { duplicate_notification_window: 1, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		assert!(offchain_mmr.canonicalize_and_prune(n1.clone()));
+		assert!(!offchain_mmr.canonicalize_and_prune(n1.clone()));
+		client.assert_canonicalized(&[&a1]);
+		assert!(offchain_mmr.canonicalize_and_prune(n2.clone()));
+		assert!(!offchain_mmr.canonicalize_and_prune(n2.clone()));
+		client.assert_canonicalized(&[&a2]);
+		// Out of the window.
+		assert!(offchain_mmr.canonicalize_and_prune(n1.clone()));
+
+		let config = MmrGadgetConfig { duplicate_notification_window: 0, ..Default::default() };
+		let mut offchain_mmr = client.offchain_mmr(1, config);
+		assert!(offchain_mmr.canonicalize_and_prune(n2.clone()));
+		assert!(offchain_mmr.canonicalize_and_prune(n2));
+	}
+
+	#[test]
+	fn canonical_mmr_round_trips_through_snapshot() {
+		let client = Arc::new(MockClient::new());
+
+		// G -> A1 -> A2 -> A3
+		let a3 = futures::executor::block_on(async {
+			let a1 = client.import_block(&BlockId::Number(0), b"a1", Some(0)).await;
+			let a2 = client.import_block(&BlockId::Hash(a1.hash()), b"a2", Some(1)).await;
+			client.import_block(&BlockId::Hash(a2.hash()), b"a3", Some(2)).await.hash()
+		});
+		client.finalize_block(a3, Some(3));
+		let mut offchain_mmr = client.offchain_mmr(1, Default::default());
+		offchain_mmr.canonicalize_finalized(a3, 3);
+
+		let mut snapshot = Vec::new();
+		// Leaves 0, 1 and 3, and node 2.
+		assert_eq!(offchain_mmr.export_canonical(&mut snapshot).unwrap(), 4);
+		let nodes = offchain_mmr.read_canonical_nodes(0..4);
+		assert!(nodes.iter().all(Option::is_some));
+
+		let other_client = Arc::new(MockClient::new());
+		let mut other_mmr = other_client.offchain_mmr(1, Default::default());
+		assert_eq!(other_mmr.read_canonical_nodes(0..4), vec![None; 4]);
+		assert_eq!(other_mmr.import_canonical(&snapshot[..]).unwrap(), 4);
+		assert_eq!(other_mmr.read_canonical_nodes(0..4), nodes);
+
+		// Truncated snapshots are rejected without writing anything.
+		let mut empty_mmr = Arc::new(MockClient::new()).offchain_mmr(1, Default::default());
+		assert!(matches!(
+			empty_mmr.import_canonical(&snapshot[..snapshot.len() - 1]),
+			Err(Error::Snapshot(_))
+		));
+		assert_eq!(empty_mmr.read_canonical_nodes(0..4), vec![None; 4]);
+	}
+
+	#[test]
+	fn pair_of_sinks_commits_both_and_reports_failures() {
+		let flaky_sink = |failures_left| FlakySink {
+			failing_key: b"key".to_vec(),
+			failures_left,
+			pending: Vec::new(),
+			committed: Default::default(),
+		};
+
+		// The second sink fails: the first one is committed anyway.
+		let mut sink = (TransactionalSink::default(), flaky_sink(1));
+		sink.begin_block();
+		sink.write(b"key", b"elem");
+		assert_eq!(
+			sink.commit_block().unwrap_err().to_string(),
+			"Canonical sink error: second sink: Canonical sink error: transient failure"
+		);
+		assert_eq!(*sink.0.committed.lock(), vec![vec![b"key".to_vec()]]);
+		assert!(sink.1.committed.lock().is_empty());
+
+		// The first sink fails: the second one is still committed.
+		let mut sink = (flaky_sink(1), TransactionalSink::default());
+		sink.begin_block();
+		sink.write(b"key", b"elem");
+		assert_eq!(
+			sink.commit_block().unwrap_err().to_string(),
+			"Canonical sink error: first sink: Canonical sink error: transient failure"
+		);
+		assert!(sink.0.committed.lock().is_empty());
+		assert_eq!(*sink.1.committed.lock(), vec![vec![b"key".to_vec()]]);
+
+		// Both fail.
+		let mut sink = (flaky_sink(1), flaky_sink(1));
+		sink.write(b"key", b"elem");
+		assert_eq!(
+			sink.commit_block().unwrap_err().to_string(),
+			"Canonical sink error: first sink: Canonical sink error: transient failure; second \
+			 sink: Canonical sink error: transient failure"
+		);
+	}
 }
@@ -0,0 +1,245 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Encoding of the offchain keys of the MMR nodes.
+//!
+//! Key derivation is pure: it only depends on the indexing prefix, the node position and, for
+//! fork-aware keys, the hash identifying the fork.
+
+use codec::Encode;
+use sp_mmr_primitives::{utils::NodesUtils, NodeIndex};
+use sp_runtime::traits::Header;
+
+/// Bytes every canonical key under `indexing_prefix` starts with, for the default
+/// [`CanonicalKeyEncoding::Scale`] read by `pallet-mmr`.
+///
+/// Tools can find the canonical MMR nodes with a prefix scan of the offchain db, keeping the keys
+/// matched by [`is_canonical_key`]: fork-aware keys start with the same bytes.
+pub fn canonical_key_prefix(indexing_prefix: &[u8]) -> Vec<u8> {
+	CanonicalKeyEncoding::Scale.canonical_key_prefix(indexing_prefix)
+}
+
+/// Whether `key` is the canonical key of an MMR node under `indexing_prefix`, for the default
+/// [`CanonicalKeyEncoding::Scale`].
+pub fn is_canonical_key(indexing_prefix: &[u8], key: &[u8]) -> bool {
+	CanonicalKeyEncoding::Scale.is_canonical_key(indexing_prefix, key)
+}
+
+/// Fork-aware key of the MMR node at `pos`, under `prefix`, for the fork identified by
+/// `fork_hash`, see [`ForkDiscriminator`].
+pub fn fork_key<H: Header>(prefix: &[u8], pos: NodeIndex, fork_hash: H::Hash) -> Vec<u8> {
+	NodesUtils::node_temp_offchain_key::<H>(prefix, pos, fork_hash)
+}
+
+/// Length of the longest offchain key, fork-aware or canonical, built from `prefix`.
+pub fn max_key_len<H: Header>(prefix: &[u8], encoding: &CanonicalKeyEncoding) -> usize {
+	// Positions and hashes have a fixed size, so any will do.
+	let fork_key_len = fork_key::<H>(prefix, 0, Default::default()).len();
+	fork_key_len.max(encoding.canonical_key(prefix, 0).len())
+}
+
+/// How the canonical key of an MMR node is built from the indexing prefix and the node position.
+///
+/// Only [`CanonicalKeyEncoding::Scale`] keys can be read by `pallet-mmr` and the MMR RPC; the
+/// other encodings are meant for a [`CanonicalSink`](crate::CanonicalSink) mirroring the MMR to
+/// an external store. Fork-aware keys are written by the runtime and always SCALE-encoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CanonicalKeyEncoding {
+	/// SCALE-encoded `(prefix, pos)`, see [`NodesUtils::node_canon_offchain_key`].
+	Scale,
+	/// Raw prefix, followed by `separator` and the position as 8 big-endian bytes.
+	FixedWidthBigEndian {
+		/// Bytes between the prefix and the position.
+		separator: Vec<u8>,
+	},
+}
+
+impl Default for CanonicalKeyEncoding {
+	fn default() -> Self {
+		Self::Scale
+	}
+}
+
+impl CanonicalKeyEncoding {
+	/// Canonical key of the MMR node at `pos`, under `prefix`.
+	pub fn canonical_key(&self, prefix: &[u8], pos: NodeIndex) -> Vec<u8> {
+		match self {
+			Self::Scale => NodesUtils::node_canon_offchain_key(prefix, pos),
+			Self::FixedWidthBigEndian { separator } =>
+				[prefix, &separator[..], &pos.to_be_bytes()[..]].concat(),
+		}
+	}
+
+	/// Bytes every canonical key under `prefix` starts with, allowing external tools to find the
+	/// canonical MMR nodes with a prefix scan of the offchain db.
+	///
+	/// With [`CanonicalKeyEncoding::Scale`], the fork-aware keys written by the runtime start
+	/// with the same bytes, use [`CanonicalKeyEncoding::is_canonical_key`] to tell them apart.
+	pub fn canonical_key_prefix(&self, prefix: &[u8]) -> Vec<u8> {
+		match self {
+			Self::Scale => prefix.encode(),
+			Self::FixedWidthBigEndian { separator } => [prefix, &separator[..]].concat(),
+		}
+	}
+
+	/// Whether `key` is the canonical key of an MMR node under `prefix`.
+	pub fn is_canonical_key(&self, prefix: &[u8], key: &[u8]) -> bool {
+		let key_prefix = self.canonical_key_prefix(prefix);
+		// Positions take 8 bytes, fork-aware keys also hold a hash.
+		key.len() == key_prefix.len() + 8 && key.starts_with(&key_prefix)
+	}
+}
+
+/// Hash distinguishing the fork-aware keys of the MMR nodes added by blocks of different forks.
+///
+/// `pallet-mmr` currently keys the nodes added by a block by the hash of its parent, the
+/// default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkDiscriminator {
+	/// Hash of the parent of the block that added the node.
+	ParentHash,
+	/// Hash of the block that added the node.
+	BlockHash,
+}
+
+impl Default for ForkDiscriminator {
+	fn default() -> Self {
+		Self::ParentHash
+	}
+}
+
+impl ForkDiscriminator {
+	/// Pick the hash discriminating the fork-aware keys of a block with hash `block_hash` and
+	/// parent `parent_hash`.
+	pub fn select<H>(&self, block_hash: H, parent_hash: H) -> H {
+		match self {
+			Self::ParentHash => parent_hash,
+			Self::BlockHash => block_hash,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		canonical_key_prefix, fork_key, is_canonical_key, max_key_len, CanonicalKeyEncoding,
+		ForkDiscriminator,
+	};
+	use codec::Encode;
+	use sp_core::H256;
+	use sp_mmr_primitives::utils::NodesUtils;
+	use sp_runtime::testing::Header;
+	use std::collections::BTreeMap;
+
+	#[test]
+	fn default_encoding_is_scale() {
+		assert_eq!(
+			CanonicalKeyEncoding::default().canonical_key(b"mmr", 5),
+			(&b"mmr"[..], 5u64).encode()
+		);
+	}
+
+	#[test]
+	fn fixed_width_big_endian_encoding() {
+		let encoding = CanonicalKeyEncoding::FixedWidthBigEndian { separator: b"/".to_vec() };
+
+		assert_eq!(encoding.canonical_key(b"mmr", 5), b"mmr/\0\0\0\0\0\0\0\x05".to_vec());
+		assert_eq!(encoding.canonical_key(b"mmr", 256), b"mmr/\0\0\0\0\0\0\x01\0".to_vec());
+	}
+
+	#[test]
+	fn fork_discriminator_selects_hash() {
+		assert_eq!(ForkDiscriminator::default().select("block", "parent"), "parent");
+		assert_eq!(ForkDiscriminator::BlockHash.select("block", "parent"), "block");
+	}
+
+	#[test]
+	fn canonical_keys_start_with_canonical_key_prefix() {
+		let encodings = [
+			CanonicalKeyEncoding::Scale,
+			CanonicalKeyEncoding::FixedWidthBigEndian { separator: b"/".to_vec() },
+		];
+		// Long enough for the SCALE length of the prefix to take more than one byte.
+		let long_prefix = vec![b'm'; 100];
+
+		for encoding in &encodings {
+			for prefix in [&b"mmr"[..], &long_prefix[..]] {
+				let key_prefix = encoding.canonical_key_prefix(prefix);
+				for pos in [0, 1, 255, 256, u64::MAX] {
+					let key = encoding.canonical_key(prefix, pos);
+					assert!(key.starts_with(&key_prefix), "{:?} at {}", encoding, pos);
+					assert_eq!(key.len(), key_prefix.len() + 8);
+				}
+			}
+		}
+
+		// Keys under another indexing prefix don't match.
+		let key_prefix = CanonicalKeyEncoding::Scale.canonical_key_prefix(b"mmr");
+		assert!(!NodesUtils::node_canon_offchain_key(b"mmr2", 0).starts_with(&key_prefix));
+	}
+
+	#[test]
+	fn prefix_scan_finds_canonical_keys_only() {
+		// Offchain db holding canonical and fork-aware entries, under two indexing prefixes.
+		let mut db = BTreeMap::new();
+		let mut canonical = Vec::new();
+		for pos in [0, 1, 255, 256, u64::MAX] {
+			let key = NodesUtils::node_canon_offchain_key(b"mmr", pos);
+			db.insert(key.clone(), ());
+			canonical.push(key);
+			db.insert(fork_key::<Header>(b"mmr", pos, H256::repeat_byte(7)), ());
+			db.insert(NodesUtils::node_canon_offchain_key(b"mmr2", pos), ());
+			db.insert(fork_key::<Header>(b"mmr2", pos, H256::repeat_byte(7)), ());
+		}
+		canonical.sort();
+
+		let key_prefix = canonical_key_prefix(b"mmr");
+		let scanned: Vec<_> = db
+			.range(key_prefix.clone()..)
+			.map(|(key, _)| key)
+			.take_while(|key| key.starts_with(&key_prefix))
+			.filter(|key| is_canonical_key(b"mmr", key))
+			.cloned()
+			.collect();
+		assert_eq!(scanned, canonical);
+	}
+
+	#[test]
+	fn fork_key_is_scale_encoded_with_fork_hash() {
+		let fork_hash = H256::repeat_byte(7);
+
+		assert_eq!(
+			fork_key::<Header>(b"mmr", 5, fork_hash),
+			(&b"mmr"[..], 5u64, fork_hash).encode()
+		);
+		assert_ne!(
+			fork_key::<Header>(b"mmr", 5, fork_hash),
+			fork_key::<Header>(b"mmr", 5, H256::repeat_byte(8))
+		);
+	}
+
+	#[test]
+	fn max_key_len_covers_both_key_kinds() {
+		// SCALE length of the prefix, prefix, position and hash.
+		assert_eq!(max_key_len::<Header>(b"mmr", &CanonicalKeyEncoding::Scale), 1 + 3 + 8 + 32);
+
+		let separator = vec![b'/'; 64];
+		let encoding = CanonicalKeyEncoding::FixedWidthBigEndian { separator };
+		assert_eq!(max_key_len::<Header>(b"mmr", &encoding), 3 + 64 + 8);
+	}
+}
@@ -0,0 +1,72 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! MMR peak math shared by the gadget features.
+
+use sp_mmr_primitives::{utils::NodesUtils, LeafIndex, NodeIndex};
+
+/// Positions of the peaks of an MMR holding `leaf_count` leaves, from left to right.
+///
+/// Positions are 0-based, same as the node indices used for the offchain keys.
+pub fn peaks_for_leaf_count(leaf_count: LeafIndex) -> Vec<NodeIndex> {
+	let mut peaks = Vec::with_capacity(NodesUtils::new(leaf_count).number_of_peaks() as usize);
+	// Every bit set in `leaf_count` is a perfect subtree, the biggest one being leftmost.
+	let mut subtree_offset = 0;
+	for height in (0..LeafIndex::BITS).rev() {
+		if leaf_count & (1 << height) != 0 {
+			let subtree_size = (1 << (height + 1)) - 1;
+			peaks.push(subtree_offset + subtree_size - 1);
+			subtree_offset += subtree_size;
+		}
+	}
+	peaks
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn peaks_for_leaf_count_works() {
+		assert_eq!(peaks_for_leaf_count(0), Vec::<NodeIndex>::new());
+		// 0
+		assert_eq!(peaks_for_leaf_count(1), vec![0]);
+		//   2
+		//  / \
+		// 0   1   3
+		assert_eq!(peaks_for_leaf_count(3), vec![2, 3]);
+		//       6
+		//     /   \
+		//    2     5     9
+		//   / \   / \   / \
+		//  0   1 3   4 7   8  10
+		assert_eq!(peaks_for_leaf_count(7), vec![6, 9, 10]);
+		assert_eq!(peaks_for_leaf_count(11), vec![14, 17, 18]);
+	}
+
+	#[test]
+	fn peaks_for_leaf_count_matches_mmr_size() {
+		for leaf_count in 1..=64 {
+			let peaks = peaks_for_leaf_count(leaf_count);
+			let nodes = NodesUtils::new(leaf_count);
+			assert_eq!(peaks.len() as NodeIndex, nodes.number_of_peaks());
+			// The rightmost peak is the last node of the MMR.
+			assert_eq!(peaks.last(), Some(&(nodes.size() - 1)));
+		}
+	}
+}
@@ -0,0 +1,58 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Prefix of the offchain db keys of the MMR nodes.
+
+/// Prefix of the offchain db keys under which `pallet-mmr` indexes the MMR nodes.
+///
+/// Must match the `INDEXING_PREFIX` configured for the pallet.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IndexingPrefix(Vec<u8>);
+
+impl IndexingPrefix {
+	/// Raw bytes of the prefix.
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl From<&str> for IndexingPrefix {
+	fn from(prefix: &str) -> Self {
+		Self(prefix.as_bytes().to_vec())
+	}
+}
+
+impl From<Vec<u8>> for IndexingPrefix {
+	fn from(prefix: Vec<u8>) -> Self {
+		Self(prefix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::IndexingPrefix;
+
+	#[test]
+	fn can_be_built_from_str_and_bytes() {
+		let from_str = IndexingPrefix::from("mmr");
+		let from_bytes = IndexingPrefix::from(b"mmr".to_vec());
+
+		assert_eq!(from_str, from_bytes);
+		assert_eq!(from_str.as_bytes(), b"mmr");
+	}
+}
@@ -0,0 +1,285 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Read access to the MMR nodes stored in the offchain db.
+
+use crate::{CanonicalKeyEncoding, Error, IndexingPrefix, LOG_TARGET};
+use codec::{Decode, DecodeAll};
+use log::error;
+use lru::LruCache;
+use sc_offchain::OffchainDb;
+use sp_core::offchain::{DbExternalities, OffchainStorage, StorageKind};
+use sp_mmr_primitives::NodeIndex;
+use std::num::NonZeroUsize;
+
+/// What [`OffchainMmrReader::decode_canonical_node`] does with entries that don't decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnNodeDecodeError {
+	/// Log the entry and read it as absent.
+	Skip,
+	/// Return an [`Error::NodeDecode`].
+	Abort,
+}
+
+impl Default for OnNodeDecodeError {
+	fn default() -> Self {
+		Self::Abort
+	}
+}
+
+/// Reads MMR nodes from the offchain db, optionally caching the most recently read ones.
+///
+/// The cache is disabled by default (see [`OffchainMmrReader::with_cache_size`]). Writes and
+/// deletes performed through the reader keep the cache up to date, but changes made to the
+/// offchain db through other handles aren't seen by cached entries.
+pub struct OffchainMmrReader<S> {
+	offchain_db: OffchainDb<S>,
+	indexing_prefix: IndexingPrefix,
+	canonical_key_encoding: CanonicalKeyEncoding,
+	cache: Option<LruCache<Vec<u8>, Vec<u8>>>,
+	on_decode_error: OnNodeDecodeError,
+}
+
+impl<S: OffchainStorage> OffchainMmrReader<S> {
+	/// Create a reader for the MMR nodes stored under `indexing_prefix`.
+	pub fn new(offchain_db: OffchainDb<S>, indexing_prefix: impl Into<IndexingPrefix>) -> Self {
+		Self {
+			offchain_db,
+			indexing_prefix: indexing_prefix.into(),
+			canonical_key_encoding: Default::default(),
+			cache: None,
+			on_decode_error: Default::default(),
+		}
+	}
+
+	/// Cache up to `size` entries read through this reader. `0` disables the cache.
+	pub fn with_cache_size(mut self, size: usize) -> Self {
+		self.cache = NonZeroUsize::new(size).map(LruCache::new);
+		self
+	}
+
+	/// Look the canonical nodes up under keys built with `encoding`,
+	/// [`CanonicalKeyEncoding::Scale`] by default.
+	///
+	/// Has to match the
+	/// [`MmrGadgetConfig::canonical_key_encoding`](crate::MmrGadgetConfig::canonical_key_encoding)
+	/// of the gadget that wrote the nodes.
+	pub fn with_canonical_key_encoding(mut self, encoding: CanonicalKeyEncoding) -> Self {
+		self.canonical_key_encoding = encoding;
+		self
+	}
+
+	/// Handle the entries that don't decode as set by `policy`, [`OnNodeDecodeError::Abort`] by
+	/// default.
+	pub fn with_on_decode_error(mut self, policy: OnNodeDecodeError) -> Self {
+		self.on_decode_error = policy;
+		self
+	}
+
+	/// Value of the MMR node at `pos`, stored under its canonical key.
+	pub fn canonical_node(&mut self, pos: NodeIndex) -> Option<Vec<u8>> {
+		let key = self.canonical_key(pos);
+		self.get(&key)
+	}
+
+	/// Value of the MMR node at `pos`, stored under its canonical key, decoded as a `T`.
+	///
+	/// The whole entry has to decode. Failures name the position and key of the entry, and are
+	/// handled as set by [`OffchainMmrReader::with_on_decode_error`].
+	pub fn decode_canonical_node<T: Decode>(&mut self, pos: NodeIndex) -> Result<Option<T>, Error> {
+		let key = self.canonical_key(pos);
+		let value = match self.get(&key) {
+			Some(value) => value,
+			None => return Ok(None),
+		};
+		match T::decode_all(&mut &value[..]) {
+			Ok(node) => Ok(Some(node)),
+			Err(error) => {
+				let error = Error::NodeDecode { pos, key, error };
+				match self.on_decode_error {
+					OnNodeDecodeError::Skip => {
+						error!(target: LOG_TARGET, "Skipping undecodable entry: {}", error);
+						Ok(None)
+					},
+					OnNodeDecodeError::Abort => Err(error),
+				}
+			},
+		}
+	}
+
+	fn canonical_key(&self, pos: NodeIndex) -> Vec<u8> {
+		self.canonical_key_encoding.canonical_key(self.indexing_prefix.as_bytes(), pos)
+	}
+
+	/// Value stored under `key`.
+	pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+		let key = key.to_vec();
+		if let Some(value) = self.cache.as_mut().and_then(|cache| cache.get(&key)) {
+			return Some(value.clone())
+		}
+		let value = self.offchain_db.local_storage_get(StorageKind::PERSISTENT, &key)?;
+		if let Some(cache) = self.cache.as_mut() {
+			cache.put(key, value.clone());
+		}
+		Some(value)
+	}
+
+	/// Store `value` under `key`.
+	pub fn set(&mut self, key: &[u8], value: &[u8]) {
+		self.offchain_db.local_storage_set(StorageKind::PERSISTENT, key, value);
+		if let Some(cache) = self.cache.as_mut() {
+			cache.pop(&key.to_vec());
+		}
+	}
+
+	/// Remove the value stored under `key`.
+	pub fn clear(&mut self, key: &[u8]) {
+		self.offchain_db.local_storage_clear(StorageKind::PERSISTENT, key);
+		if let Some(cache) = self.cache.as_mut() {
+			cache.pop(&key.to_vec());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{OffchainMmrReader, OnNodeDecodeError};
+	use crate::{CanonicalKeyEncoding, Error};
+	use codec::Encode;
+	use parking_lot::Mutex;
+	use sc_offchain::OffchainDb;
+	use sp_core::offchain::OffchainStorage;
+	use sp_mmr_primitives::utils::NodesUtils;
+	use std::{collections::HashMap, sync::Arc};
+
+	/// Offchain storage counting the reads.
+	#[derive(Clone, Default)]
+	struct CountingStorage {
+		entries: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+		reads: Arc<Mutex<usize>>,
+	}
+
+	impl OffchainStorage for CountingStorage {
+		fn set(&mut self, prefix: &[u8], key: &[u8], value: &[u8]) {
+			self.entries.lock().insert([prefix, key].concat(), value.to_vec());
+		}
+
+		fn remove(&mut self, prefix: &[u8], key: &[u8]) {
+			self.entries.lock().remove(&[prefix, key].concat());
+		}
+
+		fn get(&self, prefix: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+			*self.reads.lock() += 1;
+			self.entries.lock().get(&[prefix, key].concat()).cloned()
+		}
+
+		fn compare_and_set(
+			&mut self,
+			prefix: &[u8],
+			key: &[u8],
+			old_value: Option<&[u8]>,
+			new_value: &[u8],
+		) -> bool {
+			let mut entries = self.entries.lock();
+			let key = [prefix, key].concat();
+			if entries.get(&key).map(Vec::as_slice) != old_value {
+				return false
+			}
+			entries.insert(key, new_value.to_vec());
+			true
+		}
+	}
+
+	#[test]
+	fn cached_reads_do_not_hit_storage() {
+		let storage = CountingStorage::default();
+		let reads = storage.reads.clone();
+		let mut reader = OffchainMmrReader::new(OffchainDb::new(storage), "mmr").with_cache_size(2);
+
+		reader.set(b"key", b"value");
+		assert_eq!(reader.get(b"key"), Some(b"value".to_vec()));
+		assert_eq!(*reads.lock(), 1);
+		assert_eq!(reader.get(b"key"), Some(b"value".to_vec()));
+		assert_eq!(*reads.lock(), 1);
+
+		// Writes and deletes invalidate the cached entry.
+		reader.set(b"key", b"other");
+		assert_eq!(reader.get(b"key"), Some(b"other".to_vec()));
+		assert_eq!(*reads.lock(), 2);
+		reader.clear(b"key");
+		assert_eq!(reader.get(b"key"), None);
+		assert_eq!(*reads.lock(), 3);
+	}
+
+	#[test]
+	fn cache_is_disabled_by_default() {
+		let storage = CountingStorage::default();
+		let reads = storage.reads.clone();
+		let mut reader = OffchainMmrReader::new(OffchainDb::new(storage), "mmr");
+
+		reader.set(b"key", b"value");
+		reader.get(b"key");
+		reader.get(b"key");
+		assert_eq!(*reads.lock(), 2);
+	}
+
+	#[test]
+	fn undecodable_canonical_nodes_name_their_position() {
+		let storage = CountingStorage::default();
+		let mut reader = OffchainMmrReader::new(OffchainDb::new(storage), "mmr");
+		let key = |pos| NodesUtils::node_canon_offchain_key(b"mmr", pos);
+
+		reader.set(&key(3), &7u64.encode());
+		reader.set(&key(4), &[0xde, 0xad]);
+		assert_eq!(reader.decode_canonical_node::<u64>(3).unwrap(), Some(7));
+		assert_eq!(reader.decode_canonical_node::<u64>(5).unwrap(), None);
+		match reader.decode_canonical_node::<u64>(4) {
+			Err(e @ Error::NodeDecode { pos: 4, .. }) =>
+				assert!(e.to_string().starts_with("Couldn't decode the MMR node at pos 4 (key 0x")),
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		let mut reader = reader.with_on_decode_error(OnNodeDecodeError::Skip);
+		assert_eq!(reader.decode_canonical_node::<u64>(4).unwrap(), None);
+	}
+
+	#[test]
+	fn canonical_nodes_are_read_with_configured_encoding() {
+		let encodings = [
+			CanonicalKeyEncoding::Scale,
+			CanonicalKeyEncoding::FixedWidthBigEndian { separator: b"/".to_vec() },
+		];
+		for encoding in encodings {
+			let storage = CountingStorage::default();
+			let mut reader = OffchainMmrReader::new(OffchainDb::new(storage), "mmr")
+				.with_canonical_key_encoding(encoding.clone());
+
+			reader.set(&encoding.canonical_key(b"mmr", 3), &7u64.encode());
+			assert_eq!(reader.canonical_node(3), Some(7u64.encode()));
+			assert_eq!(reader.decode_canonical_node::<u64>(3).unwrap(), Some(7));
+			assert_eq!(reader.canonical_node(4), None);
+		}
+
+		// Nodes written with another encoding aren't found.
+		let storage = CountingStorage::default();
+		let mut reader = OffchainMmrReader::new(OffchainDb::new(storage), "mmr");
+		let fixed_width = CanonicalKeyEncoding::FixedWidthBigEndian { separator: Vec::new() };
+		reader.set(&fixed_width.canonical_key(b"mmr", 3), &7u64.encode());
+		assert_eq!(reader.canonical_node(3), None);
+	}
+}
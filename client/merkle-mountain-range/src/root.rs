@@ -0,0 +1,105 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! MMR root derived from the canonical offchain entries.
+
+use beefy_primitives::MmrRootHash;
+use codec::{Decode, Input};
+
+/// Computes the MMR root from the offchain entries of the MMR peaks.
+///
+/// Used to cross-check the canonical entries against the root reported by the runtime, see
+/// [`MmrGadgetConfig::root_hasher`](crate::MmrGadgetConfig::root_hasher). It has to match the
+/// hashing and the leaf encoding of the runtime.
+pub trait MmrRootHasher: Send + Sync {
+	/// Root of an MMR whose peaks, from left to right, are stored as `peaks`.
+	///
+	/// Returns `None` if an entry can't be decoded.
+	fn root(&self, peaks: &[Vec<u8>]) -> Option<MmrRootHash>;
+}
+
+/// [`MmrRootHasher`] of `pallet-mmr` hashing with Keccak-256, as used with BEEFY.
+///
+/// Entries are SCALE-encoded `DataOrHash` nodes, the hash of a leaf being the Keccak-256 hash
+/// of its encoding. Peaks are bagged from right to left, each step hashing the concatenation
+/// of the accumulated hash and the next peak.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keccak256RootHasher;
+
+impl Keccak256RootHasher {
+	fn node_hash(mut entry: &[u8]) -> Option<MmrRootHash> {
+		match entry.read_byte().ok()? {
+			0 => Some(sp_core::keccak_256(entry).into()),
+			1 => MmrRootHash::decode(&mut entry).ok().filter(|_| entry.is_empty()),
+			_ => None,
+		}
+	}
+}
+
+impl MmrRootHasher for Keccak256RootHasher {
+	fn root(&self, peaks: &[Vec<u8>]) -> Option<MmrRootHash> {
+		let mut peaks = peaks.iter().rev().map(|peak| Self::node_hash(peak));
+		let mut root = peaks.next()??;
+		for peak in peaks {
+			root = sp_core::keccak_256(&[root.as_bytes(), peak?.as_bytes()].concat()).into();
+		}
+		Some(root)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Keccak256RootHasher, MmrRootHasher};
+	use beefy_primitives::MmrRootHash;
+	use codec::Encode;
+
+	fn hash_entry(hash: MmrRootHash) -> Vec<u8> {
+		[&[1u8][..], hash.as_bytes()].concat()
+	}
+
+	#[test]
+	fn keccak256_root_bags_peaks_from_the_right() {
+		let hasher = Keccak256RootHasher;
+		let (h0, h1) = (MmrRootHash::repeat_byte(1), MmrRootHash::repeat_byte(2));
+		let leaf = [&[0u8][..], &b"leaf".encode()[..]].concat();
+		let leaf_hash = MmrRootHash::from(sp_core::keccak_256(&b"leaf".encode()));
+
+		assert_eq!(hasher.root(&[]), None);
+		assert_eq!(hasher.root(&[hash_entry(h0)]), Some(h0));
+		assert_eq!(hasher.root(&[leaf.clone()]), Some(leaf_hash));
+
+		let right_then_left = |left: MmrRootHash, right: MmrRootHash| -> MmrRootHash {
+			sp_core::keccak_256(&[right.as_bytes(), left.as_bytes()].concat()).into()
+		};
+		assert_eq!(
+			hasher.root(&[hash_entry(h0), hash_entry(h1), leaf]),
+			Some(right_then_left(h0, right_then_left(h1, leaf_hash)))
+		);
+	}
+
+	#[test]
+	fn keccak256_root_rejects_undecodable_entries() {
+		let hasher = Keccak256RootHasher;
+		let h0 = MmrRootHash::repeat_byte(1);
+
+		assert_eq!(hasher.root(&[vec![]]), None);
+		assert_eq!(hasher.root(&[vec![2, 0]]), None);
+		assert_eq!(hasher.root(&[hash_entry(h0)[..16].to_vec()]), None);
+		assert_eq!(hasher.root(&[hash_entry(h0), vec![1, 0]]), None);
+	}
+}
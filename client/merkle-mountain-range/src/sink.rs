@@ -0,0 +1,113 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Destinations of the canonicalized MMR entries.
+
+use crate::Error;
+use sc_offchain::OffchainDb;
+use sp_core::offchain::{DbExternalities, OffchainStorage, StorageKind};
+
+/// Key-value store receiving the MMR entries moved under canonical keys by
+/// [`OffchainMmr`](crate::OffchainMmr).
+///
+/// The node offchain db is the default sink. Other implementations allow feeding the canonical
+/// MMR data to an external store, either instead of the offchain db or, using a pair of sinks,
+/// in addition to it.
+pub trait CanonicalSink {
+	/// Store `value` under `key`, overwriting any previous value.
+	fn write(&mut self, key: &[u8], value: &[u8]);
+
+	/// Remove the value stored under `key`, if any.
+	fn delete(&mut self, key: &[u8]);
+
+	/// Value stored under `key`, if any.
+	///
+	/// Used to check the canonical entries against the runtime MMR root, see
+	/// [`MmrGadgetConfig::root_hasher`](crate::MmrGadgetConfig::root_hasher). By default, the
+	/// sink can't be read from and that check is skipped.
+	fn read(&mut self, _key: &[u8]) -> Option<Vec<u8>> {
+		None
+	}
+
+	/// Start a batch holding the operations for a single block.
+	///
+	/// Sinks backed by a transactional store should buffer the following operations until
+	/// [`CanonicalSink::commit_block`], so that a crash can't leave a block half-canonicalized.
+	/// By default, operations are applied one by one.
+	fn begin_block(&mut self) {}
+
+	/// Atomically apply the operations buffered since [`CanonicalSink::begin_block`].
+	///
+	/// On failure, the fork-aware entries of the block are kept and the block is canonicalized
+	/// again later, see
+	/// [`MmrGadgetConfig::max_canonicalize_retries`](crate::MmrGadgetConfig::max_canonicalize_retries).
+	fn commit_block(&mut self) -> Result<(), Error> {
+		Ok(())
+	}
+}
+
+impl<S: OffchainStorage> CanonicalSink for OffchainDb<S> {
+	fn write(&mut self, key: &[u8], value: &[u8]) {
+		self.local_storage_set(StorageKind::PERSISTENT, key, value);
+	}
+
+	fn delete(&mut self, key: &[u8]) {
+		self.local_storage_clear(StorageKind::PERSISTENT, key);
+	}
+
+	fn read(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+		self.local_storage_get(StorageKind::PERSISTENT, key)
+	}
+}
+
+/// Forward every operation to both sinks.
+///
+/// The two sinks aren't committed atomically. Both are committed even if the first one fails,
+/// and a failure of either fails the whole block, which is later canonicalized again: the sink
+/// that succeeded then receives the same entries a second time. Reads are served by the first
+/// sink, falling back to the second one.
+impl<A: CanonicalSink, B: CanonicalSink> CanonicalSink for (A, B) {
+	fn write(&mut self, key: &[u8], value: &[u8]) {
+		self.0.write(key, value);
+		self.1.write(key, value);
+	}
+
+	fn delete(&mut self, key: &[u8]) {
+		self.0.delete(key);
+		self.1.delete(key);
+	}
+
+	fn read(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+		self.0.read(key).or_else(|| self.1.read(key))
+	}
+
+	fn begin_block(&mut self) {
+		self.0.begin_block();
+		self.1.begin_block();
+	}
+
+	fn commit_block(&mut self) -> Result<(), Error> {
+		match (self.0.commit_block(), self.1.commit_block()) {
+			(Ok(()), Ok(())) => Ok(()),
+			(Err(e), Ok(())) => Err(Error::CanonicalSink(format!("first sink: {}", e))),
+			(Ok(()), Err(e)) => Err(Error::CanonicalSink(format!("second sink: {}", e))),
+			(Err(e0), Err(e1)) =>
+				Err(Error::CanonicalSink(format!("first sink: {}; second sink: {}", e0, e1))),
+		}
+	}
+}
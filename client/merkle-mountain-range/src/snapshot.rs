@@ -0,0 +1,126 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Portable file format of the canonical MMR nodes, see
+//! [`OffchainMmr::export_canonical`](crate::OffchainMmr::export_canonical).
+//!
+//! A snapshot starts with [`MAGIC`] and a version byte, followed by one record per node: its
+//! position as 8 little-endian bytes, the length of its bytes as 4 little-endian bytes, then
+//! the bytes themselves. Positions don't depend on the canonical key encoding, so a snapshot
+//! can be imported by a node using another encoding.
+
+use sp_mmr_primitives::NodeIndex;
+use std::io::{self, Read, Write};
+
+/// Bytes every snapshot starts with.
+pub(crate) const MAGIC: &[u8; 8] = b"MMRCANON";
+
+/// Version of the snapshots written by this version of the gadget.
+pub(crate) const VERSION: u8 = 1;
+
+pub(crate) fn write_header(writer: &mut impl Write) -> io::Result<()> {
+	writer.write_all(MAGIC)?;
+	writer.write_all(&[VERSION])
+}
+
+/// Read the header of a snapshot, failing if it isn't a snapshot or uses an unknown version.
+pub(crate) fn read_header(reader: &mut impl Read) -> io::Result<()> {
+	let mut header = [0; MAGIC.len() + 1];
+	reader.read_exact(&mut header)?;
+	if &header[..MAGIC.len()] != MAGIC {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "not a canonical MMR snapshot"))
+	}
+	let version = header[MAGIC.len()];
+	if version != VERSION {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("unsupported snapshot version {}, expected {}", version, VERSION),
+		))
+	}
+	Ok(())
+}
+
+pub(crate) fn write_node(writer: &mut impl Write, pos: NodeIndex, node: &[u8]) -> io::Result<()> {
+	let len = u32::try_from(node.len())
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MMR node too large"))?;
+	writer.write_all(&pos.to_le_bytes())?;
+	writer.write_all(&len.to_le_bytes())?;
+	writer.write_all(node)
+}
+
+/// Read the next node of a snapshot, `None` at the end of the snapshot.
+pub(crate) fn read_node(reader: &mut impl Read) -> io::Result<Option<(NodeIndex, Vec<u8>)>> {
+	let mut pos = [0; 8];
+	if reader.read(&mut pos[..1])? == 0 {
+		return Ok(None)
+	}
+	reader.read_exact(&mut pos[1..])?;
+	let mut len = [0; 4];
+	reader.read_exact(&mut len)?;
+	let len = u32::from_le_bytes(len);
+	// Not allocating `len` bytes upfront, as the length may be corrupted.
+	let mut node = Vec::new();
+	reader.take(len.into()).read_to_end(&mut node)?;
+	if node.len() as u64 != u64::from(len) {
+		return Err(io::ErrorKind::UnexpectedEof.into())
+	}
+	Ok(Some((NodeIndex::from_le_bytes(pos), node)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{read_header, read_node, write_header, write_node, MAGIC};
+	use std::io::ErrorKind;
+
+	#[test]
+	fn nodes_round_trip() {
+		let mut snapshot = Vec::new();
+		write_header(&mut snapshot).unwrap();
+		write_node(&mut snapshot, 0, b"leaf").unwrap();
+		write_node(&mut snapshot, u64::MAX, b"").unwrap();
+
+		let mut reader = &snapshot[..];
+		read_header(&mut reader).unwrap();
+		assert_eq!(read_node(&mut reader).unwrap(), Some((0, b"leaf".to_vec())));
+		assert_eq!(read_node(&mut reader).unwrap(), Some((u64::MAX, Vec::new())));
+		assert_eq!(read_node(&mut reader).unwrap(), None);
+	}
+
+	#[test]
+	fn invalid_snapshots_are_rejected() {
+		let unknown_version = [&MAGIC[..], &[2u8][..]].concat();
+		assert_eq!(
+			read_header(&mut &unknown_version[..]).unwrap_err().kind(),
+			ErrorKind::InvalidData
+		);
+		assert_eq!(
+			read_header(&mut &b"MMRCANOX\x01"[..]).unwrap_err().kind(),
+			ErrorKind::InvalidData
+		);
+		assert_eq!(read_header(&mut &MAGIC[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+		let mut truncated = Vec::new();
+		write_node(&mut truncated, 3, b"node").unwrap();
+		for len in 1..truncated.len() {
+			assert_eq!(
+				read_node(&mut &truncated[..len]).unwrap_err().kind(),
+				ErrorKind::UnexpectedEof
+			);
+		}
+	}
+}
@@ -0,0 +1,173 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Shared handle exposing the state of a running MMR gadget.
+
+use crate::clock::{Clock, SystemClock};
+use parking_lot::RwLock;
+use sp_runtime::{
+	traits::{Block, NumberFor, Zero},
+	Saturating,
+};
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+struct StatusInner<B: Block> {
+	first_mmr_block: Option<NumberFor<B>>,
+	best_canonicalized: Option<NumberFor<B>>,
+	last_progress: Instant,
+	last_received: NumberFor<B>,
+	last_processed: NumberFor<B>,
+	finality_stream_open: bool,
+	offchain_indexing_missing: bool,
+}
+
+/// Cheaply cloneable handle to the state of a running [`MmrGadget`](crate::MmrGadget).
+///
+/// The gadget updates the handle as it makes progress; any clone of it can be used to read
+/// that state from outside of the gadget task.
+pub struct MmrGadgetStatus<B: Block> {
+	inner: Arc<RwLock<StatusInner<B>>>,
+	clock: Arc<dyn Clock>,
+	/// Last canonicalized block, watched by [`MmrGadgetStatus::wait_for_canonicalized`].
+	canonicalized: Arc<watch::Sender<Option<NumberFor<B>>>>,
+}
+
+impl<B: Block> Clone for MmrGadgetStatus<B> {
+	fn clone(&self) -> Self {
+		Self {
+			inner: self.inner.clone(),
+			clock: self.clock.clone(),
+			canonicalized: self.canonicalized.clone(),
+		}
+	}
+}
+
+impl<B: Block> Default for MmrGadgetStatus<B> {
+	fn default() -> Self {
+		Self::with_clock(Arc::new(SystemClock))
+	}
+}
+
+impl<B: Block> MmrGadgetStatus<B> {
+	/// Create a new status handle measuring time with `clock`.
+	pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+		let inner = StatusInner {
+			first_mmr_block: None,
+			best_canonicalized: None,
+			last_progress: clock.now(),
+			last_received: Zero::zero(),
+			last_processed: Zero::zero(),
+			finality_stream_open: true,
+			offchain_indexing_missing: false,
+		};
+		let (canonicalized, _) = watch::channel(None);
+		Self { inner: Arc::new(RwLock::new(inner)), clock, canonicalized: Arc::new(canonicalized) }
+	}
+
+	/// Block number where `pallet-mmr` was added to the runtime.
+	///
+	/// `None` until the gadget detects the pallet.
+	pub fn first_mmr_block(&self) -> Option<NumberFor<B>> {
+		self.inner.read().first_mmr_block
+	}
+
+	/// Number of the last block whose MMR nodes were canonicalized.
+	pub fn best_canonicalized(&self) -> Option<NumberFor<B>> {
+		self.inner.read().best_canonicalized
+	}
+
+	/// Whether the gadget hasn't canonicalized any block for longer than `threshold`.
+	///
+	/// Time is measured from the last canonicalized block, or from the creation of the handle
+	/// if nothing was canonicalized yet.
+	pub fn is_stalled(&self, threshold: Duration) -> bool {
+		let last_progress = self.inner.read().last_progress;
+		self.clock.now().saturating_duration_since(last_progress) > threshold
+	}
+
+	/// Number of finalized blocks the gadget has been notified about but hasn't processed yet.
+	///
+	/// This is the distance between the most recently received finality notification and the
+	/// most recently processed one.
+	pub fn processing_lag(&self) -> NumberFor<B> {
+		let inner = self.inner.read();
+		inner.last_received.saturating_sub(inner.last_processed)
+	}
+
+	/// Wait until block `block_num` has been canonicalized, i.e. until
+	/// [`MmrGadgetStatus::best_canonicalized`] reaches it.
+	pub async fn wait_for_canonicalized(&self, block_num: NumberFor<B>) {
+		let mut canonicalized = self.canonicalized.subscribe();
+		loop {
+			let reached = canonicalized.borrow().map_or(false, |best| best >= block_num);
+			if reached {
+				return
+			}
+			// `self` holds the sender, so the channel can't be closed.
+			let _ = canonicalized.changed().await;
+		}
+	}
+
+	/// Whether the finality notification stream followed by the gadget is still open.
+	///
+	/// Once `false`, the gadget has stopped for good and has to be restarted to make progress.
+	pub fn is_finality_stream_open(&self) -> bool {
+		self.inner.read().finality_stream_open
+	}
+
+	/// Whether the MMR nodes of finalized blocks were missing from the offchain db when the
+	/// gadget started, which means the node most likely runs with the offchain indexing API
+	/// disabled.
+	///
+	/// In that case, the gadget has nothing to canonicalize.
+	pub fn is_offchain_indexing_missing(&self) -> bool {
+		self.inner.read().offchain_indexing_missing
+	}
+
+	pub(crate) fn record_offchain_indexing_missing(&self) {
+		self.inner.write().offchain_indexing_missing = true;
+	}
+
+	pub(crate) fn record_finality_stream_closed(&self) {
+		self.inner.write().finality_stream_open = false;
+	}
+
+	pub(crate) fn record_notification_received(&self, block_num: NumberFor<B>) {
+		self.inner.write().last_received = block_num;
+	}
+
+	pub(crate) fn record_notification_processed(&self, block_num: NumberFor<B>) {
+		self.inner.write().last_processed = block_num;
+	}
+
+	pub(crate) fn set_first_mmr_block(&self, first_mmr_block: NumberFor<B>) {
+		self.inner.write().first_mmr_block = Some(first_mmr_block);
+	}
+
+	pub(crate) fn record_canonicalized(&self, block_num: NumberFor<B>) {
+		let now = self.clock.now();
+		let mut inner = self.inner.write();
+		inner.best_canonicalized = Some(block_num);
+		inner.last_progress = now;
+		self.canonicalized.send_replace(Some(block_num));
+	}
+}
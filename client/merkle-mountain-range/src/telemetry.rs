@@ -0,0 +1,72 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2022 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Reporting of the MMR gadget progress to the node telemetry.
+
+#[cfg(feature = "telemetry")]
+use sc_telemetry::{telemetry, TelemetryHandle, CONSENSUS_INFO};
+#[cfg(feature = "telemetry")]
+use std::fmt;
+
+/// Progress of the MMR gadget reported to the telemetry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TelemetryEvent<N> {
+	/// The blocks finalized before the gadget started were canonicalized, see
+	/// [`OffchainMmr::is_caught_up`](crate::OffchainMmr::is_caught_up).
+	CaughtUp {
+		/// Number of blocks canonicalized while catching up.
+		blocks: N,
+		/// Number of the last canonicalized block.
+		best_canonicalized: N,
+	},
+	/// Periodic report of the last canonicalized block.
+	Canonicalized {
+		/// Number of the last canonicalized block.
+		best_canonicalized: N,
+	},
+}
+
+/// Destination of the [`TelemetryEvent`]s of the MMR gadget.
+///
+/// Implemented for [`sc_telemetry::TelemetryHandle`] when the `telemetry` feature is enabled.
+pub trait MmrTelemetry<N>: Send {
+	/// Report `event`. Must not block.
+	fn report(&self, event: TelemetryEvent<N>);
+}
+
+#[cfg(feature = "telemetry")]
+impl<N: fmt::Debug> MmrTelemetry<N> for TelemetryHandle {
+	fn report(&self, event: TelemetryEvent<N>) {
+		let telemetry = Some(self);
+		match event {
+			TelemetryEvent::CaughtUp { blocks, best_canonicalized } => telemetry!(
+				telemetry;
+				CONSENSUS_INFO;
+				"mmr.caught_up";
+				"blocks" => ?blocks,
+				"best_canonicalized" => ?best_canonicalized,
+			),
+			TelemetryEvent::Canonicalized { best_canonicalized } => telemetry!(
+				telemetry;
+				CONSENSUS_INFO;
+				"mmr.canonicalized";
+				"best_canonicalized" => ?best_canonicalized,
+			),
+		}
+	}
+}
@@ -16,7 +16,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::MmrGadget;
+use crate::{clock::Clock, offchain_mmr::OffchainMmr, MmrGadget, MmrGadgetConfig, MmrGadgetStatus};
 use parking_lot::Mutex;
 use sc_block_builder::BlockBuilderProvider;
 use sc_client_api::{
@@ -37,7 +37,11 @@ use sp_runtime::{
 	generic::BlockId,
 	traits::{Block as BlockT, Header as HeaderT},
 };
-use std::{future::Future, sync::Arc, time::Duration};
+use std::{
+	future::Future,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 use substrate_test_runtime_client::{
 	runtime::{Block, BlockNumber, Hash, Header},
 	Backend, BlockBuilderExt, Client, ClientBlockImportExt, ClientExt, DefaultTestClientBuilderExt,
@@ -47,8 +51,38 @@ use tokio::runtime::Runtime;
 
 type MmrHash = H256;
 
+/// [`Clock`] that only moves forward when told to.
+pub(crate) struct MockClock {
+	now: Mutex<Instant>,
+}
+
+impl Default for MockClock {
+	fn default() -> Self {
+		Self { now: Mutex::new(Instant::now()) }
+	}
+}
+
+impl MockClock {
+	pub(crate) fn advance(&self, duration: Duration) {
+		*self.now.lock() += duration;
+	}
+}
+
+impl Clock for MockClock {
+	fn now(&self) -> Instant {
+		*self.now.lock()
+	}
+}
+
 pub(crate) struct MockRuntimeApiData {
 	pub(crate) num_blocks: BlockNumber,
+	/// Whether `mmr_leaf_count` calls wait, to observe the gadget in the middle of processing a
+	/// finality notification.
+	pub(crate) stalled: bool,
+	/// Number of times `mmr_leaf_count` was called.
+	pub(crate) leaf_count_calls: usize,
+	/// Root returned by `mmr_root`, `None` reporting the pallet as missing.
+	pub(crate) mmr_root: Option<MmrHash>,
 }
 
 #[derive(Clone)]
@@ -108,7 +142,12 @@ impl MockClient {
 		MockClient {
 			client: Mutex::new(client),
 			backend,
-			runtime_api_params: Arc::new(Mutex::new(MockRuntimeApiData { num_blocks: 0 })),
+			runtime_api_params: Arc::new(Mutex::new(MockRuntimeApiData {
+				num_blocks: 0,
+				stalled: false,
+				leaf_count_calls: 0,
+				mmr_root: None,
+			})),
 		}
 	}
 
@@ -116,6 +155,22 @@ impl MockClient {
 		OffchainDb::new(self.backend.offchain_storage().unwrap())
 	}
 
+	pub(crate) fn offchain_mmr(
+		self: &Arc<Self>,
+		first_mmr_block: BlockNumber,
+		config: MmrGadgetConfig<Block>,
+	) -> OffchainMmr<Block, Backend, MockClient> {
+		OffchainMmr::new(
+			self.backend.clone(),
+			self.clone(),
+			self.offchain_db(),
+			MockRuntimeApi::INDEXING_PREFIX.to_vec(),
+			first_mmr_block,
+			config,
+		)
+		.unwrap()
+	}
+
 	pub async fn import_block(
 		&self,
 		at: &BlockId<Block>,
@@ -153,6 +208,11 @@ impl MockClient {
 		MmrBlock { block, leaf_idx: maybe_leaf_idx, leaf_data: parent_hash.as_ref().to_vec() }
 	}
 
+	/// Make the runtime API calls wait until called again with `false`.
+	pub(crate) fn stall_runtime_api(&self, stalled: bool) {
+		self.runtime_api_params.lock().stalled = stalled;
+	}
+
 	pub fn finalize_block(&self, hash: Hash, maybe_num_mmr_blocks: Option<BlockNumber>) {
 		let client = self.client.lock();
 		if let Some(num_mmr_blocks) = maybe_num_mmr_blocks {
@@ -162,6 +222,10 @@ impl MockClient {
 		client.finalize_block(hash, None).unwrap();
 	}
 
+	pub fn mmr_leaf_count_calls(&self) -> usize {
+		self.runtime_api_params.lock().leaf_count_calls
+	}
+
 	pub fn undo_block_canonicalization(&self, mmr_block: &MmrBlock) {
 		let mut offchain_db = self.offchain_db();
 		for node in NodesUtils::right_branch_ending_in_leaf(mmr_block.leaf_idx.unwrap()) {
@@ -287,12 +351,17 @@ impl ProvideRuntimeApi<Block> for MockClient {
 
 sp_api::mock_impl_runtime_apis! {
 	impl mmr::MmrApi<Block, MmrHash, BlockNumber> for MockRuntimeApi {
-		fn mmr_root() -> Result<MmrHash, mmr::Error> {
-			Err(mmr::Error::PalletNotIncluded)
+		fn mmr_root(&self) -> Result<MmrHash, mmr::Error> {
+			self.data.lock().mmr_root.ok_or(mmr::Error::PalletNotIncluded)
 		}
 
 		fn mmr_leaf_count(&self) -> Result<LeafIndex, mmr::Error> {
-			Ok(self.data.lock().num_blocks)
+			while self.data.lock().stalled {
+				std::thread::sleep(Duration::from_millis(10));
+			}
+			let mut data = self.data.lock();
+			data.leaf_count_calls += 1;
+			Ok(data.num_blocks)
 		}
 
 		fn generate_proof(
@@ -319,6 +388,26 @@ sp_api::mock_impl_runtime_apis! {
 	}
 }
 
+/// Wait until the gadget reporting to `status` has canonicalized block `block_num`, and has
+/// finished processing the finality notification that canonicalized it.
+///
+/// Panics if that takes more than a few seconds.
+pub(crate) async fn wait_for_canonicalized(
+	status: &MmrGadgetStatus<Block>,
+	block_num: BlockNumber,
+) {
+	let processed = async {
+		status.wait_for_canonicalized(block_num).await;
+		// The stale forks are only pruned once the finalized blocks are canonicalized.
+		while status.processing_lag() > 0 {
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+	};
+	tokio::time::timeout(Duration::from_secs(10), processed)
+		.await
+		.expect("the gadget canonicalizes the finalized blocks")
+}
+
 pub(crate) fn run_test_with_mmr_gadget<F, Fut>(post_gadget: F)
 where
 	F: FnOnce(Arc<MockClient>) -> Fut + 'static,
@@ -327,6 +416,35 @@ where
 	run_test_with_mmr_gadget_pre_post(|_| async {}, post_gadget);
 }
 
+pub(crate) fn run_test_with_mmr_gadget_and_status<F, Fut>(
+	status: MmrGadgetStatus<Block>,
+	post_gadget: F,
+) where
+	F: FnOnce(Arc<MockClient>) -> Fut + 'static,
+	Fut: Future<Output = ()>,
+{
+	run_test_with_mmr_gadget_and_config(
+		MmrGadgetConfig { status, ..Default::default() },
+		post_gadget,
+	)
+}
+
+pub(crate) fn run_test_with_mmr_gadget_and_config<F, Fut>(
+	config: MmrGadgetConfig<Block>,
+	post_gadget: F,
+) where
+	F: FnOnce(Arc<MockClient>) -> Fut + 'static,
+	Fut: Future<Output = ()>,
+{
+	let client = Arc::new(MockClient::new());
+	run_test_with_mmr_gadget_pre_post_using_client_and_config(
+		client,
+		config,
+		|_| async {},
+		post_gadget,
+	);
+}
+
 pub(crate) fn run_test_with_mmr_gadget_pre_post<F, G, RetF, RetG>(pre_gadget: F, post_gadget: G)
 where
 	F: FnOnce(Arc<MockClient>) -> RetF + 'static,
@@ -338,6 +456,24 @@ where
 	run_test_with_mmr_gadget_pre_post_using_client(client, pre_gadget, post_gadget)
 }
 
+pub(crate) fn run_test_with_mmr_gadget_pre_post_and_status<F, G, RetF, RetG>(
+	status: MmrGadgetStatus<Block>,
+	pre_gadget: F,
+	post_gadget: G,
+) where
+	F: FnOnce(Arc<MockClient>) -> RetF + 'static,
+	G: FnOnce(Arc<MockClient>) -> RetG + 'static,
+	RetF: Future<Output = ()>,
+	RetG: Future<Output = ()>,
+{
+	run_test_with_mmr_gadget_pre_post_using_client_and_config(
+		Arc::new(MockClient::new()),
+		MmrGadgetConfig { status, ..Default::default() },
+		pre_gadget,
+		post_gadget,
+	)
+}
+
 pub(crate) fn run_test_with_mmr_gadget_pre_post_using_client<F, G, RetF, RetG>(
 	client: Arc<MockClient>,
 	pre_gadget: F,
@@ -347,6 +483,25 @@ pub(crate) fn run_test_with_mmr_gadget_pre_post_using_client<F, G, RetF, RetG>(
 	G: FnOnce(Arc<MockClient>) -> RetG + 'static,
 	RetF: Future<Output = ()>,
 	RetG: Future<Output = ()>,
+{
+	run_test_with_mmr_gadget_pre_post_using_client_and_config(
+		client,
+		Default::default(),
+		pre_gadget,
+		post_gadget,
+	)
+}
+
+pub(crate) fn run_test_with_mmr_gadget_pre_post_using_client_and_config<F, G, RetF, RetG>(
+	client: Arc<MockClient>,
+	config: MmrGadgetConfig<Block>,
+	pre_gadget: F,
+	post_gadget: G,
+) where
+	F: FnOnce(Arc<MockClient>) -> RetF + 'static,
+	G: FnOnce(Arc<MockClient>) -> RetG + 'static,
+	RetF: Future<Output = ()>,
+	RetG: Future<Output = ()>,
 {
 	let client_clone = client.clone();
 	let runtime = Runtime::new().unwrap();
@@ -355,7 +510,13 @@ pub(crate) fn run_test_with_mmr_gadget_pre_post_using_client<F, G, RetF, RetG>(
 	let client_clone = client.clone();
 	runtime.spawn(async move {
 		let backend = client_clone.backend.clone();
-		MmrGadget::start(client_clone, backend, MockRuntimeApi::INDEXING_PREFIX.to_vec()).await
+		MmrGadget::start_with_config(
+			client_clone,
+			backend,
+			MockRuntimeApi::INDEXING_PREFIX.to_vec(),
+			config,
+		)
+		.await
 	});
 
 	runtime.block_on(async move {
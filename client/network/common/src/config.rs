@@ -22,7 +22,12 @@ use crate::protocol;
 
 use codec::Encode;
 use libp2p::{multiaddr, Multiaddr, PeerId};
-use std::{fmt, str, str::FromStr};
+use log::warn;
+use std::{
+	collections::{BTreeSet, HashSet},
+	fmt, str,
+	str::FromStr,
+};
 
 /// Protocol name prefix, transmitted on the wire for legacy protocol names.
 /// I.e., `dot` in `/dot/sync/2`. Should be unique for each chain. Always UTF-8.
@@ -36,6 +41,27 @@ impl<'a> From<&'a str> for ProtocolId {
 	}
 }
 
+impl ProtocolId {
+	/// Raw bytes of the protocol ID, as transmitted on the wire.
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0[..]
+	}
+
+	/// The protocol ID as a string.
+	///
+	/// Fails if the protocol ID isn't valid UTF-8, which can't happen as long as it was built
+	/// through [`From<&str>`](#impl-From%3C%26str%3E-for-ProtocolId). Unlike
+	/// [`AsRef<str>`](#impl-AsRef%3Cstr%3E-for-ProtocolId), this keeps working if a constructor
+	/// taking raw bytes is ever added.
+	pub fn as_str(&self) -> Result<&str, str::Utf8Error> {
+		str::from_utf8(&self.0[..])
+	}
+}
+
+/// # Panics
+///
+/// Panics if the protocol ID isn't valid UTF-8, which can't happen when it was built through
+/// `From<&str>`, the only constructor. See [`ProtocolId::as_str`] for a fallible variant.
 impl AsRef<str> for ProtocolId {
 	fn as_ref(&self) -> &str {
 		str::from_utf8(&self.0[..])
@@ -52,6 +78,9 @@ impl fmt::Debug for ProtocolId {
 /// Parses a string address and splits it into Multiaddress and PeerId, if
 /// valid.
 ///
+/// Leading and trailing whitespace is ignored. Blank input is rejected with
+/// [`ParseErr::EmptyInput`].
+///
 /// # Example
 ///
 /// ```
@@ -64,10 +93,57 @@ impl fmt::Debug for ProtocolId {
 /// assert_eq!(addr, "/ip4/198.51.100.19/tcp/30333".parse::<Multiaddr>().unwrap());
 /// ```
 pub fn parse_str_addr(addr_str: &str) -> Result<(PeerId, Multiaddr), ParseErr> {
+	let addr_str = addr_str.trim();
+	if addr_str.is_empty() {
+		return Err(ParseErr::EmptyInput)
+	}
 	let addr: Multiaddr = addr_str.parse()?;
 	parse_addr(addr)
 }
 
+/// Same as [`parse_str_addr`], but rejects inputs longer than `max_len` bytes with
+/// [`ParseErr::TooLong`] before parsing them.
+///
+/// Meant for addresses coming from untrusted sources, such as other peers.
+pub fn parse_str_addr_bounded(
+	addr_str: &str,
+	max_len: usize,
+) -> Result<(PeerId, Multiaddr), ParseErr> {
+	if addr_str.len() > max_len {
+		return Err(ParseErr::TooLong)
+	}
+	parse_str_addr(addr_str)
+}
+
+/// Same as [`parse_str_addr`], but also rejects addresses that can't be dialed with `transport`.
+///
+/// [`TransportConfig::MemoryOnly`] only supports `/memory/...` addresses, which are in turn not
+/// supported by [`TransportConfig::Normal`]. Addresses using a protocol of the wrong kind are
+/// rejected with [`ParseErr::UnsupportedTransport`].
+pub fn parse_str_addr_for_transport(
+	addr_str: &str,
+	transport: &TransportConfig,
+) -> Result<(PeerId, Multiaddr), ParseErr> {
+	let (peer_id, addr) = parse_str_addr(addr_str)?;
+	let is_memory =
+		|protocol: multiaddr::Protocol| matches!(protocol, multiaddr::Protocol::Memory(_));
+	let supported = match transport {
+		TransportConfig::MemoryOnly { .. } => addr.iter().all(is_memory),
+		TransportConfig::Normal { .. } => !addr.iter().any(is_memory),
+	};
+
+	if supported {
+		Ok((peer_id, addr))
+	} else {
+		Err(ParseErr::UnsupportedTransport(addr))
+	}
+}
+
+/// Returns `true` if `addr` routes through a relay, i.e. contains a `/p2p-circuit` component.
+pub fn is_relay_addr(addr: &Multiaddr) -> bool {
+	addr.iter().any(|protocol| matches!(protocol, multiaddr::Protocol::P2pCircuit))
+}
+
 /// Splits a Multiaddress into a Multiaddress and PeerId.
 pub fn parse_addr(mut addr: Multiaddr) -> Result<(PeerId, Multiaddr), ParseErr> {
 	let who = match addr.pop() {
@@ -79,6 +155,24 @@ pub fn parse_addr(mut addr: Multiaddr) -> Result<(PeerId, Multiaddr), ParseErr>
 	Ok((who, addr))
 }
 
+/// Same as [`parse_addr`], but also returns `addr` itself, with the `/p2p` component.
+///
+/// Returns the peer ID, the address without `/p2p` and the full address, in that order.
+pub fn parse_addr_full(addr: Multiaddr) -> Result<(PeerId, Multiaddr, Multiaddr), ParseErr> {
+	let (who, stripped) = parse_addr(addr.clone())?;
+	Ok((who, stripped, addr))
+}
+
+/// Returns the peer ID in the trailing `/p2p` component of `addr`, without modifying `addr`.
+///
+/// Returns `None` if the last component isn't `/p2p` or holds an invalid peer ID.
+pub fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+	match addr.iter().last() {
+		Some(multiaddr::Protocol::P2p(key)) => PeerId::from_multihash(key).ok(),
+		_ => None,
+	}
+}
+
 /// Address of a node, including its identity.
 ///
 /// This struct represents a decoded version of a multiaddress that ends with `/p2p/<peerid>`.
@@ -93,7 +187,7 @@ pub fn parse_addr(mut addr: Multiaddr) -> Result<(PeerId, Multiaddr), ParseErr>
 /// assert_eq!(addr.peer_id.to_base58(), "QmSk5HQbn6LhUwDiNMseVUjuRYhEtYj4aUZ6WfWoGURpdV");
 /// assert_eq!(addr.multiaddr.to_string(), "/ip4/198.51.100.19/tcp/30333");
 /// ```
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(try_from = "String", into = "String")]
 pub struct MultiaddrWithPeerId {
 	/// Address of the node.
@@ -103,11 +197,43 @@ pub struct MultiaddrWithPeerId {
 }
 
 impl MultiaddrWithPeerId {
+	/// Builds a [`MultiaddrWithPeerId`] out of an address and the identity of the node.
+	///
+	/// If `multiaddr` already ends with `/p2p/<peer_id>`, that component is stripped, so that
+	/// the result is identical to the one obtained by parsing [`MultiaddrWithPeerId::concat`].
+	pub fn from_components(mut multiaddr: Multiaddr, peer_id: PeerId) -> Self {
+		if let Some(multiaddr::Protocol::P2p(key)) = multiaddr.iter().last() {
+			if PeerId::from_multihash(key).map_or(false, |who| who == peer_id) {
+				multiaddr.pop();
+			}
+		}
+
+		Self { multiaddr, peer_id }
+	}
+
+	/// Address of the node, without the `/p2p/<peer_id>` suffix.
+	pub fn multiaddr(&self) -> &Multiaddr {
+		&self.multiaddr
+	}
+
+	/// Identity of the node.
+	pub fn peer_id(&self) -> PeerId {
+		self.peer_id
+	}
+
 	/// Concatenates the multiaddress and peer ID into one multiaddress containing both.
+	///
+	/// Parsing the result (or its string representation) back yields a [`MultiaddrWithPeerId`]
+	/// equal to `self`.
 	pub fn concat(&self) -> Multiaddr {
 		let proto = multiaddr::Protocol::P2p(From::from(self.peer_id));
 		self.multiaddr.clone().with(proto)
 	}
+
+	/// Returns `true` if `other` refers to the same node, whatever their addresses.
+	pub fn same_peer(&self, other: &Self) -> bool {
+		self.peer_id == other.peer_id
+	}
 }
 
 impl fmt::Display for MultiaddrWithPeerId {
@@ -138,6 +264,126 @@ impl TryFrom<String> for MultiaddrWithPeerId {
 	}
 }
 
+/// Entry of a reserved nodes list, with or without a known address.
+///
+/// Anything convertible into a [`ReservedPeer`] can be passed to [`SetConfig::add_reserved`] and
+/// [`NonDefaultSetConfig::add_reserved`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReservedPeer {
+	/// Node reachable at a known address.
+	Address(MultiaddrWithPeerId),
+	/// Node whose address isn't known. Its addresses have to be found through the discovery
+	/// mechanisms (DHT, mDNS) before it can be connected to.
+	PeerId(PeerId),
+}
+
+impl ReservedPeer {
+	/// Identity of the node.
+	pub fn peer_id(&self) -> PeerId {
+		match self {
+			Self::Address(addr) => addr.peer_id,
+			Self::PeerId(peer_id) => *peer_id,
+		}
+	}
+}
+
+impl From<MultiaddrWithPeerId> for ReservedPeer {
+	fn from(addr: MultiaddrWithPeerId) -> Self {
+		Self::Address(addr)
+	}
+}
+
+impl From<PeerId> for ReservedPeer {
+	fn from(peer_id: PeerId) -> Self {
+		Self::PeerId(peer_id)
+	}
+}
+
+/// Encodes a list of nodes into the string representation used for bootnodes in chain specs and
+/// on the command line.
+pub fn encode_bootnodes(nodes: &[MultiaddrWithPeerId]) -> Vec<String> {
+	nodes.iter().map(ToString::to_string).collect()
+}
+
+/// Decodes a list of bootnodes encoded with [`encode_bootnodes`].
+///
+/// On failure, returns the index of the first entry that couldn't be parsed along with the error.
+pub fn decode_bootnodes(strs: &[String]) -> Result<Vec<MultiaddrWithPeerId>, (usize, ParseErr)> {
+	strs.iter()
+		.enumerate()
+		.map(|(index, s)| s.parse().map_err(|err| (index, err)))
+		.collect()
+}
+
+/// Compares two lists of reserved nodes, e.g. before and after a configuration reload.
+///
+/// Returns the nodes of `new` missing from `old`, and the nodes of `old` missing from `new`,
+/// in that order. Both are sorted and free of duplicates.
+pub fn diff_reserved(
+	old: &[MultiaddrWithPeerId],
+	new: &[MultiaddrWithPeerId],
+) -> (Vec<MultiaddrWithPeerId>, Vec<MultiaddrWithPeerId>) {
+	let old = old.iter().collect::<BTreeSet<_>>();
+	let new = new.iter().collect::<BTreeSet<_>>();
+	let added = new.difference(&old).map(|&node| node.clone()).collect();
+	let removed = old.difference(&new).map(|&node| node.clone()).collect();
+	(added, removed)
+}
+
+/// Parses a JSON array of node addresses, such as a reserved nodes list managed in a file.
+///
+/// Unlike [`decode_bootnodes`], every entry is checked, and all the invalid ones are reported
+/// along with their index in the array.
+pub fn parse_nodes_json(json: &str) -> Result<Vec<MultiaddrWithPeerId>, NodesJsonError> {
+	let strs: Vec<String> = serde_json::from_str(json).map_err(NodesJsonError::Json)?;
+	let mut nodes = Vec::with_capacity(strs.len());
+	let mut invalid = Vec::new();
+	for (index, s) in strs.iter().enumerate() {
+		match s.parse() {
+			Ok(node) => nodes.push(node),
+			Err(err) => invalid.push((index, err)),
+		}
+	}
+	if invalid.is_empty() {
+		Ok(nodes)
+	} else {
+		Err(NodesJsonError::InvalidEntries(invalid))
+	}
+}
+
+/// Error that can be generated by [`parse_nodes_json`].
+#[derive(Debug)]
+pub enum NodesJsonError {
+	/// The input isn't a JSON array of strings.
+	Json(serde_json::Error),
+	/// Entries that aren't valid addresses, with their index in the array.
+	InvalidEntries(Vec<(usize, ParseErr)>),
+}
+
+impl fmt::Display for NodesJsonError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Json(err) => write!(f, "Invalid list of addresses: {}", err),
+			Self::InvalidEntries(entries) => {
+				write!(f, "Invalid addresses:")?;
+				for (index, err) in entries {
+					write!(f, " #{}: {};", index, err)?;
+				}
+				Ok(())
+			},
+		}
+	}
+}
+
+impl std::error::Error for NodesJsonError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Json(err) => Some(err),
+			Self::InvalidEntries(_) => None,
+		}
+	}
+}
+
 /// Error that can be generated by `parse_str_addr`.
 #[derive(Debug)]
 pub enum ParseErr {
@@ -147,6 +393,12 @@ pub enum ParseErr {
 	InvalidPeerId,
 	/// The peer ID is missing from the address.
 	PeerIdMissing,
+	/// The address is empty or only made of whitespace.
+	EmptyInput,
+	/// The address can't be used with the configured transport.
+	UnsupportedTransport(Multiaddr),
+	/// The address is longer than the allowed maximum.
+	TooLong,
 }
 
 impl fmt::Display for ParseErr {
@@ -155,6 +407,10 @@ impl fmt::Display for ParseErr {
 			Self::MultiaddrParse(err) => write!(f, "{}", err),
 			Self::InvalidPeerId => write!(f, "Peer id at the end of the address is invalid"),
 			Self::PeerIdMissing => write!(f, "Peer id is missing from the address"),
+			Self::EmptyInput => write!(f, "Address is empty"),
+			Self::UnsupportedTransport(addr) =>
+				write!(f, "Address {} isn't supported by the configured transport", addr),
+			Self::TooLong => write!(f, "Address is too long"),
 		}
 	}
 }
@@ -165,25 +421,112 @@ impl std::error::Error for ParseErr {
 			Self::MultiaddrParse(err) => Some(err),
 			Self::InvalidPeerId => None,
 			Self::PeerIdMissing => None,
+			Self::EmptyInput => None,
+			Self::UnsupportedTransport(_) => None,
+			Self::TooLong => None,
 		}
 	}
 }
 
+impl ParseErr {
+	/// Kind of the error, without the details it may hold.
+	pub fn kind(&self) -> ParseErrKind {
+		match self {
+			Self::MultiaddrParse(_) => ParseErrKind::MultiaddrParse,
+			Self::InvalidPeerId => ParseErrKind::InvalidPeerId,
+			Self::PeerIdMissing => ParseErrKind::PeerIdMissing,
+			Self::EmptyInput => ParseErrKind::EmptyInput,
+			Self::UnsupportedTransport(_) => ParseErrKind::UnsupportedTransport,
+			Self::TooLong => ParseErrKind::TooLong,
+		}
+	}
+}
+
+/// Kind of a [`ParseErr`], see [`ParseErr::kind`].
+///
+/// Unlike [`ParseErr`], it can be compared, e.g. in tests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrKind {
+	/// See [`ParseErr::MultiaddrParse`].
+	MultiaddrParse,
+	/// See [`ParseErr::InvalidPeerId`].
+	InvalidPeerId,
+	/// See [`ParseErr::PeerIdMissing`].
+	PeerIdMissing,
+	/// See [`ParseErr::EmptyInput`].
+	EmptyInput,
+	/// See [`ParseErr::UnsupportedTransport`].
+	UnsupportedTransport,
+	/// See [`ParseErr::TooLong`].
+	TooLong,
+}
+
 impl From<multiaddr::Error> for ParseErr {
 	fn from(err: multiaddr::Error) -> ParseErr {
 		Self::MultiaddrParse(err)
 	}
 }
 
+/// Number of peers that [`PeerLimit::capacity_hint`] sizes caches and buffers for when the
+/// number of peers isn't limited.
+pub const UNLIMITED_PEERS_CAPACITY_HINT: u32 = 128;
+
+/// Limit on the number of peers of a set in one direction, see [`SetConfig::in_peers`] and
+/// [`SetConfig::out_peers`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerLimit {
+	/// At most this number of peers.
+	Limited(u32),
+	/// Any number of peers.
+	Unlimited,
+}
+
+impl PeerLimit {
+	/// Number of slots to allocate to the peers.
+	///
+	/// [`PeerLimit::Unlimited`] is `u32::MAX` slots, which the peerset never runs out of. Use
+	/// [`PeerLimit::capacity_hint`] to size anything allocated per peer.
+	pub fn slots(&self) -> u32 {
+		match self {
+			Self::Limited(slots) => *slots,
+			Self::Unlimited => u32::MAX,
+		}
+	}
+
+	/// Number of peers to size per-peer caches and buffers for.
+	///
+	/// [`PeerLimit::Unlimited`] is [`UNLIMITED_PEERS_CAPACITY_HINT`] peers.
+	pub fn capacity_hint(&self) -> u32 {
+		match self {
+			Self::Limited(slots) => *slots,
+			Self::Unlimited => UNLIMITED_PEERS_CAPACITY_HINT,
+		}
+	}
+
+	/// Whether no peer is allowed.
+	pub fn is_zero(&self) -> bool {
+		*self == Self::Limited(0)
+	}
+}
+
+impl From<u32> for PeerLimit {
+	fn from(slots: u32) -> Self {
+		Self::Limited(slots)
+	}
+}
+
 /// Configuration for a set of nodes.
 #[derive(Clone, Debug)]
 pub struct SetConfig {
 	/// Maximum allowed number of incoming substreams related to this set.
-	pub in_peers: u32,
+	pub in_peers: PeerLimit,
 	/// Number of outgoing substreams related to this set that we're trying to maintain.
-	pub out_peers: u32,
+	pub out_peers: PeerLimit,
 	/// List of reserved node addresses.
 	pub reserved_nodes: Vec<MultiaddrWithPeerId>,
+	/// Reserved nodes whose address isn't known. They can only be connected to once the
+	/// discovery mechanisms (DHT, mDNS) found one of their addresses.
+	pub reserved_peer_ids: Vec<PeerId>,
 	/// Whether nodes that aren't in [`SetConfig::reserved_nodes`] are accepted or automatically
 	/// refused.
 	pub non_reserved_mode: NonReservedPeerMode,
@@ -192,14 +535,85 @@ pub struct SetConfig {
 impl Default for SetConfig {
 	fn default() -> Self {
 		Self {
-			in_peers: 25,
-			out_peers: 75,
+			in_peers: PeerLimit::Limited(25),
+			out_peers: PeerLimit::Limited(75),
 			reserved_nodes: Vec::new(),
+			reserved_peer_ids: Vec::new(),
 			non_reserved_mode: NonReservedPeerMode::Accept,
 		}
 	}
 }
 
+impl SetConfig {
+	/// Multiply [`SetConfig::in_peers`] and [`SetConfig::out_peers`] by `factor`.
+	///
+	/// Results are rounded down, and saturate at `0` and `u32::MAX`. A negative or NaN `factor`
+	/// removes all the limited slots, while [`PeerLimit::Unlimited`] is kept as is.
+	pub fn scale_slots(&mut self, factor: f32) {
+		let scale = |limit| match limit {
+			PeerLimit::Limited(slots) =>
+				PeerLimit::Limited((f64::from(slots) * f64::from(factor)).floor() as u32),
+			PeerLimit::Unlimited => PeerLimit::Unlimited,
+		};
+		self.in_peers = scale(self.in_peers);
+		self.out_peers = scale(self.out_peers);
+	}
+
+	/// Add a node to the list of reserved nodes.
+	///
+	/// Nodes whose address isn't known, see [`ReservedPeer::PeerId`], are added to
+	/// [`SetConfig::reserved_peer_ids`].
+	pub fn add_reserved(&mut self, peer: impl Into<ReservedPeer>) {
+		match peer.into() {
+			ReservedPeer::Address(addr) => self.reserved_nodes.push(addr),
+			ReservedPeer::PeerId(peer_id) => self.reserved_peer_ids.push(peer_id),
+		}
+	}
+
+	/// Identities of all the reserved nodes, with or without a known address.
+	pub fn reserved_peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+		self.reserved_nodes
+			.iter()
+			.map(|node| node.peer_id)
+			.chain(self.reserved_peer_ids.iter().copied())
+	}
+
+	/// Copy of [`SetConfig::reserved_nodes`], sorted and without duplicates.
+	///
+	/// Unlike the list itself, the result doesn't depend on the order in which the nodes were
+	/// added, which makes it suitable for hashing or logging the configuration.
+	pub fn sorted_unique_reserved(&self) -> Vec<MultiaddrWithPeerId> {
+		let mut reserved = self.reserved_nodes.clone();
+		reserved.sort();
+		reserved.dedup();
+		reserved
+	}
+
+	/// Addresses to dial the reserved nodes at, ending with their `/p2p` component.
+	///
+	/// Duplicates are removed, and the addresses are in the order of
+	/// [`SetConfig::sorted_unique_reserved`]. Nodes without a known address, see
+	/// [`SetConfig::reserved_peer_ids`], can't be dialed and are left out.
+	pub fn dial_addrs(&self) -> Vec<Multiaddr> {
+		self.sorted_unique_reserved().iter().map(MultiaddrWithPeerId::concat).collect()
+	}
+
+	/// Whether only reserved nodes can be part of this set, i.e. non-reserved nodes are denied
+	/// and no slots are allocated to them.
+	pub fn is_reserved_only(&self) -> bool {
+		self.non_reserved_mode == NonReservedPeerMode::Deny &&
+			self.in_peers.is_zero() &&
+			self.out_peers.is_zero()
+	}
+
+	/// Whether non-reserved nodes can be part of this set, i.e. they are accepted and at least
+	/// one slot, inbound or outbound, is allocated to them.
+	pub fn would_accept_non_reserved(&self) -> bool {
+		self.non_reserved_mode == NonReservedPeerMode::Accept &&
+			!(self.in_peers.is_zero() && self.out_peers.is_zero())
+	}
+}
+
 /// Custom handshake for the notification protocol
 #[derive(Debug, Clone)]
 pub struct NotificationHandshake(Vec<u8>);
@@ -214,6 +628,65 @@ impl NotificationHandshake {
 	pub fn from_bytes(bytes: Vec<u8>) -> Self {
 		Self(bytes)
 	}
+
+	/// Create new versioned `NotificationHandshake`, made of `version` followed by the encoded
+	/// `handshake`.
+	///
+	/// Nothing in the bytes tells a versioned handshake apart from one built with
+	/// [`NotificationHandshake::new`]; both peers must agree that the protocol uses versioned
+	/// handshakes.
+	pub fn new_versioned<H: Encode>(version: u8, handshake: H) -> Self {
+		Self((version, handshake).encode())
+	}
+
+	/// Version of a handshake built with [`NotificationHandshake::new_versioned`].
+	///
+	/// Returns `None` if the handshake is empty.
+	pub fn version(&self) -> Option<u8> {
+		self.0.first().copied()
+	}
+
+	/// Payload of a handshake built with [`NotificationHandshake::new_versioned`], i.e. the
+	/// bytes following the version.
+	pub fn payload(&self) -> &[u8] {
+		self.0.get(1..).unwrap_or_default()
+	}
+
+	/// Whether `other` is the exact same handshake.
+	pub fn matches(&self, other: &[u8]) -> bool {
+		self.0 == other
+	}
+
+	/// Index of the first byte differing between this handshake and `other`, meant for
+	/// debugging handshake mismatches.
+	///
+	/// If one handshake is a prefix of the other, this is the length of the shorter one. Returns
+	/// `None` if both handshakes are equal.
+	pub fn diff(&self, other: &[u8]) -> Option<usize> {
+		if self.matches(other) {
+			return None
+		}
+		let common = self.0.iter().zip(other).take_while(|(a, b)| a == b).count();
+		Some(common)
+	}
+
+	/// Check that the handshake is at least `len` bytes long, e.g. for a handshake received from
+	/// a peer before decoding it.
+	pub fn expect_min_len(&self, len: usize) -> Result<(), HandshakeError> {
+		if self.0.len() < len {
+			return Err(HandshakeError::TooShort { min: len, actual: self.0.len() })
+		}
+		Ok(())
+	}
+
+	/// Check that the handshake is exactly `len` bytes long, for protocols with a fixed-size
+	/// handshake.
+	pub fn expect_exact_len(&self, len: usize) -> Result<(), HandshakeError> {
+		if self.0.len() != len {
+			return Err(HandshakeError::WrongLength { expected: len, actual: self.0.len() })
+		}
+		Ok(())
+	}
 }
 
 impl std::ops::Deref for NotificationHandshake {
@@ -224,6 +697,38 @@ impl std::ops::Deref for NotificationHandshake {
 	}
 }
 
+/// Error returned by the [`NotificationHandshake`] length checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+	/// See [`NotificationHandshake::expect_min_len`].
+	TooShort {
+		/// Minimum length of the handshake.
+		min: usize,
+		/// Length of the handshake.
+		actual: usize,
+	},
+	/// See [`NotificationHandshake::expect_exact_len`].
+	WrongLength {
+		/// Expected length of the handshake.
+		expected: usize,
+		/// Length of the handshake.
+		actual: usize,
+	},
+}
+
+impl fmt::Display for HandshakeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::TooShort { min, actual } =>
+				write!(f, "Handshake too short: {} bytes, expected at least {}", actual, min),
+			Self::WrongLength { expected, actual } =>
+				write!(f, "Handshake of {} bytes, expected {}", actual, expected),
+		}
+	}
+}
+
+impl std::error::Error for HandshakeError {}
+
 /// Extension to [`SetConfig`] for sets that aren't the default set.
 ///
 /// > **Note**: As new fields might be added in the future, please consider using the `new` method
@@ -264,32 +769,202 @@ impl NonDefaultSetConfig {
 			fallback_names: Vec::new(),
 			handshake: None,
 			set_config: SetConfig {
-				in_peers: 0,
-				out_peers: 0,
+				in_peers: PeerLimit::Limited(0),
+				out_peers: PeerLimit::Limited(0),
 				reserved_nodes: Vec::new(),
+				reserved_peer_ids: Vec::new(),
 				non_reserved_mode: NonReservedPeerMode::Deny,
 			},
 		}
 	}
 
+	/// Name of the main protocol of the set, see
+	/// [`NonDefaultSetConfig::notifications_protocol`].
+	pub fn protocol_name_str(&self) -> &str {
+		&self.notifications_protocol
+	}
+
 	/// Modifies the configuration to allow non-reserved nodes.
 	pub fn allow_non_reserved(&mut self, in_peers: u32, out_peers: u32) {
-		self.set_config.in_peers = in_peers;
-		self.set_config.out_peers = out_peers;
+		self.set_config.in_peers = PeerLimit::Limited(in_peers);
+		self.set_config.out_peers = PeerLimit::Limited(out_peers);
 		self.set_config.non_reserved_mode = NonReservedPeerMode::Accept;
 	}
 
+	/// Modifies the configuration to deny non-reserved nodes, keeping the slot counts.
+	pub fn deny_non_reserved(&mut self) {
+		self.set_config.non_reserved_mode = NonReservedPeerMode::Deny;
+	}
+
+	/// Sets the number of ingoing and outgoing slots, keeping the non-reserved mode.
+	pub fn set_peers(&mut self, in_peers: u32, out_peers: u32) {
+		self.set_config.in_peers = PeerLimit::Limited(in_peers);
+		self.set_config.out_peers = PeerLimit::Limited(out_peers);
+	}
+
+	/// Whether the set only accepts reserved nodes, which is the case after
+	/// [`NonDefaultSetConfig::new`].
+	///
+	/// See [`SetConfig::is_reserved_only`].
+	pub fn is_reserved_only(&self) -> bool {
+		self.set_config.is_reserved_only()
+	}
+
 	/// Add a node to the list of reserved nodes.
-	pub fn add_reserved(&mut self, peer: MultiaddrWithPeerId) {
-		self.set_config.reserved_nodes.push(peer);
+	///
+	/// See [`SetConfig::add_reserved`].
+	pub fn add_reserved(&mut self, peer: impl Into<ReservedPeer>) {
+		self.set_config.add_reserved(peer);
 	}
 
 	/// Add a list of protocol names used for backward compatibility.
 	///
+	/// Names that are already registered as fallbacks, or that are equal to
+	/// [`NonDefaultSetConfig::notifications_protocol`], are skipped.
+	///
 	/// See the explanations in [`NonDefaultSetConfig::fallback_names`].
 	pub fn add_fallback_names(&mut self, fallback_names: Vec<protocol::ProtocolName>) {
-		self.fallback_names.extend(fallback_names);
+		for name in fallback_names {
+			self.add_fallback_name(name);
+		}
+	}
+
+	fn add_fallback_name(&mut self, name: protocol::ProtocolName) {
+		if name == self.notifications_protocol {
+			warn!(
+				target: "sub-libp2p",
+				"Ignoring fallback name {} equal to the main protocol name.",
+				name,
+			);
+		} else if self.fallback_names.contains(&name) {
+			warn!(
+				target: "sub-libp2p",
+				"Ignoring duplicate fallback name {} for protocol {}.",
+				name,
+				self.notifications_protocol,
+			);
+		} else {
+			self.fallback_names.push(name);
+		}
+	}
+
+	/// Names of the protocols of this set, in negotiation order: the main protocol name first,
+	/// then the fallback names.
+	pub fn protocol_names(&self) -> impl Iterator<Item = &protocol::ProtocolName> {
+		std::iter::once(&self.notifications_protocol).chain(self.fallback_names.iter())
 	}
+
+	/// Builder-style variant of [`NonDefaultSetConfig::add_fallback_names`].
+	pub fn with_fallbacks<I>(mut self, names: I) -> Self
+	where
+		I: IntoIterator<Item = protocol::ProtocolName>,
+	{
+		for name in names {
+			self.add_fallback_name(name);
+		}
+		self
+	}
+
+	/// Check that every fallback name is the main protocol under another naming scheme.
+	///
+	/// Fallback names usually only differ from [`NonDefaultSetConfig::notifications_protocol`]
+	/// by their first path segment, e.g. `/<genesis_hash>/sync/2` and `/<protocol_id>/sync/2`.
+	/// Returns the fallback names whose remaining path differs from the main protocol's.
+	///
+	/// The check is advisory: it is up to the caller to warn about or reject the configuration.
+	pub fn validate_fallbacks(&self) -> Result<(), Vec<protocol::ProtocolName>> {
+		let suffix = protocol_name_suffix(&self.notifications_protocol);
+		let mismatched = self
+			.fallback_names
+			.iter()
+			.filter(|name| protocol_name_suffix(name) != suffix)
+			.cloned()
+			.collect::<Vec<_>>();
+
+		if mismatched.is_empty() {
+			Ok(())
+		} else {
+			Err(mismatched)
+		}
+	}
+}
+
+/// Names of the notifications protocols of the `sets` listing `peer` among their reserved nodes.
+///
+/// Useful to tell why a peer is connected.
+pub fn sets_reserving_peer<'a>(
+	sets: &'a [NonDefaultSetConfig],
+	peer: &PeerId,
+) -> Vec<&'a protocol::ProtocolName> {
+	sets.iter()
+		.filter(|set| set.set_config.reserved_peers().any(|reserved| reserved == *peer))
+		.map(|set| &set.notifications_protocol)
+		.collect()
+}
+
+/// Check that no two of the `sets` share the same [`NonDefaultSetConfig::notifications_protocol`].
+///
+/// Returns the names claimed by more than one set otherwise, each reported once, in the order
+/// in which the collisions appear. Fallback names aren't checked.
+pub fn check_protocol_name_uniqueness(
+	sets: &[NonDefaultSetConfig],
+) -> Result<(), Vec<protocol::ProtocolName>> {
+	let mut seen = HashSet::new();
+	let mut colliding = Vec::new();
+	for set in sets {
+		let name = &set.notifications_protocol;
+		if !seen.insert(name) && !colliding.contains(name) {
+			colliding.push(name.clone());
+		}
+	}
+	if colliding.is_empty() {
+		Ok(())
+	} else {
+		Err(colliding)
+	}
+}
+
+/// Total number of slots, inbound and outbound, of the `default` set and the other `sets`.
+///
+/// Meant to be compared against a node-wide connection cap. [`PeerLimit::Unlimited`] counts as
+/// [`PeerLimit::slots`], and the sum saturates at `u64::MAX`.
+pub fn total_slots(sets: &[NonDefaultSetConfig], default: &SetConfig) -> u64 {
+	std::iter::once(default)
+		.chain(sets.iter().map(|set| &set.set_config))
+		.map(|set| u64::from(set.in_peers.slots()) + u64::from(set.out_peers.slots()))
+		.fold(0, u64::saturating_add)
+}
+
+/// Components of a protocol name shaped as `/<chain>/<protocol>/<version>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolNameComponents<'a> {
+	/// First segment, identifying the chain: either the legacy [`ProtocolId`] or the genesis
+	/// hash.
+	pub chain: &'a str,
+	/// Segments between the chain and the version, e.g. `sync` or `<fork_id>/sync`.
+	pub protocol: &'a str,
+	/// Last segment.
+	pub version: &'a str,
+}
+
+/// Splits `name`, e.g. `/dot/sync/2`, into its [`ProtocolNameComponents`].
+///
+/// Returns `None` if `name` doesn't start with `/`, has less than three segments, or has an
+/// empty segment.
+pub fn protocol_name_components(name: &str) -> Option<ProtocolNameComponents<'_>> {
+	let path = name.strip_prefix('/')?;
+	let (chain, rest) = path.split_once('/')?;
+	let (protocol, version) = rest.rsplit_once('/')?;
+	if chain.is_empty() || version.is_empty() || protocol.split('/').any(str::is_empty) {
+		return None
+	}
+	Some(ProtocolNameComponents { chain, protocol, version })
+}
+
+/// Path of a protocol name without its first segment, e.g. `/sync/2` for `/dot/sync/2`.
+fn protocol_name_suffix(name: &str) -> &str {
+	let name = name.strip_prefix('/').unwrap_or(name);
+	name.find('/').map_or("", |idx| &name[idx..])
 }
 
 /// Configuration for the transport layer.
@@ -309,7 +984,39 @@ pub enum TransportConfig {
 
 	/// Only allow connections within the same process.
 	/// Only addresses of the form `/memory/...` will be supported.
-	MemoryOnly,
+	MemoryOnly {
+		/// Memory address to listen on, in addition to
+		/// `::sc_network::config::NetworkConfiguration::listen_addresses`.
+		///
+		/// Lets tests pin a deterministic `/memory/...` address. `None` leaves the addresses
+		/// entirely up to `listen_addresses`.
+		listen_addr: Option<Multiaddr>,
+	},
+}
+
+impl TransportConfig {
+	/// Check that the transport configuration is self-consistent.
+	///
+	/// Fails if the `listen_addr` of [`TransportConfig::MemoryOnly`] isn't a `/memory/...`
+	/// address.
+	pub fn validate(&self) -> Result<(), crate::error::Error> {
+		match self {
+			Self::MemoryOnly { listen_addr: Some(addr) } => {
+				let mut protocols = addr.iter();
+				let is_memory = matches!(protocols.next(), Some(multiaddr::Protocol::Memory(_))) &&
+					protocols.next().is_none();
+				if is_memory {
+					Ok(())
+				} else {
+					Err(crate::error::Error::AddressesForAnotherTransport {
+						transport: self.clone(),
+						addresses: vec![addr.clone()],
+					})
+				}
+			},
+			Self::MemoryOnly { listen_addr: None } | Self::Normal { .. } => Ok(()),
+		}
+	}
 }
 
 /// The policy for connections to non-reserved peers.
@@ -330,4 +1037,746 @@ impl NonReservedPeerMode {
 			_ => None,
 		}
 	}
+
+	/// Build the peer mode from a flag telling whether non-reserved nodes are accepted.
+	pub fn from_accept_flag(accept: bool) -> Self {
+		if accept {
+			Self::Accept
+		} else {
+			Self::Deny
+		}
+	}
+
+	/// Whether non-reserved nodes are accepted.
+	pub fn as_accept_flag(&self) -> bool {
+		matches!(self, Self::Accept)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use codec::Decode;
+
+	const PEER_ID: &str = "QmSk5HQbn6LhUwDiNMseVUjuRYhEtYj4aUZ6WfWoGURpdV";
+
+	fn representative_addrs() -> Vec<Multiaddr> {
+		[
+			"/ip4/198.51.100.19/tcp/30333",
+			"/ip6/2001:db8::1/tcp/30333/ws",
+			"/dns/bootnode.example.com/tcp/443/wss",
+			"/memory/1234",
+			"/ip4/127.0.0.1/tcp/30333/p2p/QmSk5HQbn6LhUwDiNMseVUjuRYhEtYj4aUZ6WfWoGURpdV/p2p-circuit",
+		]
+		.iter()
+		.map(|addr| addr.parse().unwrap())
+		.collect()
+	}
+
+	#[test]
+	fn multiaddr_with_peer_id_string_round_trip_is_stable() {
+		let peer_ids = vec![PEER_ID.parse::<PeerId>().unwrap(), PeerId::random()];
+
+		for multiaddr in representative_addrs() {
+			for peer_id in &peer_ids {
+				let addr = MultiaddrWithPeerId::from_components(multiaddr.clone(), *peer_id);
+
+				let string = addr.to_string();
+				assert_eq!(string.parse::<MultiaddrWithPeerId>().unwrap(), addr);
+				assert_eq!(
+					MultiaddrWithPeerId::try_from(String::from(addr.clone())).unwrap(),
+					addr
+				);
+				assert_eq!(addr.concat().to_string(), string);
+				assert_eq!(addr.multiaddr(), &multiaddr);
+				assert_eq!(addr.peer_id(), *peer_id);
+			}
+		}
+	}
+
+	#[test]
+	fn from_components_strips_trailing_peer_id() {
+		let peer_id = PEER_ID.parse::<PeerId>().unwrap();
+		let multiaddr: Multiaddr = "/ip4/198.51.100.19/tcp/30333".parse().unwrap();
+
+		let addr = MultiaddrWithPeerId::from_components(
+			multiaddr.clone().with(multiaddr::Protocol::P2p(peer_id.into())),
+			peer_id,
+		);
+		assert_eq!(addr.multiaddr(), &multiaddr);
+		assert_eq!(addr.to_string(), format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID));
+
+		// A trailing peer ID that belongs to another node is part of the address.
+		let relay = PeerId::random();
+		let relayed = multiaddr.with(multiaddr::Protocol::P2p(relay.into()));
+		let addr = MultiaddrWithPeerId::from_components(relayed.clone(), peer_id);
+		assert_eq!(addr.multiaddr(), &relayed);
+		assert_eq!(addr.to_string().parse::<MultiaddrWithPeerId>().unwrap(), addr);
+	}
+
+	#[test]
+	fn versioned_handshake_round_trip() {
+		let payload = (42u32, b"genesis".to_vec());
+		let handshake = NotificationHandshake::new_versioned(3, payload.clone());
+
+		assert_eq!(handshake.version(), Some(3));
+		assert_eq!(handshake.payload(), &payload.encode()[..]);
+		assert_eq!(<(u32, Vec<u8>)>::decode(&mut handshake.payload()).unwrap(), payload);
+
+		let decoded = NotificationHandshake::from_bytes(handshake.to_vec());
+		assert_eq!(decoded.version(), Some(3));
+		assert_eq!(decoded.payload(), handshake.payload());
+	}
+
+	#[test]
+	fn versioned_handshake_accessors_handle_short_input() {
+		let empty = NotificationHandshake::from_bytes(Vec::new());
+		assert_eq!(empty.version(), None);
+		assert!(empty.payload().is_empty());
+
+		let version_only = NotificationHandshake::new_versioned(1, ());
+		assert_eq!(version_only.version(), Some(1));
+		assert!(version_only.payload().is_empty());
+	}
+
+	#[test]
+	fn handshake_length_checks() {
+		let handshake = NotificationHandshake::from_bytes(vec![0; 4]);
+
+		assert_eq!(handshake.expect_min_len(3), Ok(()));
+		assert_eq!(handshake.expect_min_len(4), Ok(()));
+		assert_eq!(
+			handshake.expect_min_len(5),
+			Err(HandshakeError::TooShort { min: 5, actual: 4 })
+		);
+
+		assert_eq!(
+			handshake.expect_exact_len(3),
+			Err(HandshakeError::WrongLength { expected: 3, actual: 4 })
+		);
+		assert_eq!(handshake.expect_exact_len(4), Ok(()));
+		assert_eq!(
+			handshake.expect_exact_len(5),
+			Err(HandshakeError::WrongLength { expected: 5, actual: 4 })
+		);
+	}
+
+	#[test]
+	fn bootnodes_encode_decode_round_trip() {
+		let nodes = representative_addrs()
+			.into_iter()
+			.map(|multiaddr| MultiaddrWithPeerId::from_components(multiaddr, PeerId::random()))
+			.collect::<Vec<_>>();
+
+		let encoded = encode_bootnodes(&nodes);
+		assert_eq!(encoded.len(), nodes.len());
+		assert_eq!(decode_bootnodes(&encoded).unwrap(), nodes);
+	}
+
+	#[test]
+	fn decode_bootnodes_reports_failing_entry() {
+		let mut encoded = encode_bootnodes(&[MultiaddrWithPeerId::from_components(
+			"/ip4/198.51.100.19/tcp/30333".parse().unwrap(),
+			PeerId::random(),
+		)]);
+		encoded.push("/ip4/198.51.100.19/tcp/30333".into());
+
+		match decode_bootnodes(&encoded) {
+			Err((1, ParseErr::PeerIdMissing)) => {},
+			other => panic!("Unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn blank_addresses_are_rejected() {
+		for input in ["", "   ", "\t\n"] {
+			assert!(matches!(parse_str_addr(input), Err(ParseErr::EmptyInput)));
+			assert!(matches!(input.parse::<MultiaddrWithPeerId>(), Err(ParseErr::EmptyInput)));
+		}
+		assert_eq!(ParseErr::EmptyInput.to_string(), "Address is empty");
+
+		let addr = format!(" /ip4/198.51.100.19/tcp/30333/p2p/{} ", PEER_ID);
+		assert!(parse_str_addr(&addr).is_ok());
+	}
+
+	#[test]
+	fn protocol_id_as_bytes() {
+		let protocol_id = ProtocolId::from("dot");
+		assert_eq!(protocol_id.as_bytes(), "dot".as_bytes());
+		assert_eq!(protocol_id.as_bytes(), protocol_id.as_ref().as_bytes());
+
+		let protocol_id = ProtocolId::from("a-longer-protocol-id");
+		assert_eq!(protocol_id.as_bytes(), b"a-longer-protocol-id");
+	}
+
+	#[test]
+	fn with_fallbacks_appends_names_in_order() {
+		let config = NonDefaultSetConfig::new("/chain/proto/3".into(), 1024).with_fallbacks([
+			protocol::ProtocolName::from("/chain/proto/2"),
+			protocol::ProtocolName::from("/chain/proto/1"),
+			protocol::ProtocolName::from(String::from("/legacy/proto/1")),
+		]);
+
+		assert_eq!(
+			config.fallback_names,
+			vec![
+				protocol::ProtocolName::from("/chain/proto/2"),
+				protocol::ProtocolName::from("/chain/proto/1"),
+				protocol::ProtocolName::from("/legacy/proto/1"),
+			]
+		);
+		assert_eq!(&*config.notifications_protocol, "/chain/proto/3");
+	}
+
+	#[test]
+	fn memory_only_listen_addr_is_validated() {
+		let memory_addr: Multiaddr = "/memory/1234".parse().unwrap();
+		assert!(TransportConfig::MemoryOnly { listen_addr: Some(memory_addr) }
+			.validate()
+			.is_ok());
+		assert!(TransportConfig::MemoryOnly { listen_addr: None }.validate().is_ok());
+
+		let invalid_addrs = vec![
+			"/ip4/127.0.0.1/tcp/30333".parse().unwrap(),
+			"/memory/1234/tcp/30333".parse().unwrap(),
+			Multiaddr::empty(),
+		];
+		for addr in invalid_addrs {
+			assert!(matches!(
+				TransportConfig::MemoryOnly { listen_addr: Some(addr.clone()) }.validate(),
+				Err(crate::error::Error::AddressesForAnotherTransport { addresses, .. })
+					if addresses == vec![addr]
+			));
+		}
+	}
+
+	#[test]
+	fn is_reserved_only_follows_allow_non_reserved() {
+		let mut config = NonDefaultSetConfig::new("/test/1".into(), 1024);
+		assert!(config.is_reserved_only());
+
+		config.allow_non_reserved(1, 1);
+		assert!(!config.is_reserved_only());
+
+		assert!(!SetConfig::default().is_reserved_only());
+	}
+
+	#[test]
+	fn redundant_fallback_names_are_ignored() {
+		let mut config = NonDefaultSetConfig::new("/chain/proto/2".into(), 1024);
+		config.add_fallback_names(vec![
+			"/chain/proto/1".into(),
+			"/chain/proto/2".into(),
+			"/chain/proto/1".into(),
+		]);
+		let config = config.with_fallbacks(["/chain/proto/1".into(), "/chain/proto/0".into()]);
+
+		assert_eq!(
+			config.fallback_names,
+			vec![
+				protocol::ProtocolName::from("/chain/proto/1"),
+				protocol::ProtocolName::from("/chain/proto/0"),
+			]
+		);
+	}
+
+	#[test]
+	fn reserved_nodes_accept_bare_peer_ids() {
+		let with_addr: MultiaddrWithPeerId =
+			format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID).parse().unwrap();
+		let bare = PeerId::random();
+
+		let mut config = NonDefaultSetConfig::new("/test/1".into(), 1024);
+		config.add_reserved(with_addr.clone());
+		config.add_reserved(bare);
+		config.add_reserved(ReservedPeer::PeerId(bare));
+
+		assert_eq!(config.set_config.reserved_nodes, vec![with_addr.clone()]);
+		assert_eq!(config.set_config.reserved_peer_ids, vec![bare, bare]);
+		assert_eq!(
+			config.set_config.reserved_peers().collect::<Vec<_>>(),
+			vec![with_addr.peer_id, bare, bare]
+		);
+
+		assert_eq!(ReservedPeer::from(bare).peer_id(), bare);
+		assert_eq!(ReservedPeer::from(with_addr.clone()).peer_id(), with_addr.peer_id);
+	}
+
+	#[test]
+	fn protocol_names_yields_primary_then_fallbacks() {
+		let config = NonDefaultSetConfig::new("/chain/proto/3".into(), 1024)
+			.with_fallbacks(["/chain/proto/2".into(), "/chain/proto/1".into()]);
+
+		assert_eq!(
+			config.protocol_names().map(|name| &**name).collect::<Vec<_>>(),
+			vec!["/chain/proto/3", "/chain/proto/2", "/chain/proto/1"]
+		);
+		assert_eq!(
+			NonDefaultSetConfig::new("/chain/proto/3".into(), 1024).protocol_names().count(),
+			1
+		);
+	}
+
+	#[test]
+	fn scale_slots_rounds_down_and_saturates() {
+		let config = |in_peers: u32, out_peers: u32| SetConfig {
+			in_peers: in_peers.into(),
+			out_peers: out_peers.into(),
+			..Default::default()
+		};
+		let scaled = |mut config: SetConfig, factor| {
+			config.scale_slots(factor);
+			(config.in_peers.slots(), config.out_peers.slots())
+		};
+
+		assert_eq!(scaled(config(25, 75), 0.5), (12, 37));
+		assert_eq!(scaled(config(25, 75), 2.0), (50, 150));
+		assert_eq!(scaled(config(25, u32::MAX / 2), 3.0), (75, u32::MAX));
+		assert_eq!(scaled(config(25, 75), f32::MAX), (u32::MAX, u32::MAX));
+		assert_eq!(scaled(config(25, 75), -1.0), (0, 0));
+		assert_eq!(scaled(config(25, 75), f32::NAN), (0, 0));
+
+		let mut unlimited = config(25, 75);
+		unlimited.in_peers = PeerLimit::Unlimited;
+		unlimited.scale_slots(0.5);
+		assert_eq!(
+			(unlimited.in_peers, unlimited.out_peers),
+			(PeerLimit::Unlimited, PeerLimit::Limited(37))
+		);
+	}
+
+	#[test]
+	fn addresses_are_checked_against_transport() {
+		let memory_only = TransportConfig::MemoryOnly { listen_addr: None };
+		let normal = TransportConfig::Normal { enable_mdns: false, allow_private_ip: true };
+		let tcp = format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID);
+		let memory = format!("/memory/1234/p2p/{}", PEER_ID);
+
+		let tcp_addr: Multiaddr = "/ip4/198.51.100.19/tcp/30333".parse().unwrap();
+		let memory_addr: Multiaddr = "/memory/1234".parse().unwrap();
+		assert!(matches!(
+			parse_str_addr_for_transport(&tcp, &memory_only),
+			Err(ParseErr::UnsupportedTransport(addr)) if addr == tcp_addr
+		));
+		assert!(matches!(
+			parse_str_addr_for_transport(&memory, &normal),
+			Err(ParseErr::UnsupportedTransport(addr)) if addr == memory_addr
+		));
+
+		assert_eq!(
+			parse_str_addr_for_transport(&tcp, &normal).unwrap(),
+			parse_str_addr(&tcp).unwrap()
+		);
+		assert_eq!(
+			parse_str_addr_for_transport(&memory, &memory_only).unwrap(),
+			parse_str_addr(&memory).unwrap()
+		);
+		assert!(matches!(parse_str_addr_for_transport("", &normal), Err(ParseErr::EmptyInput)));
+	}
+
+	#[test]
+	fn validate_fallbacks_flags_mismatched_suffixes() {
+		let config = NonDefaultSetConfig::new("/0x1234/sync/2".into(), 1024)
+			.with_fallbacks(vec!["/dot/sync/2".into(), "/dot/sync/1".into()]);
+
+		assert_eq!(
+			config.validate_fallbacks(),
+			Err(vec![protocol::ProtocolName::from("/dot/sync/1")])
+		);
+
+		let config = NonDefaultSetConfig::new("/0x1234/sync/2".into(), 1024)
+			.with_fallbacks(vec!["/dot/sync/2".into()]);
+		assert_eq!(config.validate_fallbacks(), Ok(()));
+	}
+
+	#[test]
+	fn parse_str_addr_bounded_rejects_long_input() {
+		let addr = format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID);
+
+		assert!(parse_str_addr_bounded(&addr, addr.len()).is_ok());
+		assert!(matches!(parse_str_addr_bounded(&addr, addr.len() - 1), Err(ParseErr::TooLong)));
+	}
+
+	#[test]
+	fn peer_id_of_reads_trailing_p2p_component() {
+		let addr: Multiaddr =
+			format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID).parse().unwrap();
+		assert_eq!(peer_id_of(&addr), Some(PEER_ID.parse().unwrap()));
+		assert_eq!(addr.iter().count(), 3);
+
+		let addr: Multiaddr = "/ip4/198.51.100.19/tcp/30333".parse().unwrap();
+		assert_eq!(peer_id_of(&addr), None);
+	}
+
+	#[test]
+	fn is_relay_addr_detects_circuit_component() {
+		let direct: Multiaddr =
+			format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID).parse().unwrap();
+		assert!(!is_relay_addr(&direct));
+
+		let relayed: Multiaddr =
+			format!("/ip4/198.51.100.19/tcp/30333/p2p/{}/p2p-circuit", PEER_ID)
+				.parse()
+				.unwrap();
+		assert!(is_relay_addr(&relayed));
+	}
+
+	#[test]
+	fn peer_mutators_are_orthogonal() {
+		let mut config = NonDefaultSetConfig::new("/proto/1".into(), 1024);
+
+		config.set_peers(3, 4);
+		assert_eq!(
+			(config.set_config.in_peers, config.set_config.out_peers),
+			(PeerLimit::Limited(3), PeerLimit::Limited(4))
+		);
+		assert_eq!(config.set_config.non_reserved_mode, NonReservedPeerMode::Deny);
+
+		config.allow_non_reserved(5, 6);
+		config.deny_non_reserved();
+		assert_eq!(
+			(config.set_config.in_peers, config.set_config.out_peers),
+			(PeerLimit::Limited(5), PeerLimit::Limited(6))
+		);
+		assert_eq!(config.set_config.non_reserved_mode, NonReservedPeerMode::Deny);
+
+		config.allow_non_reserved(5, 6);
+		config.set_peers(7, 8);
+		assert_eq!(
+			(config.set_config.in_peers, config.set_config.out_peers),
+			(PeerLimit::Limited(7), PeerLimit::Limited(8))
+		);
+		assert_eq!(config.set_config.non_reserved_mode, NonReservedPeerMode::Accept);
+	}
+
+	#[test]
+	fn handshake_diff_finds_first_differing_byte() {
+		let handshake = NotificationHandshake::from_bytes(vec![1, 2, 3]);
+
+		assert!(handshake.matches(&[1, 2, 3]));
+		assert_eq!(handshake.diff(&[1, 2, 3]), None);
+
+		assert!(!handshake.matches(&[1, 2]));
+		assert_eq!(handshake.diff(&[1, 2]), Some(2));
+		assert_eq!(handshake.diff(&[1, 2, 3, 4]), Some(3));
+
+		assert!(!handshake.matches(&[1, 5, 3]));
+		assert_eq!(handshake.diff(&[1, 5, 3]), Some(1));
+	}
+
+	#[test]
+	fn protocol_name_components_handles_name_shapes() {
+		let components =
+			|chain, protocol, version| Some(ProtocolNameComponents { chain, protocol, version });
+
+		assert_eq!(protocol_name_components("/dot/sync/2"), components("dot", "sync", "2"));
+		assert_eq!(
+			protocol_name_components("/0x1234/block-announces/1"),
+			components("0x1234", "block-announces", "1")
+		);
+		assert_eq!(
+			protocol_name_components("/0x1234/fork/sync/2"),
+			components("0x1234", "fork/sync", "2")
+		);
+
+		assert_eq!(protocol_name_components("dot/sync/2"), None);
+		assert_eq!(protocol_name_components("/dot/sync"), None);
+		assert_eq!(protocol_name_components("/dot//2"), None);
+		assert_eq!(protocol_name_components("/dot/sync/"), None);
+	}
+
+	#[test]
+	fn protocol_id_as_str() {
+		let protocol_id = ProtocolId::from("dot");
+
+		assert_eq!(protocol_id.as_str(), Ok("dot"));
+		assert_eq!(protocol_id.as_str().unwrap(), protocol_id.as_ref());
+	}
+
+	#[test]
+	fn sorted_unique_reserved_sorts_and_dedups() {
+		let first: MultiaddrWithPeerId =
+			format!("/ip4/127.0.0.1/tcp/30333/p2p/{}", PeerId::random()).parse().unwrap();
+		let second: MultiaddrWithPeerId =
+			format!("/ip4/127.0.0.2/tcp/30333/p2p/{}", PeerId::random()).parse().unwrap();
+		let set_config = SetConfig {
+			reserved_nodes: vec![second.clone(), first.clone(), second.clone(), first.clone()],
+			..Default::default()
+		};
+
+		assert_eq!(set_config.sorted_unique_reserved(), vec![first, second]);
+		assert_eq!(set_config.reserved_nodes.len(), 4);
+	}
+
+	#[test]
+	fn diff_reserved_lists_added_and_removed_nodes() {
+		let mut nodes: Vec<MultiaddrWithPeerId> = (1..=4)
+			.map(|i| format!("/ip4/127.0.0.{}/tcp/30333/p2p/{}", i, PeerId::random()))
+			.map(|addr| addr.parse().unwrap())
+			.collect();
+		nodes.sort();
+		let [a, b, c, d] = <[_; 4]>::try_from(nodes).unwrap();
+
+		let old = vec![b.clone(), a.clone(), c.clone(), a.clone()];
+		let new = vec![d.clone(), c.clone(), d.clone(), b.clone()];
+		assert_eq!(diff_reserved(&old, &new), (vec![d.clone()], vec![a.clone()]));
+		assert_eq!(diff_reserved(&new, &old), (vec![a.clone()], vec![d.clone()]));
+		assert_eq!(diff_reserved(&old, &old), (vec![], vec![]));
+		assert_eq!(diff_reserved(&[], &new), (vec![b.clone(), c.clone(), d.clone()], vec![]));
+		assert_eq!(diff_reserved(&old, &[]), (vec![], vec![a, b, c]));
+	}
+
+	#[test]
+	fn non_reserved_peer_mode_accept_flag_round_trip() {
+		for mode in [NonReservedPeerMode::Accept, NonReservedPeerMode::Deny] {
+			assert_eq!(NonReservedPeerMode::from_accept_flag(mode.as_accept_flag()), mode);
+		}
+		assert!(NonReservedPeerMode::Accept.as_accept_flag());
+		assert!(!NonReservedPeerMode::Deny.as_accept_flag());
+	}
+
+	#[test]
+	fn parse_addr_full_keeps_the_full_address() {
+		let full: Multiaddr =
+			format!("/ip4/198.51.100.19/tcp/30333/p2p/{}", PEER_ID).parse().unwrap();
+
+		let (peer_id, stripped, returned) = parse_addr_full(full.clone()).unwrap();
+		assert_eq!(peer_id, PEER_ID.parse::<PeerId>().unwrap());
+		assert_eq!(stripped, "/ip4/198.51.100.19/tcp/30333".parse::<Multiaddr>().unwrap());
+		assert_eq!(returned, full);
+		assert!(matches!(
+			parse_addr_full("/ip4/198.51.100.19/tcp/30333".parse().unwrap()),
+			Err(ParseErr::PeerIdMissing)
+		));
+	}
+
+	#[test]
+	fn sets_reserving_peer_lists_matching_protocols() {
+		let shared = PeerId::random();
+		let other = PeerId::random();
+		let mut sync = NonDefaultSetConfig::new("/dot/sync/2".into(), 1024);
+		sync.add_reserved(shared);
+		let mut grandpa = NonDefaultSetConfig::new("/dot/grandpa/1".into(), 1024);
+		grandpa.add_reserved(other);
+		grandpa.add_reserved(shared);
+		let transactions = NonDefaultSetConfig::new("/dot/transactions/1".into(), 1024);
+		let sets = [sync, grandpa, transactions];
+
+		let names = |peer| {
+			sets_reserving_peer(&sets, peer)
+				.into_iter()
+				.map(|name| &**name)
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(names(&shared), vec!["/dot/sync/2", "/dot/grandpa/1"]);
+		assert_eq!(names(&other), vec!["/dot/grandpa/1"]);
+		assert!(names(&PeerId::random()).is_empty());
+	}
+
+	#[test]
+	fn parse_err_kind_matches_failure() {
+		let kind = |addr: &str| parse_str_addr(addr).unwrap_err().kind();
+
+		assert_eq!(kind("/ip4/198.51.100.19/tcp/30333"), ParseErrKind::PeerIdMissing);
+		assert_eq!(kind("/ip4/198.51.100.19/tcp/not-a-port"), ParseErrKind::MultiaddrParse);
+		assert_eq!(kind("  "), ParseErrKind::EmptyInput);
+
+		// SHA2-512 multihashes can't be peer IDs.
+		let multihash = multiaddr::multihash::Multihash::wrap(0x13, &[0; 64]).unwrap();
+		let addr = "/ip4/198.51.100.19/tcp/30333"
+			.parse::<Multiaddr>()
+			.unwrap()
+			.with(multiaddr::Protocol::P2p(multihash));
+		assert_eq!(parse_addr(addr).unwrap_err().kind(), ParseErrKind::InvalidPeerId);
+	}
+
+	#[test]
+	fn would_accept_non_reserved_requires_accept_mode_and_slots() {
+		for (non_reserved_mode, in_peers, out_peers, expected) in [
+			(NonReservedPeerMode::Accept, 25, 75, true),
+			(NonReservedPeerMode::Accept, 25, 0, true),
+			(NonReservedPeerMode::Accept, 0, 75, true),
+			(NonReservedPeerMode::Accept, 0, 0, false),
+			(NonReservedPeerMode::Deny, 25, 75, false),
+			(NonReservedPeerMode::Deny, 25, 0, false),
+			(NonReservedPeerMode::Deny, 0, 75, false),
+			(NonReservedPeerMode::Deny, 0, 0, false),
+		] {
+			let set_config = SetConfig {
+				non_reserved_mode: non_reserved_mode.clone(),
+				in_peers: PeerLimit::Limited(in_peers),
+				out_peers: PeerLimit::Limited(out_peers),
+				..Default::default()
+			};
+			assert_eq!(
+				set_config.would_accept_non_reserved(),
+				expected,
+				"{:?} with {} in and {} out slots",
+				non_reserved_mode,
+				in_peers,
+				out_peers
+			);
+		}
+	}
+
+	#[test]
+	fn dial_addrs_end_with_peer_id() {
+		let (first, second) = (PeerId::random(), PeerId::random());
+		let first_addr: MultiaddrWithPeerId =
+			format!("/ip4/127.0.0.1/tcp/30333/p2p/{}", first).parse().unwrap();
+		let second_addr: MultiaddrWithPeerId =
+			format!("/dns/example.com/tcp/30333/p2p/{}", second).parse().unwrap();
+		let set_config = SetConfig {
+			reserved_nodes: vec![first_addr.clone(), second_addr.clone(), first_addr.clone()],
+			..Default::default()
+		};
+
+		let dial_addrs = set_config.dial_addrs();
+		assert_eq!(dial_addrs.len(), 2);
+		for (addr, expected) in [(&first_addr, first), (&second_addr, second)] {
+			let dial_addr = dial_addrs
+				.iter()
+				.find(|dial_addr| dial_addr.to_string().starts_with(&addr.multiaddr.to_string()))
+				.expect("every reserved node can be dialed");
+			assert_eq!(dial_addr.iter().last(), Some(multiaddr::Protocol::P2p(expected.into())));
+		}
+	}
+
+	#[test]
+	fn dial_addrs_skip_nodes_without_address() {
+		let with_addr: MultiaddrWithPeerId =
+			format!("/ip4/127.0.0.1/tcp/30333/p2p/{}", PEER_ID).parse().unwrap();
+		let mut set_config = SetConfig::default();
+		set_config.add_reserved(PeerId::random());
+		set_config.add_reserved(with_addr.clone());
+
+		assert_eq!(set_config.dial_addrs(), vec![with_addr.concat()]);
+		assert_eq!(set_config.sorted_unique_reserved(), vec![with_addr]);
+	}
+
+	#[test]
+	fn sets_can_be_limited_or_unlimited() {
+		let limited = SetConfig::default();
+		assert_eq!(limited.in_peers, PeerLimit::Limited(25));
+		assert_eq!(limited.out_peers, PeerLimit::Limited(75));
+		assert_eq!((limited.in_peers.slots(), limited.out_peers.slots()), (25, 75));
+
+		let unlimited = SetConfig {
+			in_peers: PeerLimit::Unlimited,
+			out_peers: PeerLimit::Limited(0),
+			..Default::default()
+		};
+		assert_eq!(unlimited.in_peers.slots(), u32::MAX);
+		assert_eq!(unlimited.in_peers.capacity_hint(), UNLIMITED_PEERS_CAPACITY_HINT);
+		assert_eq!(limited.out_peers.capacity_hint(), 75);
+		assert!(!unlimited.in_peers.is_zero());
+		assert!(unlimited.out_peers.is_zero());
+		assert!(unlimited.would_accept_non_reserved());
+
+		let mut set = NonDefaultSetConfig::new("/proto/1".into(), 1024);
+		set.allow_non_reserved(0, 8);
+		set.set_config.in_peers = PeerLimit::Unlimited;
+		assert!(!set.is_reserved_only());
+	}
+
+	#[test]
+	fn protocol_name_str_borrows_the_main_protocol() {
+		let set = NonDefaultSetConfig::new("/static/1".into(), 1024);
+		assert_eq!(set.protocol_name_str(), "/static/1");
+
+		let mut set = NonDefaultSetConfig::new(format!("/{}/1", "on-heap").into(), 1024);
+		set.add_fallback_names(vec!["/fallback/1".into()]);
+		assert_eq!(set.protocol_name_str(), "/on-heap/1");
+	}
+
+	#[test]
+	fn total_slots_sums_all_sets() {
+		let default = SetConfig::default();
+		assert_eq!(total_slots(&[], &default), 25 + 75);
+
+		let mut sync = NonDefaultSetConfig::new("/sync/1".into(), 1024);
+		sync.allow_non_reserved(10, 20);
+		let reserved_only = NonDefaultSetConfig::new("/reserved/1".into(), 1024);
+		let mut grandpa = NonDefaultSetConfig::new("/grandpa/1".into(), 1024);
+		grandpa.set_peers(0, 5);
+		let sets = [sync, reserved_only, grandpa];
+		assert_eq!(total_slots(&sets, &default), 25 + 75 + 10 + 20 + 5);
+
+		let unlimited = SetConfig {
+			in_peers: PeerLimit::Unlimited,
+			out_peers: PeerLimit::Unlimited,
+			..Default::default()
+		};
+		assert_eq!(total_slots(&sets, &unlimited), 2 * u64::from(u32::MAX) + 10 + 20 + 5);
+	}
+
+	#[test]
+	fn check_protocol_name_uniqueness_reports_collisions() {
+		let set = |name: &'static str| NonDefaultSetConfig::new(name.into(), 1024);
+
+		assert_eq!(check_protocol_name_uniqueness(&[]), Ok(()));
+		assert_eq!(check_protocol_name_uniqueness(&[set("/a/1"), set("/b/1")]), Ok(()));
+		assert_eq!(
+			check_protocol_name_uniqueness(&[
+				set("/a/1"),
+				set("/b/1"),
+				set("/a/1"),
+				set("/a/1"),
+				set("/c/1")
+			]),
+			Err(vec!["/a/1".into()])
+		);
+
+		// Dynamically allocated names collide with static ones.
+		let mut sets = vec![set("/a/1"), set("/b/1")];
+		sets[1].notifications_protocol = String::from("/a/1").into();
+		assert_eq!(check_protocol_name_uniqueness(&sets), Err(vec!["/a/1".into()]));
+	}
+
+	#[test]
+	fn parse_nodes_json_reports_invalid_entries_with_their_index() {
+		let peer_id = PeerId::random();
+		let valid = format!("/ip4/127.0.0.1/tcp/30333/p2p/{}", peer_id);
+		let json = format!(
+			r#"["{}", "/ip4/127.0.0.1/tcp/30333", "{}", "", "not an address"]"#,
+			valid, valid
+		);
+
+		let invalid = match parse_nodes_json(&json) {
+			Err(NodesJsonError::InvalidEntries(invalid)) => invalid,
+			other => panic!("Unexpected result: {:?}", other),
+		};
+		let kinds: Vec<_> = invalid.iter().map(|(index, err)| (*index, err.kind())).collect();
+		assert_eq!(
+			kinds,
+			vec![
+				(1, ParseErrKind::PeerIdMissing),
+				(3, ParseErrKind::EmptyInput),
+				(4, ParseErrKind::MultiaddrParse)
+			]
+		);
+
+		let json = format!(r#"["{}"]"#, valid);
+		assert_eq!(parse_nodes_json(&json).unwrap(), vec![valid.parse().unwrap()]);
+		assert!(matches!(parse_nodes_json("[1, 2]"), Err(NodesJsonError::Json(_))));
+		assert!(matches!(parse_nodes_json("{}"), Err(NodesJsonError::Json(_))));
+	}
+
+	#[test]
+	fn same_peer_ignores_addresses() {
+		let peer_id = PEER_ID.parse::<PeerId>().unwrap();
+		let tcp = MultiaddrWithPeerId::from_components(
+			"/ip4/198.51.100.19/tcp/30333".parse().unwrap(),
+			peer_id,
+		);
+		let dns = MultiaddrWithPeerId::from_components(
+			"/dns/example.com/tcp/30333".parse().unwrap(),
+			peer_id,
+		);
+		assert_ne!(tcp, dns);
+		assert!(tcp.same_peer(&dns));
+
+		let other = MultiaddrWithPeerId::from_components(tcp.multiaddr().clone(), PeerId::random());
+		assert!(!tcp.same_peer(&other));
+	}
 }
@@ -218,7 +218,10 @@ impl NetworkConfiguration {
 			boot_nodes: Vec::new(),
 			node_key,
 			request_response_protocols: Vec::new(),
-			default_peers_set_num_full: default_peers_set.in_peers + default_peers_set.out_peers,
+			default_peers_set_num_full: default_peers_set
+				.in_peers
+				.slots()
+				.saturating_add(default_peers_set.out_peers.slots()),
 			default_peers_set,
 			extra_sets: Vec::new(),
 			client_version: client_version.into(),
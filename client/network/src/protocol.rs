@@ -228,27 +228,17 @@ where
 
 		let important_peers = {
 			let mut imp_p = HashSet::new();
-			for reserved in &network_config.default_peers_set.reserved_nodes {
-				imp_p.insert(reserved.peer_id);
-			}
-			for reserved in network_config
-				.extra_sets
-				.iter()
-				.flat_map(|s| s.set_config.reserved_nodes.iter())
-			{
-				imp_p.insert(reserved.peer_id);
+			imp_p.extend(network_config.default_peers_set.reserved_peers());
+			for set_cfg in &network_config.extra_sets {
+				imp_p.extend(set_cfg.set_config.reserved_peers());
 			}
 			imp_p.shrink_to_fit();
 			imp_p
 		};
 
 		let default_peers_set_no_slot_peers = {
-			let mut no_slot_p: HashSet<PeerId> = network_config
-				.default_peers_set
-				.reserved_nodes
-				.iter()
-				.map(|reserved| reserved.peer_id)
-				.collect();
+			let mut no_slot_p: HashSet<PeerId> =
+				network_config.default_peers_set.reserved_peers().collect();
 			no_slot_p.shrink_to_fit();
 			no_slot_p
 		};
@@ -259,13 +249,10 @@ where
 			let mut sets =
 				Vec::with_capacity(NUM_HARDCODED_PEERSETS + network_config.extra_sets.len());
 
-			let mut default_sets_reserved = HashSet::new();
+			let default_sets_reserved: HashSet<PeerId> =
+				network_config.default_peers_set.reserved_peers().collect();
 			for reserved in network_config.default_peers_set.reserved_nodes.iter() {
-				default_sets_reserved.insert(reserved.peer_id);
-
-				if !reserved.multiaddr.is_empty() {
-					known_addresses.push((reserved.peer_id, reserved.multiaddr.clone()));
-				}
+				known_addresses.push((reserved.peer_id, reserved.multiaddr.clone()));
 			}
 
 			let mut bootnodes = Vec::with_capacity(network_config.boot_nodes.len());
@@ -275,8 +262,8 @@ where
 
 			// Set number 0 is used for block announces.
 			sets.push(sc_peerset::SetConfig {
-				in_peers: network_config.default_peers_set.in_peers,
-				out_peers: network_config.default_peers_set.out_peers,
+				in_peers: network_config.default_peers_set.in_peers.slots(),
+				out_peers: network_config.default_peers_set.out_peers.slots(),
 				bootnodes,
 				reserved_nodes: default_sets_reserved.clone(),
 				reserved_only: network_config.default_peers_set.non_reserved_mode ==
@@ -284,9 +271,8 @@ where
 			});
 
 			for set_cfg in &network_config.extra_sets {
-				let mut reserved_nodes = HashSet::new();
+				let reserved_nodes: HashSet<PeerId> = set_cfg.set_config.reserved_peers().collect();
 				for reserved in set_cfg.set_config.reserved_nodes.iter() {
-					reserved_nodes.insert(reserved.peer_id);
 					known_addresses.push((reserved.peer_id, reserved.multiaddr.clone()));
 				}
 
@@ -294,8 +280,8 @@ where
 					set_cfg.set_config.non_reserved_mode == NonReservedPeerMode::Deny;
 
 				sets.push(sc_peerset::SetConfig {
-					in_peers: set_cfg.set_config.in_peers,
-					out_peers: set_cfg.set_config.out_peers,
+					in_peers: set_cfg.set_config.in_peers.slots(),
+					out_peers: set_cfg.set_config.out_peers.slots(),
 					bootnodes: Vec::new(),
 					reserved_nodes,
 					reserved_only,
@@ -327,8 +313,8 @@ where
 		};
 
 		let cache_capacity = NonZeroUsize::new(
-			(network_config.default_peers_set.in_peers as usize +
-				network_config.default_peers_set.out_peers as usize)
+			(network_config.default_peers_set.in_peers.capacity_hint() as usize +
+				network_config.default_peers_set.out_peers.capacity_hint() as usize)
 				.max(1),
 		)
 		.expect("cache capacity is not zero");
@@ -347,8 +333,9 @@ where
 			default_peers_set_no_slot_connected_peers: HashSet::new(),
 			default_peers_set_num_full: network_config.default_peers_set_num_full as usize,
 			default_peers_set_num_light: {
-				let total = network_config.default_peers_set.out_peers +
-					network_config.default_peers_set.in_peers;
+				let total = network_config.default_peers_set.out_peers.slots().saturating_add(
+					network_config.default_peers_set.in_peers.slots(),
+				);
 				total.saturating_sub(network_config.default_peers_set_num_full) as usize
 			},
 			peerset_handle: peerset_handle.clone(),
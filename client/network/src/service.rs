@@ -178,8 +178,14 @@ where
 				}
 			})
 			.collect();
+		params
+			.network_config
+			.default_peers_set
+			.reserved_peer_ids
+			.retain(|peer_id| *peer_id != local_peer_id);
 
 		// Ensure the listen addresses are consistent with the transport.
+		params.network_config.transport.validate()?;
 		ensure_addresses_consistent_with_transport(
 			params.network_config.listen_addresses.iter(),
 			&params.network_config.transport,
@@ -273,7 +279,7 @@ where
 				let mut config = DiscoveryConfig::new(local_public.clone());
 				config.with_permanent_addresses(known_addresses);
 				config.discovery_limit(
-					u64::from(params.network_config.default_peers_set.out_peers) + 15,
+					u64::from(params.network_config.default_peers_set.out_peers.slots()) + 15,
 				);
 				let genesis_hash = params
 					.chain
@@ -289,7 +295,7 @@ where
 				);
 
 				match params.network_config.transport {
-					TransportConfig::MemoryOnly => {
+					TransportConfig::MemoryOnly { .. } => {
 						config.with_mdns(false);
 						config.allow_private_ip(false);
 					},
@@ -308,7 +314,7 @@ where
 
 			let (transport, bandwidth) = {
 				let config_mem = match params.network_config.transport {
-					TransportConfig::MemoryOnly => true,
+					TransportConfig::MemoryOnly { .. } => true,
 					TransportConfig::Normal { .. } => false,
 				};
 
@@ -418,7 +424,13 @@ where
 		};
 
 		// Listen on multiaddresses.
-		for addr in &params.network_config.listen_addresses {
+		let memory_listen_addr = match &params.network_config.transport {
+			TransportConfig::MemoryOnly { listen_addr: Some(addr) }
+				if !params.network_config.listen_addresses.contains(addr) =>
+				Some(addr),
+			_ => None,
+		};
+		for addr in params.network_config.listen_addresses.iter().chain(memory_listen_addr) {
 			if let Err(err) = Swarm::<Behaviour<B, Client>>::listen_on(&mut swarm, addr.clone()) {
 				warn!(target: "sub-libp2p", "Can't listen on {} because: {:?}", addr, err)
 			}
@@ -1927,7 +1939,7 @@ fn ensure_addresses_consistent_with_transport<'a>(
 	addresses: impl Iterator<Item = &'a Multiaddr>,
 	transport: &TransportConfig,
 ) -> Result<(), Error> {
-	if matches!(transport, TransportConfig::MemoryOnly) {
+	if matches!(transport, TransportConfig::MemoryOnly { .. }) {
 		let addresses: Vec<_> = addresses
 			.filter(|x| {
 				x.iter().any(|y| !matches!(y, libp2p::core::multiaddr::Protocol::Memory(_)))
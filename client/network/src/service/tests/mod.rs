@@ -24,8 +24,8 @@ use sc_client_api::{BlockBackend, HeaderBackend};
 use sc_consensus::{ImportQueue, Link};
 use sc_network_common::{
 	config::{
-		NonDefaultSetConfig, NonReservedPeerMode, NotificationHandshake, ProtocolId, SetConfig,
-		TransportConfig,
+		NonDefaultSetConfig, NonReservedPeerMode, NotificationHandshake, PeerLimit, ProtocolId,
+		SetConfig, TransportConfig,
 	},
 	protocol::{event::Event, role::Roles},
 	service::NetworkEventStream,
@@ -170,7 +170,7 @@ impl TestNetworkBuilder {
 				set_config: self.set_config.unwrap_or_default(),
 			}],
 			listen_addresses: self.listen_addresses,
-			transport: TransportConfig::MemoryOnly,
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
 			..config::NetworkConfiguration::new_local()
 		});
 
@@ -260,9 +260,10 @@ impl TestNetworkBuilder {
 					.expect("Genesis block exists; qed"),
 			))),
 			set_config: SetConfig {
-				in_peers: 0,
-				out_peers: 0,
+				in_peers: PeerLimit::Limited(0),
+				out_peers: PeerLimit::Limited(0),
 				reserved_nodes: Vec::new(),
+				reserved_peer_ids: Vec::new(),
 				non_reserved_mode: NonReservedPeerMode::Deny,
 			},
 		};
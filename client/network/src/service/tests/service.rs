@@ -21,7 +21,7 @@ use crate::{config, service::tests::TestNetworkBuilder, NetworkService};
 use futures::prelude::*;
 use libp2p::PeerId;
 use sc_network_common::{
-	config::{MultiaddrWithPeerId, NonDefaultSetConfig, SetConfig, TransportConfig},
+	config::{MultiaddrWithPeerId, NonDefaultSetConfig, PeerLimit, SetConfig, TransportConfig},
 	protocol::event::Event,
 	service::{NetworkNotification, NetworkPeers, NetworkStateInfo},
 };
@@ -212,7 +212,7 @@ async fn lots_of_incoming_peers_works() {
 
 	let (main_node, _) = TestNetworkBuilder::new()
 		.with_listen_addresses(vec![listen_addr.clone()])
-		.with_set_config(SetConfig { in_peers: u32::MAX, ..Default::default() })
+		.with_set_config(SetConfig { in_peers: PeerLimit::Unlimited, ..Default::default() })
 		.build()
 		.start_network();
 
@@ -340,7 +340,7 @@ async fn fallback_name_working() {
 				set_config: Default::default(),
 			}],
 			listen_addresses: vec![listen_addr.clone()],
-			transport: TransportConfig::MemoryOnly,
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
 			..config::NetworkConfiguration::new_local()
 		})
 		.build()
@@ -387,6 +387,56 @@ async fn fallback_name_working() {
 	receiver.await.unwrap();
 }
 
+#[tokio::test]
+async fn unlimited_default_peers_set_works() {
+	// Node 1 doesn't limit the number of peers of its default set. Checks whether it starts and
+	// accepts node 2.
+	let listen_addr = config::build_multiaddr![Memory(rand::random::<u64>())];
+	let (node1, mut events_stream1) = TestNetworkBuilder::new()
+		.with_config(config::NetworkConfiguration {
+			default_peers_set: SetConfig {
+				in_peers: PeerLimit::Unlimited,
+				out_peers: PeerLimit::Unlimited,
+				..Default::default()
+			},
+			extra_sets: vec![NonDefaultSetConfig {
+				notifications_protocol: PROTOCOL_NAME.into(),
+				fallback_names: Vec::new(),
+				max_notification_size: 1024 * 1024,
+				handshake: None,
+				set_config: Default::default(),
+			}],
+			listen_addresses: vec![listen_addr.clone()],
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
+			..config::NetworkConfiguration::new_local()
+		})
+		.build()
+		.start_network();
+
+	let (node2, _) = TestNetworkBuilder::new()
+		.with_set_config(SetConfig {
+			reserved_nodes: vec![MultiaddrWithPeerId {
+				multiaddr: listen_addr,
+				peer_id: node1.local_peer_id(),
+			}],
+			..Default::default()
+		})
+		.build()
+		.start_network();
+
+	loop {
+		match events_stream1.next().await.unwrap() {
+			Event::NotificationStreamOpened { remote, protocol, .. }
+				if protocol == PROTOCOL_NAME.into() =>
+			{
+				assert_eq!(remote, node2.local_peer_id());
+				break
+			},
+			_ => {},
+		};
+	}
+}
+
 // Disconnect peer by calling `Protocol::disconnect_peer()` with the supplied block announcement
 // protocol name and verify that `SyncDisconnected` event is emitted
 #[tokio::test]
@@ -431,7 +481,7 @@ async fn ensure_listen_addresses_consistent_with_transport_memory() {
 	let _ = TestNetworkBuilder::new()
 		.with_config(config::NetworkConfiguration {
 			listen_addresses: vec![listen_addr.clone()],
-			transport: TransportConfig::MemoryOnly,
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
 			..config::NetworkConfiguration::new(
 				"test-node",
 				"test-client",
@@ -474,7 +524,7 @@ async fn ensure_boot_node_addresses_consistent_with_transport_memory() {
 	let _ = TestNetworkBuilder::new()
 		.with_config(config::NetworkConfiguration {
 			listen_addresses: vec![listen_addr.clone()],
-			transport: TransportConfig::MemoryOnly,
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
 			boot_nodes: vec![boot_node],
 			..config::NetworkConfiguration::new(
 				"test-node",
@@ -523,7 +573,7 @@ async fn ensure_reserved_node_addresses_consistent_with_transport_memory() {
 	let _ = TestNetworkBuilder::new()
 		.with_config(config::NetworkConfiguration {
 			listen_addresses: vec![listen_addr.clone()],
-			transport: TransportConfig::MemoryOnly,
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
 			default_peers_set: SetConfig {
 				reserved_nodes: vec![reserved_node],
 				..Default::default()
@@ -575,7 +625,7 @@ async fn ensure_public_addresses_consistent_with_transport_memory() {
 	let _ = TestNetworkBuilder::new()
 		.with_config(config::NetworkConfiguration {
 			listen_addresses: vec![listen_addr.clone()],
-			transport: TransportConfig::MemoryOnly,
+			transport: TransportConfig::MemoryOnly { listen_addr: None },
 			public_addresses: vec![public_address],
 			..config::NetworkConfiguration::new(
 				"test-node",
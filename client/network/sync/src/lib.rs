@@ -62,7 +62,8 @@ use sc_consensus::{
 };
 use sc_network_common::{
 	config::{
-		NonDefaultSetConfig, NonReservedPeerMode, NotificationHandshake, ProtocolId, SetConfig,
+		NonDefaultSetConfig, NonReservedPeerMode, NotificationHandshake, PeerLimit, ProtocolId,
+		SetConfig,
 	},
 	protocol::{role::Roles, ProtocolName},
 	request_responses::{IfDisconnected, RequestFailure},
@@ -2004,9 +2005,10 @@ where
 			// NOTE: `set_config` will be ignored by `protocol.rs` as the block announcement
 			// protocol is still hardcoded into the peerset.
 			set_config: SetConfig {
-				in_peers: 0,
-				out_peers: 0,
+				in_peers: PeerLimit::Limited(0),
+				out_peers: PeerLimit::Limited(0),
 				reserved_nodes: Vec::new(),
+				reserved_peer_ids: Vec::new(),
 				non_reserved_mode: NonReservedPeerMode::Deny,
 			},
 		}
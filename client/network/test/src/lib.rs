@@ -810,7 +810,7 @@ where
 		let mut network_config =
 			NetworkConfiguration::new("test-node", "test-client", Default::default(), None);
 		network_config.sync_mode = config.sync_mode;
-		network_config.transport = TransportConfig::MemoryOnly;
+		network_config.transport = TransportConfig::MemoryOnly { listen_addr: None };
 		network_config.listen_addresses = vec![listen_addr.clone()];
 		network_config.allow_non_globals_in_dht = true;
 		network_config
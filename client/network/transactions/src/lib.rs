@@ -33,7 +33,7 @@ use libp2p::{multiaddr, PeerId};
 use log::{debug, trace, warn};
 use prometheus_endpoint::{register, Counter, PrometheusError, Registry, U64};
 use sc_network_common::{
-	config::{NonDefaultSetConfig, NonReservedPeerMode, ProtocolId, SetConfig},
+	config::{NonDefaultSetConfig, NonReservedPeerMode, PeerLimit, ProtocolId, SetConfig},
 	error,
 	protocol::{event::Event, role::ObservedRole, ProtocolName},
 	service::{NetworkEventStream, NetworkNotification, NetworkPeers},
@@ -145,9 +145,10 @@ impl TransactionsHandlerPrototype {
 			max_notification_size: MAX_TRANSACTIONS_SIZE,
 			handshake: None,
 			set_config: SetConfig {
-				in_peers: 0,
-				out_peers: 0,
+				in_peers: PeerLimit::Limited(0),
+				out_peers: PeerLimit::Limited(0),
 				reserved_nodes: Vec::new(),
+				reserved_peer_ids: Vec::new(),
 				non_reserved_mode: NonReservedPeerMode::Deny,
 			},
 		}
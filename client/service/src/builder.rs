@@ -826,8 +826,8 @@ where
 			&protocol_id,
 			config.chain_spec.fork_id(),
 			client.clone(),
-			config.network.default_peers_set.in_peers as usize +
-				config.network.default_peers_set.out_peers as usize,
+			config.network.default_peers_set.in_peers.capacity_hint() as usize +
+				config.network.default_peers_set.out_peers.capacity_hint() as usize,
 		);
 		spawn_handle.spawn("block-request-handler", Some("networking"), handler.run());
 		protocol_config